@@ -1,20 +1,23 @@
 use anyhow::Result;
 use std::sync::Arc;
-use tokio::net::UdpSocket;
 use turn::client::*;
+use turn::util::UdpConn;
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    tracing_subscriber::fmt::init();
     let host = "127.0.0.1";
     let port = "3478";
     // let credential = user.splitn(2, "=").collect();
-    let connection = Arc::new(UdpSocket::bind("0.0.0.0:3301").await?);
+    let connection = Arc::new(UdpConn::bind("0.0.0.0:3301").await?);
     // clientの初期化
     let config = ClientConfig {
         turn_server_address: "127.0.0.1:3479".to_string(),
         username: "user".to_string(),
         password: "password".to_string(),
+        access_token: None,
+        mobility: false,
         connection: connection,
+        net: None,
     };
     let client = Client::new(config).await?;
     client.listen().await?;
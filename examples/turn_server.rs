@@ -1,17 +1,30 @@
 use anyhow::Result;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::net::UdpSocket;
 use tokio::signal;
+use turn::auth::StaticAuthHandler;
 use turn::server::*;
+use turn::util::{Conn, UdpConn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    tracing_subscriber::fmt::init();
     let host = "127.0.0.1";
     let port = "3479";
-    let conn = Arc::new(UdpSocket::bind(format!("{}:{}", host, port)).await?);
-    println!("listening {}...", conn.local_addr()?);
-    let server = Server::new(ServerConfig { conn_config: conn }).await?;
+    let conn: Arc<dyn Conn + Send + Sync> =
+        Arc::new(UdpConn::bind(format!("{}:{}", host, port)).await?);
+    let local_addr = conn.local_addr().await?;
+    println!("listening {}...", local_addr);
+    let realm = "ucchy-webrtc-realm";
+    let mut users = HashMap::new();
+    users.insert("user".to_string(), "password".to_string());
+    let server = Server::new(ServerConfig::new(
+        vec![conn],
+        realm.to_string(),
+        Arc::new(StaticAuthHandler::new(realm, &users)),
+        local_addr.ip(),
+    ))
+    .await?;
     println!("Waiting for Ctrl-C...");
     signal::ctrl_c().await.expect("failed to listen for event");
     println!("\nClosing connection now...");
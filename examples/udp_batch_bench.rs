@@ -0,0 +1,112 @@
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use turn::batch_conn::{BatchUdpConn, BATCH_SIZE};
+use turn::util::{Conn, UdpConn};
+
+// loopbackでUdpConnとBatchUdpConnの送受信のpackets per secondを比べる
+//
+//   cargo run --release --example udp_batch_bench -- [秒数] [payloadのbyte数]
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let mut args = std::env::args().skip(1);
+    let duration = Duration::from_secs(args.next().map(|s| s.parse()).transpose()?.unwrap_or(5));
+    let payload_size: usize = args.next().map(|s| s.parse()).transpose()?.unwrap_or(200);
+
+    let plain = run(
+        Arc::new(UdpConn::bind("127.0.0.1:0").await?),
+        Arc::new(UdpConn::bind("127.0.0.1:0").await?),
+        duration,
+        payload_size,
+    )
+    .await?;
+    println!(
+        "recv_from/send_to:  sent {:>12.0} pps, received {:>12.0} pps",
+        plain.0, plain.1
+    );
+
+    let receiver = BatchUdpConn::bind("127.0.0.1:0").await?;
+    println!(
+        "batch I/O: recvmmsg/sendmmsg={}, GSO={}, GRO={}",
+        receiver.is_batch_enabled(),
+        receiver.is_gso_enabled(),
+        receiver.is_gro_enabled()
+    );
+    let batch = run(
+        Arc::new(receiver),
+        Arc::new(BatchUdpConn::bind("127.0.0.1:0").await?),
+        duration,
+        payload_size,
+    )
+    .await?;
+    println!(
+        "recvmmsg/sendmmsg:  sent {:>12.0} pps, received {:>12.0} pps",
+        batch.0, batch.1
+    );
+    if plain.0 > 0.0 {
+        println!("speedup (sent):     {:.2}x", batch.0 / plain.0);
+    }
+    if plain.1 > 0.0 {
+        println!("speedup (received): {:.2}x", batch.1 / plain.1);
+    }
+    Ok(())
+}
+
+// (送信したpps, 受信したpps) を返す
+async fn run(
+    receiver: Arc<dyn Conn + Send + Sync>,
+    sender: Arc<dyn Conn + Send + Sync>,
+    duration: Duration,
+    payload_size: usize,
+) -> Result<(f64, f64)> {
+    let target: SocketAddr = receiver.local_addr().await?;
+    let running = Arc::new(AtomicBool::new(true));
+    let sent = Arc::new(AtomicU64::new(0));
+
+    let sender_task = {
+        let running = Arc::clone(&running);
+        let sent = Arc::clone(&sent);
+        tokio::spawn(async move {
+            let payload = vec![0xABu8; payload_size];
+            let packets: Vec<(&[u8], SocketAddr)> =
+                (0..BATCH_SIZE).map(|_| (&payload[..], target)).collect();
+            while running.load(Ordering::Relaxed) {
+                match sender.send_batch(&packets).await {
+                    Ok(n) => {
+                        sent.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        tracing::debug!(error = %err, "send failed");
+                        tokio::task::yield_now().await;
+                    }
+                }
+            }
+        })
+    };
+
+    let mut received = 0u64;
+    let mut buf = vec![0u8; 2048];
+    let start = Instant::now();
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            v = receiver.recv_from(&mut buf) => {
+                if v.is_ok() {
+                    received += 1;
+                }
+            }
+            _ = &mut deadline => break,
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    running.store(false, Ordering::Relaxed);
+    let _ = sender_task.await;
+    Ok((
+        sent.load(Ordering::Relaxed) as f64 / elapsed,
+        received as f64 / elapsed,
+    ))
+}
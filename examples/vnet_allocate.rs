@@ -0,0 +1,61 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use turn::auth::StaticAuthHandler;
+use turn::client::*;
+use turn::server::*;
+use turn::util::Conn;
+use turn::vnet::*;
+
+// 実際のsocketを使わずに、1つのprocessの中でserverとclientを動かしてAllocateする。
+// clientはport restricted NATの内側に置く
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let router = Router::new(RouterConfig {
+        cidr: "10.0.0.0/24".to_string(),
+        nat: None,
+    })?;
+    let server_net = Net::new(NetConfig {
+        static_ips: vec!["10.0.0.1".to_string()],
+    })?;
+    router.add_net(&server_net)?;
+    let nat_router = Router::new(RouterConfig {
+        cidr: "192.168.0.0/24".to_string(),
+        nat: Some(NatConfig::new(NatType::PortRestricted, "10.0.0.2")),
+    })?;
+    router.add_router(&nat_router)?;
+    let client_net = Net::new(NetConfig::default())?;
+    nat_router.add_net(&client_net)?;
+
+    let realm = "vnet-realm";
+    let mut users = HashMap::new();
+    users.insert("user".to_string(), "password".to_string());
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(server_net.bind("10.0.0.1:3478".parse()?)?);
+    let mut config = ServerConfig::new(
+        vec![conn],
+        realm.to_string(),
+        Arc::new(StaticAuthHandler::new(realm, &users)),
+        "10.0.0.1".parse()?,
+    );
+    config.vnet = Some(Arc::clone(&server_net));
+    let server = Server::new(config).await?;
+
+    let client = Client::new(ClientConfig {
+        turn_server_address: "10.0.0.1:3478".to_string(),
+        username: "user".to_string(),
+        password: "password".to_string(),
+        access_token: None,
+        mobility: false,
+        connection: Arc::new(client_net.bind("0.0.0.0:0".parse()?)?),
+        net: Some(Arc::clone(&client_net)),
+    })
+    .await?;
+    client.listen().await?;
+    client.allocate().await?;
+    println!("allocated from {:?}", client_net.ips());
+
+    client.close().await?;
+    server.close().await?;
+    Ok(())
+}
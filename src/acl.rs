@@ -0,0 +1,466 @@
+use crate::error::*;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+// ユーザーやrealmごとに、どのpeer(CIDR + port)へ到達できるかを決めるACL
+// ルールは上から順に評価され、最初にマッチしたルールのactionが使われる。
+// どのルールにもマッチしなければdefault_actionが使われる。
+//
+// 例: "internal" realmのアカウントだけが社内メディアサーバー(10.0.0.0/8)に到達できる
+//   PeerAclRule::new(AclSubject::Realm("internal".into()), "10.0.0.0/8".parse()?, AclAction::Allow)
+//   PeerAclRule::new(AclSubject::Any, "10.0.0.0/8".parse()?, AclAction::Deny)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclSubject {
+    Any,
+    User(String),
+    Realm(String),
+}
+
+impl AclSubject {
+    fn matches(&self, username: &str, realm: &str) -> bool {
+        match self {
+            AclSubject::Any => true,
+            AclSubject::User(u) => u == username,
+            AclSubject::Realm(r) => r == realm,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub address: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ::ffff:a.b.c.d のpeerがIPv4のルールをすり抜けないように、IPv4として比べる
+        let ip = match ip {
+            IpAddr::V6(v6) if self.address.is_ipv4() => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            _ => ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    // "10.0.0.0/8" や "::1/128" の形式。prefixを省略した場合はホストアドレスとして扱う
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| Error::ErrInvalidCidr)?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(p) => p.parse::<u8>().map_err(|_| Error::ErrInvalidCidr)?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(Error::ErrInvalidCidr);
+        }
+        Ok(Cidr {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerAclRule {
+    pub subject: AclSubject,
+    pub cidr: Cidr,
+    // Noneなら全てのportにマッチする
+    pub ports: Option<PortRange>,
+    pub action: AclAction,
+}
+
+impl PeerAclRule {
+    pub fn new(subject: AclSubject, cidr: Cidr, action: AclAction) -> Self {
+        PeerAclRule {
+            subject,
+            cidr,
+            ports: None,
+            action,
+        }
+    }
+
+    pub fn with_ports(mut self, ports: PortRange) -> Self {
+        self.ports = Some(ports);
+        self
+    }
+
+    fn matches(&self, username: &str, realm: &str, peer: SocketAddr) -> bool {
+        self.subject.matches(username, realm)
+            && self.cidr.contains(peer.ip())
+            && self.ports.map_or(true, |ports| ports.contains(peer.port()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerAcl {
+    pub rules: Vec<PeerAclRule>,
+    pub default_action: AclAction,
+}
+
+impl Default for PeerAcl {
+    fn default() -> Self {
+        PeerAcl {
+            rules: vec![],
+            default_action: AclAction::Allow,
+        }
+    }
+}
+
+impl PeerAcl {
+    pub fn check(&self, username: &str, realm: &str, peer: SocketAddr) -> AclAction {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(username, realm, peer))
+            .map_or(self.default_action, |rule| rule.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthHandler, SharedSecretAuthHandler};
+    use crate::client::check_error_response;
+    use crate::integrity::MessageIntegrity;
+    use crate::server::{Server, ServerConfig};
+    use crate::util::Conn;
+    use crate::vnet::{Net, NetConfig, Router, RouterConfig};
+    use crate::xor_address::XorPeerAddress;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    use stun::attribute::{Attribute, ATTR_NONCE, ATTR_REALM, ATTR_USERNAME};
+    use stun::message::*;
+
+    fn peer(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    // "internal" realmだけが10.0.0.0/8に到達でき、それ以外の10.0.0.0/8は拒否する
+    fn internal_only_acl() -> PeerAcl {
+        PeerAcl {
+            rules: vec![
+                PeerAclRule::new(
+                    AclSubject::Realm("internal".to_string()),
+                    "10.0.0.0/8".parse().unwrap(),
+                    AclAction::Allow,
+                ),
+                PeerAclRule::new(
+                    AclSubject::Any,
+                    "10.0.0.0/8".parse().unwrap(),
+                    AclAction::Deny,
+                ),
+            ],
+            default_action: AclAction::Allow,
+        }
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        assert_eq!(
+            "10.0.0.0/8".parse::<Cidr>().unwrap(),
+            Cidr {
+                address: "10.0.0.0".parse().unwrap(),
+                prefix_len: 8,
+            }
+        );
+        assert_eq!("192.0.2.1".parse::<Cidr>().unwrap().prefix_len, 32);
+        assert_eq!("2001:db8::/32".parse::<Cidr>().unwrap().prefix_len, 32);
+        assert_eq!("::1".parse::<Cidr>().unwrap().prefix_len, 128);
+        for invalid in &[
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/x",
+            "10.0.0/8",
+            "example.com",
+            "",
+        ] {
+            assert_eq!(
+                invalid.parse::<Cidr>(),
+                Err(Error::ErrInvalidCidr),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let v4: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(v4.contains("10.1.255.255".parse().unwrap()));
+        assert!(!v4.contains("10.2.0.0".parse().unwrap()));
+        let any_v4: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any_v4.contains("203.0.113.1".parse().unwrap()));
+        assert!(!any_v4.contains("2001:db8::1".parse().unwrap()));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+        assert!(!v6.contains("10.1.0.1".parse().unwrap()));
+        let any_v6: Cidr = "::/0".parse().unwrap();
+        assert!(any_v6.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ipv4_mapped_peer_matches_ipv4_rule() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::ffff:192.0.2.1".parse().unwrap()));
+        // IPv4-compatible(::a.b.c.d)はIPv4-mappedではない
+        assert!(!cidr.contains("::10.0.0.1".parse().unwrap()));
+
+        let acl = internal_only_acl();
+        assert_eq!(
+            acl.check("alice", "external", peer("[::ffff:10.0.0.1]:443")),
+            AclAction::Deny
+        );
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let acl = PeerAcl {
+            rules: vec![
+                PeerAclRule::new(
+                    AclSubject::User("alice".to_string()),
+                    "10.0.0.1".parse().unwrap(),
+                    AclAction::Allow,
+                ),
+                PeerAclRule::new(
+                    AclSubject::Any,
+                    "10.0.0.0/8".parse().unwrap(),
+                    AclAction::Deny,
+                ),
+                PeerAclRule::new(
+                    AclSubject::User("alice".to_string()),
+                    "10.0.0.0/8".parse().unwrap(),
+                    AclAction::Allow,
+                ),
+            ],
+            default_action: AclAction::Allow,
+        };
+        assert_eq!(
+            acl.check("alice", "r", peer("10.0.0.1:80")),
+            AclAction::Allow
+        );
+        // 後ろのAllowより先にDenyにマッチする
+        assert_eq!(
+            acl.check("alice", "r", peer("10.0.0.2:80")),
+            AclAction::Deny
+        );
+        assert_eq!(acl.check("bob", "r", peer("10.0.0.1:80")), AclAction::Deny);
+        assert_eq!(
+            acl.check("bob", "r", peer("192.0.2.1:80")),
+            AclAction::Allow
+        );
+    }
+
+    #[test]
+    fn test_ports_and_default_action() {
+        let acl = PeerAcl {
+            rules: vec![PeerAclRule::new(
+                AclSubject::Any,
+                "0.0.0.0/0".parse().unwrap(),
+                AclAction::Allow,
+            )
+            .with_ports(PortRange {
+                start: 1024,
+                end: 65535,
+            })],
+            default_action: AclAction::Deny,
+        };
+        assert_eq!(
+            acl.check("alice", "r", peer("192.0.2.1:1024")),
+            AclAction::Allow
+        );
+        assert_eq!(
+            acl.check("alice", "r", peer("192.0.2.1:1023")),
+            AclAction::Deny
+        );
+        assert_eq!(
+            acl.check("alice", "r", peer("[2001:db8::1]:5000")),
+            AclAction::Deny
+        );
+    }
+
+    #[test]
+    fn test_realm_scoping() {
+        let acl = internal_only_acl();
+        assert_eq!(
+            acl.check("alice", "internal", peer("10.0.0.1:80")),
+            AclAction::Allow
+        );
+        assert_eq!(
+            acl.check("alice", "external", peer("10.0.0.1:80")),
+            AclAction::Deny
+        );
+        assert_eq!(
+            acl.check("alice", "external", peer("192.0.2.1:80")),
+            AclAction::Allow
+        );
+        // usernameがrealmと同じ名前でもRealmのルールにはマッチしない
+        assert_eq!(
+            acl.check("internal", "external", peer("10.0.0.1:80")),
+            AclAction::Deny
+        );
+    }
+
+    const SERVER_ADDRESS: &str = "10.0.0.1:3478";
+    const PEER_ADDRESS: &str = "10.0.0.3:5000";
+
+    async fn send_and_receive(conn: &dyn Conn, packet: &[u8]) -> Message {
+        conn.send_to(packet, SERVER_ADDRESS.parse().unwrap())
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 1500];
+        let (n, _) = conn.recv_from(&mut buf).await.unwrap();
+        Message::decode_from_packet(&buf[..n].to_vec()).unwrap()
+    }
+
+    fn error_code(response: &Message) -> Option<u16> {
+        match check_error_response(response) {
+            Ok(()) => None,
+            Err(crate::error::Error::ErrErrorResponse(code)) => Some(code),
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    fn create_permission(
+        username: &str,
+        realm: &str,
+        nonce: &str,
+        key: &MessageIntegrity,
+    ) -> Vec<u8> {
+        let mut m = Message::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST);
+        m.set_extra_attribute(Box::new(XorPeerAddress {
+            address: peer(PEER_ADDRESS),
+        }))
+        .unwrap();
+        for (typ, value) in [
+            (ATTR_USERNAME, username),
+            (ATTR_REALM, realm),
+            (ATTR_NONCE, nonce),
+        ] {
+            let raw = value.as_bytes().to_vec();
+            m.attributes
+                .push(Attribute::new(typ, raw.len() as u16, raw));
+        }
+        let mut packet = m.encode_to_packet();
+        key.append_to(&mut packet);
+        packet
+    }
+
+    // 認証済みのclientがREALMを"internal"にしても、internal realm向けのルールは使えない。
+    // SharedSecretAuthHandlerはclientのREALMからkeyを作るので、REALMを偽ってもMESSAGE-INTEGRITYは通る
+    #[tokio::test]
+    async fn test_spoofed_realm_does_not_bypass_acl() {
+        let router = Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_string(),
+            nat: None,
+        })
+        .unwrap();
+        let server_net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.1".to_string()],
+        })
+        .unwrap();
+        router.add_net(&server_net).unwrap();
+        let client_net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.2".to_string()],
+        })
+        .unwrap();
+        router.add_net(&client_net).unwrap();
+
+        let auth_handler = Arc::new(SharedSecretAuthHandler::new("secret".to_string()));
+        let conn: Arc<dyn Conn + Send + Sync> =
+            Arc::new(server_net.bind(SERVER_ADDRESS.parse().unwrap()).unwrap());
+        let mut config = ServerConfig::new(
+            vec![conn],
+            "external".to_string(),
+            Arc::clone(&auth_handler) as Arc<dyn AuthHandler>,
+            "10.0.0.1".parse().unwrap(),
+        );
+        config.peer_acl = Some(Arc::new(internal_only_acl()));
+        config.vnet = Some(Arc::clone(&server_net));
+        let server = Server::new(config).await.unwrap();
+
+        let client = client_net.bind("10.0.0.2:0".parse().unwrap()).unwrap();
+        let challenge = send_and_receive(
+            &client,
+            &Message::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST).encode_to_packet(),
+        )
+        .await;
+        assert_eq!(error_code(&challenge), Some(401));
+        let nonce = crate::request::get_nonce(&challenge).unwrap();
+        assert_eq!(
+            crate::request::get_realm(&challenge).as_deref(),
+            Some("external")
+        );
+
+        let expires_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        let username = format!("{}:alice", expires_at);
+        let client_addr = client.local_addr().await.unwrap();
+        let spoofed_key = auth_handler
+            .auth_handle(&username, "internal", client_addr)
+            .unwrap();
+        let response = send_and_receive(
+            &client,
+            &create_permission(&username, "internal", &nonce, &spoofed_key),
+        )
+        .await;
+        assert_eq!(error_code(&response), Some(401));
+        let nonce = crate::request::get_nonce(&response).unwrap();
+
+        // このサーバーのrealmで認証すれば、"internal"向けのルールは当たらずに拒否される
+        let key = auth_handler
+            .auth_handle(&username, "external", client_addr)
+            .unwrap();
+        let response = send_and_receive(
+            &client,
+            &create_permission(&username, "external", &nonce, &key),
+        )
+        .await;
+        assert_eq!(error_code(&response), Some(403));
+        server.close().await.unwrap();
+    }
+}
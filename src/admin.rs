@@ -0,0 +1,396 @@
+use crate::allocation::*;
+use crate::redact::Username;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+// requestのheaderの大きさの上限。これを超えるrequestは431で断る
+const MAX_REQUEST_SIZE: usize = 8192;
+const HEADER_TERMINATOR: &[u8] = b"\r\n\r\n";
+
+// 運用者がallocationを一覧・削除するための管理用HTTPエンドポイント
+// 認証は無いので、loopback以外のアドレスではlistenしない。
+// 同じホストのブラウザからDNS rebindingで叩かれないように、Hostもloopbackの名前とこのportに限る
+//
+//   GET    /allocations                   allocationの一覧をJSONで返す
+//   DELETE /allocations/<id>              idを指定してallocationを削除する
+//   DELETE /users/<username>/allocations  ユーザーの全てのallocationを削除する。usernameはpercent-encodeする
+pub async fn serve_admin(
+    allocation_manager: Arc<AllocationManager>,
+    address: SocketAddr,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    if !address.ip().is_loopback() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            crate::error::Error::ErrAdminAddressNotLoopback,
+        ));
+    }
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!(address = %listener.local_addr()?, "serving admin API");
+    let port = listener.local_addr()?.port();
+    // 各接続のtaskがcloneを持つ。全てdropされたら処理中の接続が無くなったことが分かる
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    loop {
+        let (mut stream, _) = tokio::select! {
+            v = listener.accept() => v?,
+            did_change = shutdown_rx.changed() => {
+                if did_change.is_err() || *shutdown_rx.borrow() {
+                    break;
+                } else {
+                    continue;
+                }
+            }
+        };
+        let allocation_manager = Arc::clone(&allocation_manager);
+        let mut shutdown_rx = shutdown_rx.clone();
+        let done_tx = done_tx.clone();
+        tokio::spawn(async move {
+            let _done_tx = done_tx;
+            tokio::select! {
+                _ = handle_admin_connection(&mut stream, &allocation_manager, port) => {}
+                _ = shutdown_rx.changed() => {}
+            }
+        });
+    }
+    drop(done_tx);
+    let _ = done_rx.recv().await;
+    Ok(())
+}
+
+async fn handle_admin_connection(
+    stream: &mut TcpStream,
+    allocation_manager: &AllocationManager,
+    port: u16,
+) {
+    let (status, body) = match read_request(stream).await {
+        Ok(Some(request)) => match check_host(&request, port) {
+            Ok(()) => {
+                let mut request_line = request.lines().next().unwrap_or("").split(' ');
+                let method = request_line.next().unwrap_or("");
+                let path = request_line.next().unwrap_or("");
+                handle_admin_request(allocation_manager, method, path).await
+            }
+            Err(status) => (status, "{}".to_string()),
+        },
+        Ok(None) => ("431 Request Header Fields Too Large", "{}".to_string()),
+        Err(err) => {
+            tracing::debug!(error = %err, "failed to read admin request");
+            return;
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        tracing::debug!(error = %err, "failed to write admin response");
+    }
+}
+
+// headerの終わり(空行)まで読む。MAX_REQUEST_SIZEを超えたらNone。
+// DELETEとGETだけなのでbodyは読まない
+async fn read_request<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<Option<String>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf
+            .windows(HEADER_TERMINATOR.len())
+            .position(|window| window == HEADER_TERMINATOR)
+        {
+            buf.truncate(end);
+            return Ok(Some(String::from_utf8_lossy(&buf).into_owned()));
+        }
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+    }
+}
+
+// Hostが無ければ400、loopbackの名前とlistenしているport以外なら403
+fn check_host(request: &str, port: u16) -> std::result::Result<(), &'static str> {
+    let host = request
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim())
+        .ok_or("400 Bad Request")?;
+    if is_loopback_host(host, port) {
+        Ok(())
+    } else {
+        Err("403 Forbidden")
+    }
+}
+
+// "localhost:8080" や "127.0.0.1:8080", "[::1]:8080" の形。portを省略できるのはportが80の場合だけ
+fn is_loopback_host(host: &str, port: u16) -> bool {
+    let (name, host_port) = match host.rsplit_once(':') {
+        // "[::1]" のようにportが無いIPv6
+        Some(_) if host.ends_with(']') => (host, None),
+        Some((name, host_port)) => match host_port.parse::<u16>() {
+            Ok(host_port) => (name, Some(host_port)),
+            Err(_) => return false,
+        },
+        None => (host, None),
+    };
+    if host_port.unwrap_or(80) != port {
+        return false;
+    }
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().map_or(false, |ip| ip.is_loopback())
+}
+
+// "%20" などを元の文字に戻す。壊れたescapeやUTF-8でないものはNone
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+async fn handle_admin_request(
+    allocation_manager: &AllocationManager,
+    method: &str,
+    path: &str,
+) -> (&'static str, String) {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["allocations"]) => {
+            let allocations = allocation_manager.list_allocations().await;
+            ("200 OK", allocations_to_json(&allocations))
+        }
+        ("DELETE", ["allocations", id]) => match id.parse::<u64>() {
+            Ok(id) if allocation_manager.delete_allocation_by_id(id).await => {
+                tracing::info!(id, "admin: deleted allocation");
+                ("200 OK", "{\"deleted\":1}".to_string())
+            }
+            Ok(_) => ("404 Not Found", "{\"deleted\":0}".to_string()),
+            Err(_) => ("400 Bad Request", "{}".to_string()),
+        },
+        ("DELETE", ["users", username, "allocations"]) => {
+            // "/"を含むusernameも消せるように、segmentに分けてからdecodeする
+            let username = match percent_decode(username) {
+                Some(username) => username,
+                None => return ("400 Bad Request", "{}".to_string()),
+            };
+            let deleted = allocation_manager
+                .delete_allocations_by_username(&username)
+                .await;
+            tracing::info!(
+                deleted,
+                username = %Username(&username),
+                "admin: deleted allocations of user"
+            );
+            ("200 OK", format!("{{\"deleted\":{}}}", deleted))
+        }
+        _ => ("404 Not Found", "{}".to_string()),
+    }
+}
+
+fn allocations_to_json(allocations: &[AllocationInfo]) -> String {
+    let mut out = String::from("[");
+    for (i, info) in allocations.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"id\":{},\"username\":\"{}\",\"client_address\":\"{}\",\"server_address\":\"{}\",\"relayed_address\":\"{}\",\"age_seconds\":{},\"remaining_lifetime_seconds\":{},\"bytes_sent\":{},\"bytes_received\":{}}}",
+            info.id,
+            escape_json(&info.username),
+            info.five_tuple.src_addr,
+            info.five_tuple.dst_addr,
+            info.relay_addr,
+            info.age.as_secs(),
+            info.remaining_lifetime.as_secs(),
+            info.bytes_sent,
+            info.bytes_received
+        );
+    }
+    out.push(']');
+    out
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::five_tuple::FiveTuple;
+    use crate::metrics::Metrics;
+    use crate::requested_transport::PROTO_UDP;
+    use crate::util::Conn;
+    use crate::vnet::{Net, NetConfig, Router, RouterConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("alice").as_deref(), Some("alice"));
+        assert_eq!(
+            percent_decode("alice%20smith%2Fdev").as_deref(),
+            Some("alice smith/dev")
+        );
+        assert_eq!(percent_decode("%E3%81%82").as_deref(), Some("あ"));
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn test_check_host() {
+        let request = |host: &str| format!("GET /allocations HTTP/1.1\r\nHost: {}", host);
+        for host in &[
+            "localhost:8080",
+            "127.0.0.1:8080",
+            "[::1]:8080",
+            "LOCALHOST:8080",
+        ] {
+            assert_eq!(check_host(&request(host), 8080), Ok(()), "{}", host);
+        }
+        for host in &[
+            "attacker.example:8080",
+            "localhost:9090",
+            "localhost",
+            "192.0.2.1:8080",
+            "localhost.attacker.example:8080",
+        ] {
+            assert_eq!(
+                check_host(&request(host), 8080),
+                Err("403 Forbidden"),
+                "{}",
+                host
+            );
+        }
+        assert_eq!(check_host(&request("localhost"), 80), Ok(()));
+        assert_eq!(check_host(&request("[::1]"), 80), Ok(()));
+        assert_eq!(
+            check_host("GET /allocations HTTP/1.1\r\nAccept: */*", 8080),
+            Err("400 Bad Request")
+        );
+    }
+
+    // TCPのsegmentに分かれて届いても、空行まで読んでから処理する
+    #[tokio::test]
+    async fn test_read_request_split_across_segments() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            client
+                .write_all(b"DELETE /allocations/1 HTTP/1.1\r\n")
+                .await
+                .unwrap();
+            client.write_all(b"Host: localhost:8080\r").await.unwrap();
+            client.write_all(b"\n\r\n").await.unwrap();
+            client
+        });
+        let request = read_request(&mut server).await.unwrap().unwrap();
+        assert_eq!(
+            request,
+            "DELETE /allocations/1 HTTP/1.1\r\nHost: localhost:8080"
+        );
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_request_too_large() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let writer = tokio::spawn(async move {
+            let header = format!("X-Padding: {}\r\n", "a".repeat(1000));
+            for _ in 0..10 {
+                if client.write_all(header.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        assert_eq!(read_request(&mut server).await.unwrap(), None);
+        drop(server);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_allocations_of_percent_encoded_user() {
+        let router = Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_string(),
+            nat: None,
+        })
+        .unwrap();
+        let net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.1".to_string()],
+        })
+        .unwrap();
+        router.add_net(&net).unwrap();
+        let manager = AllocationManager::new(
+            RelayConfig {
+                relay_ip: "10.0.0.1".parse().unwrap(),
+                external_ip: None,
+                port_range: None,
+                vnet: Some(Arc::clone(&net)),
+                pcap: None,
+            },
+            AllocationQuota::default(),
+            Arc::new(Metrics::new()),
+            None,
+        );
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(net.bind("10.0.0.1:3478".parse().unwrap()).unwrap());
+        manager
+            .create_allocation(
+                FiveTuple {
+                    protocol: PROTO_UDP,
+                    src_addr: "10.0.0.2:5000".parse().unwrap(),
+                    dst_addr: "10.0.0.1:3478".parse().unwrap(),
+                },
+                "alice smith/あ".to_string(),
+                Duration::from_secs(600),
+                turn_socket,
+            )
+            .await
+            .unwrap();
+
+        let (status, _) = handle_admin_request(
+            &manager,
+            "DELETE",
+            "/users/alice%20smith%2F%E3%81%82/allocations",
+        )
+        .await;
+        assert_eq!(status, "200 OK");
+        assert_eq!(manager.allocation_count().await, 0);
+        let (status, _) =
+            handle_admin_request(&manager, "DELETE", "/users/alice%2/allocations").await;
+        assert_eq!(status, "400 Bad Request");
+    }
+}
@@ -0,0 +1,855 @@
+use crate::acl::PortRange;
+use crate::error::*;
+use crate::five_tuple::FiveTuple;
+use crate::icmp::IcmpErrorQueue;
+use crate::metrics::Metrics;
+use crate::observer::*;
+use crate::pcap::{PcapTap, TapConn};
+use crate::redact::Username;
+use crate::relay::relay_loop;
+use crate::util::{Conn, UdpConn};
+use crate::vnet::Net;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+// RFC 5766 sec 8
+// permissionの寿命は5分で、CreatePermissionかChannelBindでしか延長されない
+pub const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+// RFC 5766 sec 11
+// channel bindingの寿命は10分で、同じChannelBindを送り直すと延長される
+pub const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+// 期限が切れたchannel numberとpeerは、5分間は別の相手にbindできない
+pub const CHANNEL_COOLDOWN: Duration = Duration::from_secs(300);
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// port_rangeの中から空いているportを探す回数
+const MAX_PORT_BIND_ATTEMPTS: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    // relay用のsocketをbindするIP
+    pub relay_ip: IpAddr,
+    // clientに見せるIP。Noneならrelay_ipをそのまま使う
+    pub external_ip: Option<IpAddr>,
+    // NoneならOSにportを選ばせる
+    pub port_range: Option<PortRange>,
+    // Someならrelay用のsocketを実際のsocketではなく、このvnetのNetにbindする
+    pub vnet: Option<Arc<Net>>,
+    // Someならrelay socketで送受信したpacketを記録する
+    pub pcap: Option<Arc<PcapTap>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllocationQuota {
+    pub max_allocations: Option<usize>,
+    pub max_allocations_per_user: Option<usize>,
+}
+
+// RFC 5766 sec 5
+// clientの5-tupleとrelay用のsocketを紐づけたもの
+pub struct Allocation {
+    pub id: u64,
+    // RFC 8016のmobilityで別の5-tupleに移ることがあるので、five_tuple()で読む。
+    // 2つ目はclientとやりとりしているsocketで、peerから届いたpacketはここからclientに送る
+    client: RwLock<(FiveTuple, Arc<dyn Conn + Send + Sync>)>,
+    pub username: String,
+    pub relay_socket: Arc<dyn Conn + Send + Sync>,
+    pub relay_addr: SocketAddr,
+    // relay socketに届いたICMP errorを読み出す。受け取れないOSではNone
+    pub(crate) icmp_errors: Option<IcmpErrorQueue>,
+    pub created_at: Instant,
+    expires_at: Mutex<Instant>,
+    // peerのIPアドレス -> permissionの期限
+    permissions: Mutex<HashMap<IpAddr, Instant>>,
+    // channel number -> (peer, bindingの期限)
+    channels: Mutex<HashMap<u16, (SocketAddr, Instant)>>,
+    // 期限が切れたchannel number -> (peer, cooldownの終わり)。channelsの後にlockする
+    channel_cooldowns: Mutex<HashMap<u16, (SocketAddr, Instant)>>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    // MOBILITY-TICKETを発行したallocationならtrue。Refreshのたびに新しいticketを返す
+    mobility: AtomicBool,
+    // relay_loopとライフサイクルのイベントはこのspanの中で記録する
+    pub(crate) span: tracing::Span,
+    // relay_loopのtask。serverのcloseでrelay socketを閉じた後に終わるのを待つ
+    relay_task: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Allocation {
+    pub fn five_tuple(&self) -> FiveTuple {
+        self.client.read().unwrap().0
+    }
+
+    // peerから届いたpacketを送る先のclientのアドレスと、送るのに使うsocket
+    pub(crate) fn client(&self) -> (SocketAddr, Arc<dyn Conn + Send + Sync>) {
+        let client = self.client.read().unwrap();
+        (client.0.src_addr, Arc::clone(&client.1))
+    }
+
+    pub(crate) fn take_relay_task(&self) -> Option<JoinHandle<()>> {
+        self.relay_task.lock().unwrap().take()
+    }
+
+    pub fn is_mobility_enabled(&self) -> bool {
+        self.mobility.load(Ordering::Relaxed)
+    }
+
+    pub fn enable_mobility(&self) {
+        self.mobility.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn remaining_lifetime(&self) -> Duration {
+        self.expires_at
+            .lock()
+            .await
+            .saturating_duration_since(Instant::now())
+    }
+
+    pub fn add_bytes_sent(&self, n: usize) {
+        self.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_received(&self, n: usize) {
+        self.bytes_received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub async fn has_permission(&self, peer: SocketAddr) -> bool {
+        let permissions = self.permissions.lock().await;
+        match permissions.get(&peer.ip()) {
+            Some(expires_at) => *expires_at > Instant::now(),
+            None => false,
+        }
+    }
+
+    // 新しく作られたpermissionならtrueを返す。既存のものは期限を延長するだけ。
+    // metricsの数とmapの大きさを合わせるため、まだ消されていない期限切れのものも既存として扱う
+    pub async fn add_permission(&self, peer: SocketAddr) -> bool {
+        let mut permissions = self.permissions.lock().await;
+        permissions
+            .insert(peer.ip(), Instant::now() + PERMISSION_LIFETIME)
+            .is_none()
+    }
+
+    // 期限切れのpermissionを消し、消した数を返す
+    async fn remove_expired_permissions(&self) -> usize {
+        let mut permissions = self.permissions.lock().await;
+        let now = Instant::now();
+        let before = permissions.len();
+        permissions.retain(|_, expires_at| *expires_at > now);
+        before - permissions.len()
+    }
+
+    // 期限切れのchannel bindingをcooldownに移し、移した数を返す。
+    // cooldownが終わったものはここで捨てる
+    async fn remove_expired_channels(&self) -> usize {
+        let mut channels = self.channels.lock().await;
+        let mut cooldowns = self.channel_cooldowns.lock().await;
+        let now = Instant::now();
+        cooldowns.retain(|_, (_, cooldown_until)| *cooldown_until > now);
+        let before = channels.len();
+        channels.retain(|channel_number, (peer, expires_at)| {
+            if *expires_at > now {
+                return true;
+            }
+            cooldowns.insert(*channel_number, (*peer, *expires_at + CHANNEL_COOLDOWN));
+            false
+        });
+        before - channels.len()
+    }
+
+    pub async fn get_channel_peer(&self, channel_number: u16) -> Option<SocketAddr> {
+        let channels = self.channels.lock().await;
+        match channels.get(&channel_number) {
+            Some((peer, expires_at)) if *expires_at > Instant::now() => Some(*peer),
+            _ => None,
+        }
+    }
+
+    pub async fn get_channel_number(&self, peer: SocketAddr) -> Option<u16> {
+        let channels = self.channels.lock().await;
+        let now = Instant::now();
+        channels
+            .iter()
+            .find(|(_, (bound_peer, expires_at))| *bound_peer == peer && *expires_at > now)
+            .map(|(channel_number, _)| *channel_number)
+    }
+
+    // RFC 5766 sec 11.2
+    // channel numberが別のpeerに、またはpeerが別のchannel numberにbindされていればエラー。
+    // 期限が切れてからCHANNEL_COOLDOWNの間もbindされているものとして扱う (sec 11)。
+    // 新しくbindされたならtrueを返す。既存のものは期限を延長するだけ
+    pub async fn add_channel(&self, channel_number: u16, peer: SocketAddr) -> Result<bool> {
+        let mut channels = self.channels.lock().await;
+        let mut cooldowns = self.channel_cooldowns.lock().await;
+        let now = Instant::now();
+        let conflicts = channels
+            .iter()
+            .map(|(number, (bound_peer, expires_at))| {
+                (number, bound_peer, *expires_at + CHANNEL_COOLDOWN)
+            })
+            .chain(
+                cooldowns
+                    .iter()
+                    .map(|(number, (bound_peer, until))| (number, bound_peer, *until)),
+            )
+            .filter(|(_, _, until)| *until > now)
+            .any(|(bound_number, bound_peer, _)| {
+                (*bound_number == channel_number) != (*bound_peer == peer)
+            });
+        if conflicts {
+            return Err(Error::ErrChannelConflict);
+        }
+        // cooldown中の同じ組み合わせはbindし直せる
+        cooldowns.remove(&channel_number);
+        // まだ消されていない期限切れのbindingは使い回すだけなので、metricsの上では新しいbindingとしない
+        let is_new = !channels.contains_key(&channel_number);
+        channels.insert(channel_number, (peer, now + CHANNEL_LIFETIME));
+        Ok(is_new)
+    }
+
+    pub async fn is_expired(&self) -> bool {
+        *self.expires_at.lock().await <= Instant::now()
+    }
+
+    pub async fn info(&self) -> AllocationInfo {
+        let expires_at = *self.expires_at.lock().await;
+        AllocationInfo {
+            id: self.id,
+            username: self.username.clone(),
+            five_tuple: self.five_tuple(),
+            relay_addr: self.relay_addr,
+            age: self.created_at.elapsed(),
+            remaining_lifetime: expires_at.saturating_duration_since(Instant::now()),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn event(&self) -> AllocationEvent {
+        AllocationEvent {
+            username: self.username.clone(),
+            five_tuple: self.five_tuple(),
+            relayed_address: Some(self.relay_addr),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// 管理用APIで返すallocationのスナップショット
+#[derive(Debug, Clone)]
+pub struct AllocationInfo {
+    pub id: u64,
+    pub username: String,
+    pub five_tuple: FiveTuple,
+    pub relay_addr: SocketAddr,
+    pub age: Duration,
+    pub remaining_lifetime: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+// create_allocationが確保したquotaの枠。relay socketの準備に失敗して捨てられた時にも枠を戻す
+struct Reservation<'a> {
+    reservations: &'a std::sync::Mutex<HashMap<FiveTuple, String>>,
+    five_tuple: FiveTuple,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.reservations.lock().unwrap().remove(&self.five_tuple);
+    }
+}
+
+// 全てのallocationを5-tupleで管理する。作成・更新・削除のたびにobserverとmetricsに通知する
+pub struct AllocationManager {
+    allocations: Mutex<HashMap<FiveTuple, Arc<Allocation>>>,
+    // relay socketを準備している間のallocationの5-tupleとusername。quotaではallocationと同じく数える。
+    // allocationsのlockを取った後にだけlockする
+    reservations: std::sync::Mutex<HashMap<FiveTuple, String>>,
+    relay_config: RelayConfig,
+    quota: AllocationQuota,
+    next_id: AtomicU64,
+    // trueの間は新しいallocationを受け付けない(graceful shutdown中)
+    draining: AtomicBool,
+    metrics: Arc<Metrics>,
+    observer: Option<Arc<dyn ServerObserver>>,
+}
+
+impl AllocationManager {
+    pub fn new(
+        relay_config: RelayConfig,
+        quota: AllocationQuota,
+        metrics: Arc<Metrics>,
+        observer: Option<Arc<dyn ServerObserver>>,
+    ) -> Self {
+        AllocationManager {
+            allocations: Mutex::new(HashMap::new()),
+            reservations: std::sync::Mutex::new(HashMap::new()),
+            relay_config,
+            quota,
+            next_id: AtomicU64::new(1),
+            draining: AtomicBool::new(false),
+            metrics,
+            observer,
+        }
+    }
+
+    pub async fn get_allocation(&self, five_tuple: &FiveTuple) -> Option<Arc<Allocation>> {
+        let allocations = self.allocations.lock().await;
+        allocations.get(five_tuple).map(Arc::clone)
+    }
+
+    pub async fn get_allocation_by_id(&self, id: u64) -> Option<Arc<Allocation>> {
+        let allocations = self.allocations.lock().await;
+        allocations
+            .values()
+            .find(|allocation| allocation.id == id)
+            .map(Arc::clone)
+    }
+
+    // turn_socketはclientとやりとりしているsocketで、peerから届いたpacketはここからclientに送る
+    pub async fn create_allocation(
+        &self,
+        five_tuple: FiveTuple,
+        username: String,
+        lifetime: Duration,
+        turn_socket: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<Arc<Allocation>> {
+        let reservation = match self.reserve(five_tuple, &username).await {
+            Ok(reservation) => reservation,
+            Err(Error::ErrAllocationQuotaReached) => {
+                if let Some(observer) = &self.observer {
+                    observer.on_quota_rejected(&AllocationEvent {
+                        username,
+                        five_tuple,
+                        relayed_address: None,
+                        bytes_sent: 0,
+                        bytes_received: 0,
+                    });
+                }
+                return Err(Error::ErrAllocationQuotaReached);
+            }
+            Err(err) => return Err(err),
+        };
+        let (mut relay_socket, icmp_errors) = self.bind_relay_socket().await?;
+        let relay_port = relay_socket.local_addr().await?.port();
+        let relay_addr = SocketAddr::new(
+            self.relay_config
+                .external_ip
+                .unwrap_or(self.relay_config.relay_ip),
+            relay_port,
+        );
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Some(tap) = &self.relay_config.pcap {
+            tap.register_allocation(id, &username, five_tuple.src_addr);
+            relay_socket = Arc::new(TapConn::peer_side(relay_socket, Arc::clone(tap), id));
+        }
+        // allocationはrequestより長く生きるので、transactionのspanの子にはしない
+        let span = tracing::info_span!(
+            parent: None,
+            "allocation",
+            id,
+            username = %Username(&username),
+            relay = %relay_addr
+        );
+        let allocation = Arc::new(Allocation {
+            id,
+            client: RwLock::new((five_tuple, turn_socket)),
+            username,
+            relay_socket,
+            relay_addr,
+            icmp_errors,
+            created_at: Instant::now(),
+            expires_at: Mutex::new(Instant::now() + lifetime),
+            permissions: Mutex::new(HashMap::new()),
+            channels: Mutex::new(HashMap::new()),
+            channel_cooldowns: Mutex::new(HashMap::new()),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            mobility: AtomicBool::new(false),
+            span,
+            relay_task: std::sync::Mutex::new(None),
+        });
+        allocation.span.in_scope(|| {
+            tracing::info!(client = %five_tuple.src_addr, ?lifetime, "allocation created");
+        });
+        let relay_task = tokio::spawn(
+            relay_loop(Arc::clone(&allocation), Arc::clone(&self.metrics))
+                .instrument(allocation.span.clone()),
+        );
+        *allocation.relay_task.lock().unwrap() = Some(relay_task);
+
+        {
+            // 同じlockの中で予約を外すので、quotaから数え漏れる瞬間は無い
+            let mut allocations = self.allocations.lock().await;
+            allocations.insert(five_tuple, Arc::clone(&allocation));
+            drop(reservation);
+        }
+        self.metrics.allocation_created();
+        if let Some(observer) = &self.observer {
+            observer.on_allocation_created(&allocation.event(), lifetime);
+        }
+        Ok(allocation)
+    }
+
+    // 準備中のものも含めてquotaを確かめ、同じlockの中で枠を予約する。
+    // 複数のworkerが同時にAllocateを処理しても、確かめてから数えるまでの間に追い越されない
+    async fn reserve(&self, five_tuple: FiveTuple, username: &str) -> Result<Reservation<'_>> {
+        let allocations = self.allocations.lock().await;
+        let mut reservations = self.reservations.lock().unwrap();
+        if allocations.contains_key(&five_tuple) || reservations.contains_key(&five_tuple) {
+            return Err(Error::ErrDupeFiveTuple);
+        }
+        if let Some(max_allocations) = self.quota.max_allocations {
+            if allocations.len() + reservations.len() >= max_allocations {
+                return Err(Error::ErrAllocationQuotaReached);
+            }
+        }
+        if let Some(max_allocations_per_user) = self.quota.max_allocations_per_user {
+            let user_allocations = allocations
+                .values()
+                .filter(|allocation| allocation.username == username)
+                .count()
+                + reservations
+                    .values()
+                    .filter(|reserved| *reserved == username)
+                    .count();
+            if user_allocations >= max_allocations_per_user {
+                return Err(Error::ErrAllocationQuotaReached);
+            }
+        }
+        reservations.insert(five_tuple, username.to_string());
+        Ok(Reservation {
+            reservations: &self.reservations,
+            five_tuple,
+        })
+    }
+
+    // vnetのsocketではICMP errorを受け取れないので、2つ目は常にNoneになる
+    async fn bind_relay_socket(
+        &self,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, Option<IcmpErrorQueue>)> {
+        let relay_ip = self.relay_config.relay_ip;
+        let port_range = match self.relay_config.port_range {
+            Some(port_range) => port_range,
+            None => {
+                return self
+                    .bind_relay_socket_at(SocketAddr::new(relay_ip, 0))
+                    .await
+            }
+        };
+        for _ in 0..MAX_PORT_BIND_ATTEMPTS {
+            let port = rand::thread_rng().gen_range(port_range.start..=port_range.end);
+            if let Ok(relay_socket) = self
+                .bind_relay_socket_at(SocketAddr::new(relay_ip, port))
+                .await
+            {
+                return Ok(relay_socket);
+            }
+        }
+        Err(crate::util::Error::ErrPortSpaceExhausted.into())
+    }
+
+    async fn bind_relay_socket_at(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<(Arc<dyn Conn + Send + Sync>, Option<IcmpErrorQueue>)> {
+        if let Some(net) = &self.relay_config.vnet {
+            return Ok((Arc::new(net.bind(addr)?), None));
+        }
+        let relay_socket = Arc::new(
+            UdpSocket::bind(addr)
+                .await
+                .map_err(crate::util::Error::from)?,
+        );
+        let icmp_errors = IcmpErrorQueue::enable(&relay_socket);
+        Ok((Arc::new(UdpConn::from_shared(relay_socket)), icmp_errors))
+    }
+
+    pub async fn refresh_allocation(
+        &self,
+        five_tuple: &FiveTuple,
+        lifetime: Duration,
+    ) -> Result<()> {
+        let allocation = match self.get_allocation(five_tuple).await {
+            Some(allocation) => allocation,
+            None => return Err(Error::ErrNoAllocationFound),
+        };
+        *allocation.expires_at.lock().await = Instant::now() + lifetime;
+        if let Some(observer) = &self.observer {
+            observer.on_allocation_refreshed(&allocation.event(), lifetime);
+        }
+        Ok(())
+    }
+
+    // RFC 8016 sec 3.5
+    // allocationを新しい5-tupleに移す。relay addressやpermission, channelはそのまま引き継ぐ
+    pub async fn migrate_allocation(
+        &self,
+        allocation: &Arc<Allocation>,
+        five_tuple: FiveTuple,
+        turn_socket: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<()> {
+        let old_five_tuple = {
+            let mut allocations = self.allocations.lock().await;
+            if allocations.contains_key(&five_tuple) {
+                return Err(Error::ErrDupeFiveTuple);
+            }
+            let old_five_tuple = allocation.five_tuple();
+            if allocations.remove(&old_five_tuple).is_none() {
+                return Err(Error::ErrNoAllocationFound);
+            }
+            *allocation.client.write().unwrap() = (five_tuple, turn_socket);
+            allocations.insert(five_tuple, Arc::clone(allocation));
+            old_five_tuple
+        };
+        if let Some(tap) = &self.relay_config.pcap {
+            tap.update_client_addr(allocation.id, five_tuple.src_addr);
+        }
+        allocation.span.in_scope(|| {
+            tracing::info!(from = %old_five_tuple, to = %five_tuple, "allocation migrated");
+        });
+        self.metrics.allocation_migrated();
+        if let Some(observer) = &self.observer {
+            observer.on_allocation_migrated(&allocation.event(), old_five_tuple);
+        }
+        Ok(())
+    }
+
+    pub async fn create_permission(&self, allocation: &Allocation, peer: SocketAddr) {
+        // 期限切れのpermissionを作り直す場合に、新しいpermissionとして数えられるように先に消す
+        self.remove_expired_entries(allocation).await;
+        if allocation.add_permission(peer).await {
+            self.metrics.permission_created();
+            if let Some(observer) = &self.observer {
+                observer.on_permission_created(&allocation.event(), peer);
+            }
+        }
+    }
+
+    // ChannelBindはpermissionも作成・延長する
+    pub async fn bind_channel(
+        &self,
+        allocation: &Allocation,
+        channel_number: u16,
+        peer: SocketAddr,
+    ) -> Result<()> {
+        self.remove_expired_entries(allocation).await;
+        let is_new = allocation.add_channel(channel_number, peer).await?;
+        self.create_permission(allocation, peer).await;
+        if is_new {
+            self.metrics.channel_bound();
+            if let Some(observer) = &self.observer {
+                observer.on_channel_bound(&allocation.event(), peer, channel_number);
+            }
+        }
+        Ok(())
+    }
+
+    // 期限切れのpermissionとchannel bindingを消し、その分だけmetricsの数を減らす
+    async fn remove_expired_entries(&self, allocation: &Allocation) {
+        for _ in 0..allocation.remove_expired_permissions().await {
+            self.metrics.permission_deleted();
+        }
+        for _ in 0..allocation.remove_expired_channels().await {
+            self.metrics.channel_deleted();
+        }
+    }
+
+    pub async fn delete_allocation(&self, five_tuple: &FiveTuple) -> Option<Arc<Allocation>> {
+        let allocation = {
+            let mut allocations = self.allocations.lock().await;
+            allocations.remove(five_tuple)?
+        };
+        if let Some(tap) = &self.relay_config.pcap {
+            tap.unregister_allocation(allocation.id);
+        }
+        // relay_loopはrecv_fromがErrUseClosedNetworkConnになって止まる
+        if let Err(err) = allocation.relay_socket.close().await {
+            tracing::debug!(
+                relay = %allocation.relay_addr,
+                error = %err,
+                "failed to close relay socket"
+            );
+        }
+        let permissions = allocation.permissions.lock().await.len();
+        for _ in 0..permissions {
+            self.metrics.permission_deleted();
+        }
+        let channels = allocation.channels.lock().await.len();
+        for _ in 0..channels {
+            self.metrics.channel_deleted();
+        }
+        self.metrics.allocation_deleted();
+        let event = allocation.event();
+        allocation.span.in_scope(|| {
+            tracing::info!(
+                bytes_sent = event.bytes_sent,
+                bytes_received = event.bytes_received,
+                "allocation deleted"
+            );
+        });
+        if let Some(observer) = &self.observer {
+            observer.on_allocation_deleted(&event);
+        }
+        Some(allocation)
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub async fn allocation_count(&self) -> usize {
+        self.allocations.lock().await.len()
+    }
+
+    // 全てのallocationを削除し、削除したallocationを返す。
+    // relay socketはdelete_allocationでcloseされるので、relay_loopはこの後すぐに終わる
+    pub async fn delete_all_allocations(&self) -> Vec<Arc<Allocation>> {
+        let five_tuples: Vec<FiveTuple> = {
+            let allocations = self.allocations.lock().await;
+            allocations.keys().copied().collect()
+        };
+        let mut deleted = Vec::with_capacity(five_tuples.len());
+        for five_tuple in five_tuples {
+            if let Some(allocation) = self.delete_allocation(&five_tuple).await {
+                deleted.push(allocation);
+            }
+        }
+        deleted
+    }
+
+    pub async fn list_allocations(&self) -> Vec<AllocationInfo> {
+        let allocations: Vec<Arc<Allocation>> = {
+            let allocations = self.allocations.lock().await;
+            allocations.values().map(Arc::clone).collect()
+        };
+        let mut infos = Vec::with_capacity(allocations.len());
+        for allocation in allocations {
+            infos.push(allocation.info().await);
+        }
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    pub async fn delete_allocation_by_id(&self, id: u64) -> bool {
+        let five_tuple = {
+            let allocations = self.allocations.lock().await;
+            allocations
+                .values()
+                .find(|allocation| allocation.id == id)
+                .map(|allocation| allocation.five_tuple())
+        };
+        match five_tuple {
+            Some(five_tuple) => self.delete_allocation(&five_tuple).await.is_some(),
+            None => false,
+        }
+    }
+
+    // 削除したallocationの数を返す
+    pub async fn delete_allocations_by_username(&self, username: &str) -> usize {
+        let five_tuples: Vec<FiveTuple> = {
+            let allocations = self.allocations.lock().await;
+            allocations
+                .values()
+                .filter(|allocation| allocation.username == username)
+                .map(|allocation| allocation.five_tuple())
+                .collect()
+        };
+        let mut deleted = 0;
+        for five_tuple in five_tuples {
+            if self.delete_allocation(&five_tuple).await.is_some() {
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+
+    // 期限切れのallocationと、残っているallocationの期限切れのpermissionとchannel bindingを定期的に削除する
+    pub async fn run_expiry_loop(self: Arc<Self>, mut shutdown_rx: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                did_change = shutdown_rx.changed() => {
+                    if did_change.is_err() || *shutdown_rx.borrow() {
+                        break;
+                    } else {
+                        continue;
+                    }
+                }
+            }
+            let allocations: Vec<Arc<Allocation>> = {
+                let allocations = self.allocations.lock().await;
+                allocations.values().map(Arc::clone).collect()
+            };
+            for allocation in allocations {
+                if allocation.is_expired().await {
+                    let five_tuple = allocation.five_tuple();
+                    tracing::debug!(five_tuple = %five_tuple, "allocation expired");
+                    self.delete_allocation(&five_tuple).await;
+                } else {
+                    self.remove_expired_entries(&allocation).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requested_transport::PROTO_UDP;
+    use crate::vnet::{NetConfig, Router, RouterConfig};
+
+    fn new_manager(quota: AllocationQuota) -> (Arc<Router>, Arc<Net>, Arc<AllocationManager>) {
+        let router = Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_string(),
+            nat: None,
+        })
+        .unwrap();
+        let net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.1".to_string()],
+        })
+        .unwrap();
+        router.add_net(&net).unwrap();
+        let manager = Arc::new(AllocationManager::new(
+            RelayConfig {
+                relay_ip: "10.0.0.1".parse().unwrap(),
+                external_ip: None,
+                port_range: None,
+                vnet: Some(Arc::clone(&net)),
+                pcap: None,
+            },
+            quota,
+            Arc::new(Metrics::new()),
+            None,
+        ));
+        (router, net, manager)
+    }
+
+    fn five_tuple(port: u16) -> FiveTuple {
+        FiveTuple {
+            protocol: PROTO_UDP,
+            src_addr: SocketAddr::new("10.0.0.2".parse().unwrap(), port),
+            dst_addr: "10.0.0.1:3478".parse().unwrap(),
+        }
+    }
+
+    // 同時に処理されたAllocateが、どれもquotaの確認を通ってしまわないこと
+    async fn create_concurrently(
+        manager: &Arc<AllocationManager>,
+        turn_socket: &Arc<dyn Conn + Send + Sync>,
+        usernames: &[&str],
+    ) -> Vec<Result<Arc<Allocation>>> {
+        let tasks: Vec<_> = usernames
+            .iter()
+            .enumerate()
+            .map(|(i, username)| {
+                let manager = Arc::clone(manager);
+                let turn_socket = Arc::clone(turn_socket);
+                let username = username.to_string();
+                tokio::spawn(async move {
+                    manager
+                        .create_allocation(
+                            five_tuple(5000 + i as u16),
+                            username,
+                            Duration::from_secs(600),
+                            turn_socket,
+                        )
+                        .await
+                })
+            })
+            .collect();
+        let mut results = vec![];
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+        results
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_allocations_respect_max_allocations() {
+        let (_router, net, manager) = new_manager(AllocationQuota {
+            max_allocations: Some(2),
+            max_allocations_per_user: None,
+        });
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(net.bind("10.0.0.1:3478".parse().unwrap()).unwrap());
+        let results = create_concurrently(&manager, &turn_socket, &["alice"; 16]).await;
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+        assert!(results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .all(|err| *err == Error::ErrAllocationQuotaReached));
+        assert_eq!(manager.allocation_count().await, 2);
+        assert!(manager.reservations.lock().unwrap().is_empty());
+        manager.delete_all_allocations().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_allocations_respect_per_user_quota() {
+        let (_router, net, manager) = new_manager(AllocationQuota {
+            max_allocations: None,
+            max_allocations_per_user: Some(1),
+        });
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(net.bind("10.0.0.1:3478".parse().unwrap()).unwrap());
+        let usernames = [
+            "alice", "bob", "alice", "bob", "alice", "bob", "alice", "bob",
+        ];
+        let results = create_concurrently(&manager, &turn_socket, &usernames).await;
+        let mut created: Vec<String> = results
+            .iter()
+            .filter_map(|result| result.as_ref().ok())
+            .map(|allocation| allocation.username.clone())
+            .collect();
+        created.sort();
+        assert_eq!(created, vec!["alice", "bob"]);
+        manager.delete_all_allocations().await;
+    }
+
+    // relay socketを作れなかった場合は、予約した枠を戻す
+    #[tokio::test]
+    async fn test_failed_relay_socket_releases_reservation() {
+        let (_router, net, manager) = new_manager(AllocationQuota {
+            max_allocations: Some(1),
+            max_allocations_per_user: None,
+        });
+        let turn_socket: Arc<dyn Conn + Send + Sync> =
+            Arc::new(net.bind("10.0.0.1:3478".parse().unwrap()).unwrap());
+        let mut broken = AllocationManager::new(
+            RelayConfig {
+                relay_ip: "10.0.0.9".parse().unwrap(),
+                ..manager.relay_config.clone()
+            },
+            manager.quota,
+            Arc::new(Metrics::new()),
+            None,
+        );
+        assert!(broken
+            .create_allocation(
+                five_tuple(5000),
+                "alice".to_string(),
+                Duration::from_secs(600),
+                Arc::clone(&turn_socket),
+            )
+            .await
+            .is_err());
+        assert!(broken.reservations.get_mut().unwrap().is_empty());
+        assert!(broken.reserve(five_tuple(5001), "alice").await.is_ok());
+    }
+}
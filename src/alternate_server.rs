@@ -0,0 +1,266 @@
+use crate::error::*;
+use crate::xor_address::{FAMILY_IPV4, FAMILY_IPV6, IPV4_ADDRESS_SIZE, IPV6_ADDRESS_SIZE};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use stun::attribute::*;
+use stun::message::*;
+
+// RFC 5389 sec 15.11
+// 300 Try Alternateでclientに接続し直してもらうサーバーのアドレス。
+// XORせずにMAPPED-ADDRESSと同じ形式でエンコードする
+pub struct AlternateServer {
+    pub address: SocketAddr,
+}
+
+impl AlternateServer {
+    pub fn get_from(m: &Message) -> Result<Option<AlternateServer>> {
+        let attribute = match m.attributes.iter().find(|e| e.typ == ATTR_ALTERNATE_SERVER) {
+            Some(attribute) => attribute,
+            None => return Ok(None),
+        };
+        let raw = &attribute.value;
+        if raw.len() < IPV4_ADDRESS_SIZE {
+            return Err(Error::ErrBadAttributeLength);
+        }
+        let port = u16::from_be_bytes([raw[2], raw[3]]);
+        let ip = match raw[1] {
+            FAMILY_IPV4 => IpAddr::V4(Ipv4Addr::new(raw[4], raw[5], raw[6], raw[7])),
+            FAMILY_IPV6 if raw.len() >= IPV6_ADDRESS_SIZE => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&raw[4..IPV6_ADDRESS_SIZE]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(Error::ErrBadAttributeLength),
+        };
+        Ok(Some(AlternateServer {
+            address: SocketAddr::new(ip, port),
+        }))
+    }
+}
+
+impl Setter for AlternateServer {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let mut raw = vec![0u8; 4];
+        raw[2..4].copy_from_slice(&self.address.port().to_be_bytes());
+        match self.address.ip() {
+            IpAddr::V4(ip) => {
+                raw[1] = FAMILY_IPV4;
+                raw.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                raw[1] = FAMILY_IPV6;
+                raw.extend_from_slice(&ip.octets());
+            }
+        }
+        let extra_attribute = Attribute::new(ATTR_ALTERNATE_SERVER, raw.len() as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
+
+// RFC 8489 sec 14.16
+// TLSで接続し直す時に、証明書の検証に使うalternate serverのドメイン名
+pub struct AlternateDomain(pub String);
+
+impl Setter for AlternateDomain {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let raw = self.0.as_bytes().to_vec();
+        let extra_attribute = Attribute::new(ATTR_ALTERNATE_DOMAIN, raw.len() as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
+
+// clientを向かわせる先
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub address: SocketAddr,
+    // TLSの場合だけ必要。ALTERNATE-DOMAINとして返す
+    pub domain: Option<String>,
+}
+
+impl Redirect {
+    pub fn new(address: SocketAddr) -> Self {
+        Redirect {
+            address,
+            domain: None,
+        }
+    }
+}
+
+// redirectするかを判断する時のサーバーの状態
+#[derive(Debug, Clone, Copy)]
+pub struct ServerLoad {
+    pub allocations: usize,
+    // graceful shutdown中ならtrue
+    pub draining: bool,
+}
+
+// 新しいAllocateを別のサーバーに向かわせるかを決める。Noneなら自分で処理する
+pub trait RedirectPolicy: Send + Sync {
+    fn redirect(&self, username: &str, src_addr: SocketAddr, load: &ServerLoad)
+        -> Option<Redirect>;
+}
+
+// 全てのAllocateを同じサーバーに向かわせる。メンテナンス中に使う
+pub struct StaticRedirectPolicy {
+    redirect: Redirect,
+}
+
+impl StaticRedirectPolicy {
+    pub fn new(redirect: Redirect) -> Self {
+        StaticRedirectPolicy { redirect }
+    }
+}
+
+impl RedirectPolicy for StaticRedirectPolicy {
+    fn redirect(&self, _: &str, _: SocketAddr, _: &ServerLoad) -> Option<Redirect> {
+        Some(self.redirect.clone())
+    }
+}
+
+// 複数のサーバーに順番に振り分ける
+pub struct RoundRobinRedirectPolicy {
+    redirects: Vec<Redirect>,
+    next: AtomicUsize,
+}
+
+impl RoundRobinRedirectPolicy {
+    pub fn new(redirects: Vec<Redirect>) -> Self {
+        RoundRobinRedirectPolicy {
+            redirects,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl RedirectPolicy for RoundRobinRedirectPolicy {
+    fn redirect(&self, _: &str, _: SocketAddr, _: &ServerLoad) -> Option<Redirect> {
+        if self.redirects.is_empty() {
+            return None;
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.redirects.len();
+        Some(self.redirects[i].clone())
+    }
+}
+
+// allocationがmax_allocationsに達したか、draining中の時だけinnerに従ってredirectする
+pub struct LoadBasedRedirectPolicy {
+    max_allocations: usize,
+    inner: Box<dyn RedirectPolicy>,
+}
+
+impl LoadBasedRedirectPolicy {
+    pub fn new(max_allocations: usize, inner: Box<dyn RedirectPolicy>) -> Self {
+        LoadBasedRedirectPolicy {
+            max_allocations,
+            inner,
+        }
+    }
+}
+
+impl RedirectPolicy for LoadBasedRedirectPolicy {
+    fn redirect(
+        &self,
+        username: &str,
+        src_addr: SocketAddr,
+        load: &ServerLoad,
+    ) -> Option<Redirect> {
+        if load.draining || load.allocations >= self.max_allocations {
+            self.inner.redirect(username, src_addr, load)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USERNAME: &str = "alice";
+
+    fn src_addr() -> SocketAddr {
+        "10.0.0.2:5000".parse().unwrap()
+    }
+
+    fn load(allocations: usize, draining: bool) -> ServerLoad {
+        ServerLoad {
+            allocations,
+            draining,
+        }
+    }
+
+    fn redirect(address: &str) -> Redirect {
+        Redirect::new(address.parse().unwrap())
+    }
+
+    #[test]
+    fn test_static_policy() {
+        let mut target = redirect("203.0.113.6:3478");
+        target.domain = Some("turn2.example.org".to_string());
+        let policy = StaticRedirectPolicy::new(target.clone());
+        for allocations in [0, 1000] {
+            assert_eq!(
+                policy.redirect(USERNAME, src_addr(), &load(allocations, false)),
+                Some(target.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_round_robin_policy() {
+        let targets = vec![
+            redirect("203.0.113.6:3478"),
+            redirect("203.0.113.7:3478"),
+            redirect("[2001:db8::8]:3478"),
+        ];
+        let policy = RoundRobinRedirectPolicy::new(targets.clone());
+        let picked: Vec<_> = (0..6)
+            .map(|_| {
+                policy
+                    .redirect(USERNAME, src_addr(), &load(0, false))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(picked[..3], targets[..]);
+        assert_eq!(picked[3..], targets[..]);
+
+        let empty = RoundRobinRedirectPolicy::new(vec![]);
+        assert_eq!(empty.redirect(USERNAME, src_addr(), &load(0, false)), None);
+    }
+
+    #[test]
+    fn test_load_based_policy() {
+        let target = redirect("203.0.113.6:3478");
+        let policy =
+            LoadBasedRedirectPolicy::new(100, Box::new(StaticRedirectPolicy::new(target.clone())));
+        assert_eq!(
+            policy.redirect(USERNAME, src_addr(), &load(99, false)),
+            None
+        );
+        assert_eq!(
+            policy.redirect(USERNAME, src_addr(), &load(100, false)),
+            Some(target.clone())
+        );
+        // draining中は空いていてもredirectする
+        assert_eq!(
+            policy.redirect(USERNAME, src_addr(), &load(0, true)),
+            Some(target)
+        );
+    }
+
+    #[test]
+    fn test_alternate_server_round_trip() {
+        for address in &["203.0.113.6:3478", "[2001:db8::8]:5349"] {
+            let address: SocketAddr = address.parse().unwrap();
+            let mut m = Message::new(METHOD_ALLOCATE, CLASS_ERROR);
+            m.set_extra_attribute(Box::new(AlternateServer { address }))
+                .unwrap();
+            let decoded = AlternateServer::get_from(&m).unwrap().unwrap();
+            assert_eq!(decoded.address, address);
+        }
+        let m = Message::new(METHOD_ALLOCATE, CLASS_ERROR);
+        assert!(AlternateServer::get_from(&m).unwrap().is_none());
+    }
+}
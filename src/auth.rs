@@ -0,0 +1,114 @@
+use crate::error::*;
+use crate::integrity::MessageIntegrity;
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+// usernameとrealmからlong-term credentialのkeyを引く
+pub trait AuthHandler: Send + Sync {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<MessageIntegrity>;
+
+    // RFC 7635 sec 6.2
+    // ACCESS-TOKENのあるrequestでは、USERNAMEがkidになり、tokenの中のmac_keyをkeyとして使う
+    fn access_token_handle(
+        &self,
+        _kid: &str,
+        _access_token: &[u8],
+        _src_addr: SocketAddr,
+    ) -> Result<MessageIntegrity> {
+        Err(Error::ErrNoSuchUser)
+    }
+
+    // RFC 7635 sec 6.1
+    // Someなら401のresponseにTHIRD-PARTY-AUTHORIZATIONとして認可サーバーの名前を入れる
+    fn third_party_authorization(&self) -> Option<String> {
+        None
+    }
+}
+
+// RFC 5389 sec 15.4
+// key = MD5(username ":" realm ":" password)
+pub fn generate_auth_key(username: &str, realm: &str, password: &str) -> MessageIntegrity {
+    let mut h = Md5::new();
+    h.update(format!("{}:{}:{}", username, realm, password).as_bytes());
+    MessageIntegrity(h.finalize().to_vec())
+}
+
+// 設定ファイルなどで与えられた固定のユーザーとパスワードで認証する
+pub struct StaticAuthHandler {
+    // username -> key
+    keys: HashMap<String, MessageIntegrity>,
+}
+
+impl StaticAuthHandler {
+    pub fn new(realm: &str, users: &HashMap<String, String>) -> Self {
+        let keys = users
+            .iter()
+            .map(|(username, password)| {
+                (
+                    username.clone(),
+                    generate_auth_key(username, realm, password),
+                )
+            })
+            .collect();
+        StaticAuthHandler { keys }
+    }
+}
+
+impl AuthHandler for StaticAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        _realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<MessageIntegrity> {
+        self.keys.get(username).cloned().ok_or(Error::ErrNoSuchUser)
+    }
+}
+
+// TURN REST API (draft-uberti-behave-turn-rest)
+// username = "<有効期限のunix時間>:<ユーザーID>", password = base64(HMAC-SHA1(secret, username))
+// アプリケーションサーバーとsecretを共有するだけで、期限付きのcredentialを発行できる
+pub struct SharedSecretAuthHandler {
+    shared_secret: String,
+}
+
+impl SharedSecretAuthHandler {
+    pub fn new(shared_secret: String) -> Self {
+        SharedSecretAuthHandler { shared_secret }
+    }
+}
+
+impl AuthHandler for SharedSecretAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<MessageIntegrity> {
+        let expires_at = username
+            .split(':')
+            .next()
+            .and_then(|t| t.parse::<u64>().ok())
+            .ok_or(Error::ErrNoSuchUser)?;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        if expires_at < now {
+            return Err(Error::ErrCredentialExpired);
+        }
+        let mut mac = Hmac::<Sha1>::new_from_slice(self.shared_secret.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(username.as_bytes());
+        let password = base64::encode(mac.finalize().into_bytes());
+        Ok(generate_auth_key(username, realm, &password))
+    }
+}
@@ -0,0 +1,642 @@
+use crate::server::INBOUND_MTU;
+use crate::util::{Conn, Error, Result};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{watch, Mutex};
+
+// 1回のrecvmmsg/sendmmsgで扱うdatagramの最大数
+pub const BATCH_SIZE: usize = 32;
+// GROが有効な時は、同じpeerからの複数のdatagramが1つのbufferに連結されて届く
+const GRO_BUFFER_SIZE: usize = 65535;
+
+// recvmmsgで受信したが、まだrecv_fromで返していないdatagram。
+// bufs[slot][offset..offset + len] に入っている
+#[derive(Debug, Clone, Copy)]
+struct Datagram {
+    slot: usize,
+    offset: usize,
+    len: usize,
+    addr: SocketAddr,
+}
+
+struct RecvState {
+    bufs: Vec<Vec<u8>>,
+    pending: VecDeque<Datagram>,
+}
+
+// Linuxではrecvmmsg/sendmmsgでまとめて送受信し、カーネルが対応していればUDP GSO/GROも使う。
+// それ以外のOSや、recvmmsgが使えないカーネルでは普通のUdpSocketと同じ動きになる
+pub struct BatchUdpConn {
+    // closeでNoneにする。受信中のものが手放した時点でsocketが閉じる
+    socket: std::sync::Mutex<Option<Arc<UdpSocket>>>,
+    recv_state: Mutex<RecvState>,
+    // recvmmsg/sendmmsgがENOSYSなどで失敗したらfalseにして、以後は1つずつ送受信する
+    batch: AtomicBool,
+    gso: AtomicBool,
+    gro: AtomicBool,
+    // closeで待っているrecv_fromを起こす
+    close_tx: watch::Sender<bool>,
+}
+
+impl BatchUdpConn {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(BatchUdpConn::new(UdpSocket::bind(addr).await?))
+    }
+
+    pub fn new(socket: UdpSocket) -> Self {
+        let (batch, gso, gro) = probe_features(&socket);
+        let buffer_size = if gro { GRO_BUFFER_SIZE } else { INBOUND_MTU };
+        let slots = if batch { BATCH_SIZE } else { 1 };
+        let (close_tx, _) = watch::channel(false);
+        BatchUdpConn {
+            socket: std::sync::Mutex::new(Some(Arc::new(socket))),
+            recv_state: Mutex::new(RecvState {
+                bufs: vec![vec![0u8; buffer_size]; slots],
+                pending: VecDeque::with_capacity(slots),
+            }),
+            batch: AtomicBool::new(batch),
+            gso: AtomicBool::new(gso),
+            gro: AtomicBool::new(gro),
+            close_tx,
+        }
+    }
+
+    pub fn is_batch_enabled(&self) -> bool {
+        self.batch.load(Ordering::Relaxed)
+    }
+
+    pub fn is_gso_enabled(&self) -> bool {
+        self.gso.load(Ordering::Relaxed)
+    }
+
+    pub fn is_gro_enabled(&self) -> bool {
+        self.gro.load(Ordering::Relaxed)
+    }
+
+    fn socket(&self) -> Result<Arc<UdpSocket>> {
+        self.socket
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(Error::ErrUseClosedNetworkConn)
+    }
+
+    async fn fill(&self, socket: &UdpSocket, state: &mut RecvState) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.is_batch_enabled() {
+                match self.recv_batch(socket, state).await {
+                    Ok(()) => return Ok(()),
+                    Err(err) if sys::is_unsupported(&err) => {
+                        tracing::info!(error = %err, "recvmmsg is not supported, falling back");
+                        self.batch.store(false, Ordering::Relaxed);
+                        // recv_fromではGROで連結されたdatagramを分けられないので無効にする
+                        if self.is_gro_enabled() {
+                            use std::os::unix::io::AsRawFd;
+                            sys::set_gro(socket.as_raw_fd(), false);
+                            self.gro.store(false, Ordering::Relaxed);
+                        }
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+        let (len, addr) = socket.recv_from(&mut state.bufs[0]).await?;
+        state.pending.push_back(Datagram {
+            slot: 0,
+            offset: 0,
+            len,
+            addr,
+        });
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_batch(&self, socket: &UdpSocket, state: &mut RecvState) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::Interest;
+        let fd = socket.as_raw_fd();
+        let RecvState { bufs, pending } = state;
+        loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || sys::recv_batch(fd, bufs, pending)) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn send_batch_linux(&self, packets: &[(&[u8], SocketAddr)]) -> std::io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::Interest;
+        let fd = self.socket.as_raw_fd();
+        // 宛先が全て同じで、最後以外が同じ長さならGSOで1回のsendmsgにまとめられる
+        if self.is_gso_enabled() {
+            if let Some(segment_size) = sys::gso_segment_size(packets) {
+                let target = packets[0].1;
+                loop {
+                    self.socket.writable().await?;
+                    match self.socket.try_io(Interest::WRITABLE, || {
+                        sys::send_segments(fd, packets, segment_size, target)
+                    }) {
+                        Ok(()) => return Ok(packets.len()),
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                        Err(err) if sys::is_unsupported(&err) => {
+                            tracing::info!(error = %err, "UDP GSO is not supported, falling back");
+                            self.gso.store(false, Ordering::Relaxed);
+                            break;
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+        }
+        let mut sent = 0;
+        while sent < packets.len() {
+            self.socket.writable().await?;
+            match self
+                .socket
+                .try_io(Interest::WRITABLE, || sys::send_batch(fd, &packets[sent..]))
+            {
+                Ok(n) => sent += n,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn probe_features(socket: &UdpSocket) -> (bool, bool, bool) {
+    use std::os::unix::io::AsRawFd;
+    let fd = socket.as_raw_fd();
+    (true, sys::probe_gso(fd), sys::set_gro(fd, true))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_features(_socket: &UdpSocket) -> (bool, bool, bool) {
+    (false, false, false)
+}
+
+#[async_trait]
+impl Conn for BatchUdpConn {
+    async fn connect(&self, addr: SocketAddr) -> Result<()> {
+        Ok(self.socket()?.connect(addr).await?)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    // recvmmsgで溜めたdatagramを1つずつ返す。bufに入りきらない部分は切り捨てる
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        // 先にsubscribeしておけば、socketを取り出した後のcloseも取りこぼさない
+        let mut close_rx = self.close_tx.subscribe();
+        let socket = self.socket()?;
+        let mut state = tokio::select! {
+            state = self.recv_state.lock() => state,
+            _ = close_rx.changed() => return Err(Error::ErrUseClosedNetworkConn),
+        };
+        while state.pending.is_empty() {
+            tokio::select! {
+                v = self.fill(&socket, &mut state) => v?,
+                _ = close_rx.changed() => return Err(Error::ErrUseClosedNetworkConn),
+            }
+        }
+        let datagram = state
+            .pending
+            .pop_front()
+            .expect("pending datagrams were just filled");
+        let src = &state.bufs[datagram.slot][datagram.offset..datagram.offset + datagram.len];
+        let n = std::cmp::min(src.len(), buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        Ok((n, datagram.addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        Ok(self.socket()?.send(buf).await?)
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
+        Ok(self.socket()?.send_to(buf, target).await?)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket()?.local_addr()?)
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.socket().ok()?.peer_addr().ok()
+    }
+
+    async fn close(&self) -> Result<()> {
+        if self.socket.lock().unwrap().take().is_none() {
+            return Err(Error::ErrAlreadyClosed);
+        }
+        let _ = self.close_tx.send(true);
+        Ok(())
+    }
+
+    async fn send_batch(&self, packets: &[(&[u8], SocketAddr)]) -> Result<usize> {
+        self.check_closed()?;
+        #[cfg(target_os = "linux")]
+        {
+            if self.is_batch_enabled() {
+                match self.send_batch_linux(packets).await {
+                    Ok(n) => return Ok(n),
+                    Err(err) if sys::is_unsupported(&err) => {
+                        tracing::info!(error = %err, "sendmmsg is not supported, falling back");
+                        self.batch.store(false, Ordering::Relaxed);
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+        for (buf, target) in packets {
+            self.socket.send_to(buf, *target).await?;
+        }
+        Ok(packets.len())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) mod sys {
+    use super::{Datagram, BATCH_SIZE};
+    use std::collections::VecDeque;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::RawFd;
+    use std::ptr;
+
+    // linux/udp.h
+    const UDP_SEGMENT: libc::c_int = 103;
+    const UDP_GRO: libc::c_int = 104;
+    // 1回のsendmsgでGSOに渡せるsegmentの最大数と合計の最大長
+    const MAX_GSO_SEGMENTS: usize = 64;
+    const MAX_GSO_PAYLOAD: usize = 65507;
+    // cmsgを1つ入れるのに十分な大きさ。cmsghdrのalignmentに合わせてu64で確保する
+    const CONTROL_WORDS: usize = 8;
+
+    pub(super) fn is_unsupported(err: &io::Error) -> bool {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::EIO)
+        )
+    }
+
+    pub(super) fn probe_gso(fd: RawFd) -> bool {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_UDP,
+                UDP_SEGMENT,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        ret == 0
+    }
+
+    pub(super) fn set_gro(fd: RawFd, enabled: bool) -> bool {
+        let value = enabled as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_UDP,
+                UDP_GRO,
+                &value as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        ret == 0
+    }
+
+    pub(super) fn recv_batch(
+        fd: RawFd,
+        bufs: &mut [Vec<u8>],
+        pending: &mut VecDeque<Datagram>,
+    ) -> io::Result<()> {
+        let n = std::cmp::min(bufs.len(), BATCH_SIZE);
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut controls = [[0u64; CONTROL_WORDS]; BATCH_SIZE];
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for i in 0..n {
+            iovecs[i] = libc::iovec {
+                iov_base: bufs[i].as_mut_ptr() as *mut libc::c_void,
+                iov_len: bufs[i].len(),
+            };
+            let hdr = &mut msgs[i].msg_hdr;
+            hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovecs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = mem::size_of_val(&controls[i]) as _;
+        }
+        let ret = unsafe {
+            libc::recvmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                n as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for slot in 0..ret as usize {
+            let len = msgs[slot].msg_len as usize;
+            let addr = match from_sockaddr(&names[slot]) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            // GROで連結されていれば、segment_sizeごとに別のdatagramとして扱う
+            let segment_size = gro_segment_size(&msgs[slot].msg_hdr).unwrap_or(len).max(1);
+            let mut offset = 0;
+            while offset < len {
+                let segment_len = std::cmp::min(segment_size, len - offset);
+                pending.push_back(Datagram {
+                    slot,
+                    offset,
+                    len: segment_len,
+                    addr,
+                });
+                offset += segment_len;
+            }
+            if len == 0 {
+                pending.push_back(Datagram {
+                    slot,
+                    offset: 0,
+                    len: 0,
+                    addr,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+                    let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    return Some(size as usize);
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+
+    // GSOでは最後のsegment以外は全て同じ長さでなければならない
+    pub(super) fn gso_segment_size(packets: &[(&[u8], SocketAddr)]) -> Option<u16> {
+        if packets.len() < 2 || packets.len() > MAX_GSO_SEGMENTS {
+            return None;
+        }
+        let (first, target) = packets[0];
+        let segment_size = first.len();
+        if segment_size == 0 {
+            return None;
+        }
+        let mut total = 0;
+        for (i, (buf, addr)) in packets.iter().enumerate() {
+            let is_last = i == packets.len() - 1;
+            if *addr != target
+                || (!is_last && buf.len() != segment_size)
+                || (is_last && (buf.is_empty() || buf.len() > segment_size))
+            {
+                return None;
+            }
+            total += buf.len();
+        }
+        if total > MAX_GSO_PAYLOAD {
+            return None;
+        }
+        Some(segment_size as u16)
+    }
+
+    // packetsをiovecとして並べ、UDP_SEGMENTを付けて1回のsendmsgで送る
+    pub(super) fn send_segments(
+        fd: RawFd,
+        packets: &[(&[u8], SocketAddr)],
+        segment_size: u16,
+        target: SocketAddr,
+    ) -> io::Result<()> {
+        let mut iovecs: [libc::iovec; MAX_GSO_SEGMENTS] = unsafe { mem::zeroed() };
+        for (i, (buf, _)) in packets.iter().enumerate() {
+            iovecs[i] = libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+        }
+        let (mut name, namelen) = to_sockaddr(&target);
+        let mut control = [0u64; CONTROL_WORDS];
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = &mut name as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = namelen;
+        hdr.msg_iov = iovecs.as_mut_ptr();
+        hdr.msg_iovlen = packets.len() as _;
+        hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen =
+            unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as libc::c_uint) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as libc::c_uint) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+        }
+        let ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // 送れたdatagramの数を返す
+    pub(super) fn send_batch(fd: RawFd, packets: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let n = std::cmp::min(packets.len(), BATCH_SIZE);
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (i, (buf, target)) in packets[..n].iter().enumerate() {
+            let (name, namelen) = to_sockaddr(target);
+            names[i] = name;
+            iovecs[i] = libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            let hdr = &mut msgs[i].msg_hdr;
+            hdr.msg_name = &mut names[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = namelen;
+            hdr.msg_iov = &mut iovecs[i];
+            hdr.msg_iovlen = 1;
+        }
+        let ret = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), n as libc::c_uint, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = &mut storage as *mut _ as *mut libc::sockaddr_in;
+                unsafe {
+                    (*sin).sin_family = libc::AF_INET as libc::sa_family_t;
+                    (*sin).sin_port = addr.port().to_be();
+                    (*sin).sin_addr = libc::in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    };
+                }
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = &mut storage as *mut _ as *mut libc::sockaddr_in6;
+                unsafe {
+                    (*sin6).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    (*sin6).sin6_port = addr.port().to_be();
+                    (*sin6).sin6_flowinfo = addr.flowinfo();
+                    (*sin6).sin6_addr = libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    };
+                    (*sin6).sin6_scope_id = addr.scope_id();
+                }
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    pub(crate) fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    ip,
+                    u16::from_be(sin.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn recv_lengths(conn: &BatchUdpConn, count: usize) -> Vec<usize> {
+        let mut buf = vec![0u8; INBOUND_MTU];
+        let mut lengths = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (n, _) = tokio::time::timeout(Duration::from_secs(5), conn.recv_from(&mut buf))
+                .await
+                .expect("datagram was not delivered")
+                .unwrap();
+            lengths.push(n);
+        }
+        lengths
+    }
+
+    // 宛先が同じで最後以外が同じ長さならGSOで、そうでなければsendmmsgで送る。
+    // どちらでも受信側には別々のdatagramとして届く
+    #[tokio::test]
+    async fn test_send_batch_delivers_each_datagram() {
+        let sender = BatchUdpConn::bind("127.0.0.1:0").await.unwrap();
+        let receiver1 = BatchUdpConn::bind("127.0.0.1:0").await.unwrap();
+        let receiver2 = BatchUdpConn::bind("127.0.0.1:0").await.unwrap();
+        let target1 = receiver1.local_addr().await.unwrap();
+        let target2 = receiver2.local_addr().await.unwrap();
+
+        let full = [1u8; 100];
+        let short = [2u8; 40];
+        let same_target = vec![(&full[..], target1); 3]
+            .into_iter()
+            .chain(std::iter::once((&short[..], target1)))
+            .collect::<Vec<_>>();
+        assert_eq!(sender.send_batch(&same_target).await.unwrap(), 4);
+        assert_eq!(recv_lengths(&receiver1, 4).await, vec![100, 100, 100, 40]);
+
+        let mixed = vec![
+            (&full[..], target1),
+            (&short[..], target2),
+            (&full[..], target2),
+        ];
+        assert_eq!(sender.send_batch(&mixed).await.unwrap(), 3);
+        assert_eq!(recv_lengths(&receiver1, 1).await, vec![100]);
+        assert_eq!(recv_lengths(&receiver2, 2).await, vec![40, 100]);
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_after_close() {
+        let sender = BatchUdpConn::bind("127.0.0.1:0").await.unwrap();
+        let target = sender.local_addr().await.unwrap();
+        sender.close().await.unwrap();
+        assert!(sender.send_batch(&[(&[0u8; 4][..], target)]).await.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_gso_segment_size() {
+        let target: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let full = [0u8; 100];
+        let short = [0u8; 40];
+        let long = [0u8; 120];
+
+        assert_eq!(
+            sys::gso_segment_size(&[(&full[..], target), (&short[..], target)]),
+            Some(100)
+        );
+        assert_eq!(
+            sys::gso_segment_size(&[(&full[..], target), (&full[..], target)]),
+            Some(100)
+        );
+        // 1つだけならまとめる意味が無い
+        assert_eq!(sys::gso_segment_size(&[(&full[..], target)]), None);
+        // 宛先が違う
+        assert_eq!(
+            sys::gso_segment_size(&[(&full[..], target), (&full[..], other)]),
+            None
+        );
+        // 最後のsegmentだけが短くてよい
+        assert_eq!(
+            sys::gso_segment_size(&[(&short[..], target), (&full[..], target)]),
+            None
+        );
+        assert_eq!(
+            sys::gso_segment_size(&[(&full[..], target), (&long[..], target)]),
+            None
+        );
+        // 合計がUDPの最大長を超える
+        let big = [0u8; 1400];
+        assert_eq!(sys::gso_segment_size(&vec![(&big[..], target); 50]), None);
+    }
+}
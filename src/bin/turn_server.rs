@@ -0,0 +1,485 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::signal;
+use tracing_subscriber::EnvFilter;
+use turn::acl::{AclAction, AclSubject, Cidr, PeerAcl, PeerAclRule, PortRange};
+use turn::alternate_server::*;
+use turn::auth::*;
+use turn::batch_conn::BatchUdpConn;
+use turn::oauth::{AccessTokenKey, OAuthAuthHandler};
+use turn::pcap::{CaptureFilter, PcapTap};
+use turn::redact::{set_redact_usernames, set_redaction_salt};
+use turn::reuse_port::ReusePortListener;
+use turn::server::*;
+use turn::util::{Conn, UdpConn};
+
+// Ctrl-Cを受けてから既存のallocationが終わるのを待つ時間
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+// psや/proc/<pid>/cmdlineから見えないように、shared secretはargvではなくここから読む。
+// --users-fileか--shared-secret-fileが指定されていれば、この環境変数と設定ファイルのusers、shared_secretは使わない
+const SHARED_SECRET_ENV: &str = "TURN_SHARED_SECRET";
+const REDACT_SALT_ENV: &str = "TURN_REDACT_SALT";
+
+// 設定ファイル(TOML)の形式。CLIフラグで指定された値はこちらより優先される
+//
+//   listeners = ["0.0.0.0:3478"]
+//   realm = "example.org"
+//   relay_ip = "10.0.0.5"
+//   external_ip = "203.0.113.5"
+//   min_port = 49152
+//   max_port = 65535
+//   default_lifetime = 600
+//   max_lifetime = 3600
+//   max_allocations = 10000
+//   max_allocations_per_user = 10
+//   batch_io = true
+//   reuse_port = 4
+//   # draining中、またはallocationがredirect_above以上の時に300で振り分ける先
+//   alternate_servers = ["203.0.113.6:3478"]
+//   redirect_above = 8000
+//   # MOBILITY-TICKETでclientのアドレスが変わってもallocationを引き継げるようにする
+//   mobility = true
+//   # clientとpeerとの間のpacketをpcapngに書き出す。pcap_usersがあればそのユーザーだけ
+//   pcap = "/var/tmp/turn.pcapng"
+//   pcap_users = ["alice"]
+//   # "text" か "json"。redact_usernamesならログのusernameをhashに置き換える
+//   log_format = "json"
+//   redact_usernames = true
+//   # 省略するとhashは起動ごとに変わる。環境変数TURN_REDACT_SALTでも渡せる
+//   redact_salt = "..."
+//   # usersかshared_secretのどちらか。shared_secretは環境変数TURN_SHARED_SECRETでも渡せる。
+//   # --users-fileか--shared-secret-fileを指定すると、どちらも無視される
+//   shared_secret = "..."
+//   # 上から順に評価し、どれにもマッチしなければpeer_acl_defaultを使う(省略時は"allow")
+//   peer_acl_default = "allow"
+//   # ACCESS-TOKENでも認証する場合は認可サーバーの名前と、kidごとのkey(base64)
+//   oauth_server_name = "auth.example.org"
+//   [users]
+//   alice = "password"
+//   [oauth_keys]
+//   kid1 = "base64 encoded 16 or 32 bytes"
+//   # userとrealmを省略すると全てのユーザーにマッチする。portsも省略すれば全てのport
+//   [[peer_acl]]
+//   realm = "internal"
+//   cidr = "10.0.0.0/8"
+//   action = "allow"
+//   [[peer_acl]]
+//   cidr = "10.0.0.0/8"
+//   min_port = 1
+//   max_port = 65535
+//   action = "deny"
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    listeners: Option<Vec<SocketAddr>>,
+    realm: Option<String>,
+    users: Option<HashMap<String, String>>,
+    shared_secret: Option<String>,
+    relay_ip: Option<IpAddr>,
+    external_ip: Option<IpAddr>,
+    min_port: Option<u16>,
+    max_port: Option<u16>,
+    default_lifetime: Option<u64>,
+    max_lifetime: Option<u64>,
+    max_allocations: Option<usize>,
+    max_allocations_per_user: Option<usize>,
+    metrics_address: Option<SocketAddr>,
+    admin_address: Option<SocketAddr>,
+    drain_timeout: Option<u64>,
+    request_workers: Option<usize>,
+    batch_io: Option<bool>,
+    reuse_port: Option<usize>,
+    alternate_servers: Option<Vec<SocketAddr>>,
+    redirect_above: Option<usize>,
+    mobility: Option<bool>,
+    pcap: Option<PathBuf>,
+    pcap_users: Option<Vec<String>>,
+    log_format: Option<String>,
+    redact_usernames: Option<bool>,
+    redact_salt: Option<String>,
+    oauth_server_name: Option<String>,
+    oauth_keys: Option<HashMap<String, String>>,
+    peer_acl: Option<Vec<PeerAclRuleConfig>>,
+    peer_acl_default: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PeerAclRuleConfig {
+    user: Option<String>,
+    realm: Option<String>,
+    cidr: String,
+    min_port: Option<u16>,
+    max_port: Option<u16>,
+    action: String,
+}
+
+#[derive(Debug, Parser)]
+#[clap(name = "turn-server", about = "TURN server")]
+struct Cli {
+    /// Path to a TOML config file
+    #[clap(long, short)]
+    config: Option<PathBuf>,
+    /// Address to listen on (repeatable)
+    #[clap(long = "listen")]
+    listeners: Vec<SocketAddr>,
+    #[clap(long)]
+    realm: Option<String>,
+    /// File with one username=password per line. Overrides users and shared_secret
+    /// from the config file and TURN_SHARED_SECRET
+    #[clap(long)]
+    users_file: Option<PathBuf>,
+    /// File containing the shared secret for ephemeral credentials. Overrides users and
+    /// shared_secret from the config file and TURN_SHARED_SECRET
+    #[clap(long)]
+    shared_secret_file: Option<PathBuf>,
+    /// IP to bind relay sockets on
+    #[clap(long)]
+    relay_ip: Option<IpAddr>,
+    /// IP advertised in XOR-RELAYED-ADDRESS when running behind NAT
+    #[clap(long)]
+    external_ip: Option<IpAddr>,
+    #[clap(long)]
+    min_port: Option<u16>,
+    #[clap(long)]
+    max_port: Option<u16>,
+    /// Default allocation lifetime in seconds
+    #[clap(long)]
+    default_lifetime: Option<u64>,
+    /// Maximum allocation lifetime in seconds
+    #[clap(long)]
+    max_lifetime: Option<u64>,
+    #[clap(long)]
+    max_allocations: Option<usize>,
+    #[clap(long)]
+    max_allocations_per_user: Option<usize>,
+    #[clap(long)]
+    metrics_address: Option<SocketAddr>,
+    /// Loopback address for the unauthenticated admin API
+    #[clap(long)]
+    admin_address: Option<SocketAddr>,
+    /// Seconds to wait for allocations to finish on shutdown
+    #[clap(long)]
+    drain_timeout: Option<u64>,
+    /// Number of concurrent request workers per listener
+    #[clap(long)]
+    request_workers: Option<usize>,
+    /// Receive and send in batches with recvmmsg/sendmmsg and UDP GSO/GRO (Linux only)
+    #[clap(long)]
+    batch_io: bool,
+    /// Open this many SO_REUSEPORT sockets per listener, each with its own read loop
+    #[clap(long)]
+    reuse_port: Option<usize>,
+    /// Server to redirect new allocations to with 300 Try Alternate (repeatable)
+    #[clap(long = "alternate-server")]
+    alternate_servers: Vec<SocketAddr>,
+    /// Redirect new allocations once this many are active. Without it, only while draining
+    #[clap(long)]
+    redirect_above: Option<usize>,
+    /// Issue MOBILITY-TICKETs so clients can move their allocation to a new address (RFC 8016)
+    #[clap(long)]
+    mobility: bool,
+    /// Write client and peer traffic to this pcapng file
+    #[clap(long)]
+    pcap: Option<PathBuf>,
+    /// Only capture allocations of this user (repeatable)
+    #[clap(long = "pcap-user")]
+    pcap_users: Vec<String>,
+    /// Log output format: text or json. Filter with RUST_LOG
+    #[clap(long)]
+    log_format: Option<String>,
+    /// Replace usernames in logs with a keyed hash. Set redact_salt to keep it stable across restarts
+    #[clap(long)]
+    redact_usernames: bool,
+}
+
+fn load_file_config(path: &Option<PathBuf>) -> Result<FileConfig> {
+    match path {
+        Some(path) => Ok(toml::from_str(&read_file(path)?)?),
+        None => Ok(FileConfig::default()),
+    }
+}
+
+// 依存クレートがlogクレートで出したログもtracing-subscriberが拾うので、同じ形式で出力される
+fn init_tracing(log_format: Option<&str>) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match log_format.unwrap_or("text") {
+        "text" => builder.try_init(),
+        "json" => builder.json().try_init(),
+        log_format => return Err(anyhow!("unknown log_format: {}", log_format)),
+    };
+    result.map_err(|err| anyhow!("failed to initialize logging: {}", err))
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))
+}
+
+// 1行に1つずつusername=password。空行と#で始まる行は読み飛ばす
+fn parse_users(content: &str) -> Result<HashMap<String, String>> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| match line.split_once('=') {
+            Some((username, password)) if !username.is_empty() => {
+                Ok((username.to_string(), password.to_string()))
+            }
+            // 行の内容はpasswordを含むので、エラーには行番号だけを出す
+            _ => Err(anyhow!(
+                "users file line {} must be in the form username=password",
+                i + 1
+            )),
+        })
+        .collect()
+}
+
+// エディタが付ける末尾の改行はsecretに含めない
+fn parse_shared_secret(content: &str) -> Result<String> {
+    let secret = content.trim_end_matches(|c| c == '\n' || c == '\r');
+    if secret.is_empty() {
+        return Err(anyhow!("shared secret file is empty"));
+    }
+    Ok(secret.to_string())
+}
+
+fn parse_oauth_keys(keys: &HashMap<String, String>) -> Result<Vec<AccessTokenKey>> {
+    keys.iter()
+        .map(|(kid, key)| {
+            let key = base64::decode(key)
+                .map_err(|err| anyhow!("invalid oauth key for {}: {}", kid, err))?;
+            if key.len() != 16 && key.len() != 32 {
+                return Err(anyhow!("oauth key for {} must be 16 or 32 bytes", kid));
+            }
+            Ok(AccessTokenKey {
+                kid: kid.clone(),
+                key,
+            })
+        })
+        .collect()
+}
+
+fn parse_acl_action(action: &str) -> Result<AclAction> {
+    match action {
+        "allow" => Ok(AclAction::Allow),
+        "deny" => Ok(AclAction::Deny),
+        action => Err(anyhow!("peer_acl action must be allow or deny: {}", action)),
+    }
+}
+
+fn parse_peer_acl(rules: &[PeerAclRuleConfig], default_action: Option<&str>) -> Result<PeerAcl> {
+    let rules = rules
+        .iter()
+        .map(|rule| {
+            let subject = match (&rule.user, &rule.realm) {
+                (Some(_), Some(_)) => {
+                    return Err(anyhow!("peer_acl rule can have either user or realm"))
+                }
+                (Some(user), None) => AclSubject::User(user.clone()),
+                (None, Some(realm)) => AclSubject::Realm(realm.clone()),
+                (None, None) => AclSubject::Any,
+            };
+            let cidr: Cidr = rule.cidr.parse()?;
+            let acl_rule = PeerAclRule::new(subject, cidr, parse_acl_action(&rule.action)?);
+            match (rule.min_port, rule.max_port) {
+                (Some(start), Some(end)) => Ok(acl_rule.with_ports(PortRange { start, end })),
+                (None, None) => Ok(acl_rule),
+                _ => Err(anyhow!(
+                    "peer_acl min_port and max_port must be set together"
+                )),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(PeerAcl {
+        rules,
+        default_action: parse_acl_action(default_action.unwrap_or("allow"))?,
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let file = load_file_config(&cli.config)?;
+    init_tracing(cli.log_format.as_deref().or(file.log_format.as_deref()))?;
+    set_redact_usernames(cli.redact_usernames || file.redact_usernames.unwrap_or(false));
+    if let Some(salt) = std::env::var(REDACT_SALT_ENV).ok().or(file.redact_salt) {
+        set_redaction_salt(salt.as_bytes());
+    }
+
+    let listeners = if !cli.listeners.is_empty() {
+        cli.listeners
+    } else {
+        file.listeners.unwrap_or_default()
+    };
+    let realm = cli
+        .realm
+        .or(file.realm)
+        .ok_or_else(|| anyhow!("realm is required"))?;
+    // CLIで指定したファイル > 環境変数 > 設定ファイル の順に優先する
+    let (users, shared_secret) = match (&cli.users_file, &cli.shared_secret_file) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow!(
+                "--users-file and --shared-secret-file are exclusive"
+            ))
+        }
+        (Some(path), None) => (parse_users(&read_file(path)?)?, None),
+        (None, Some(path)) => (
+            HashMap::new(),
+            Some(parse_shared_secret(&read_file(path)?)?),
+        ),
+        (None, None) => (
+            file.users.unwrap_or_default(),
+            std::env::var(SHARED_SECRET_ENV).ok().or(file.shared_secret),
+        ),
+    };
+    let password_auth_handler: Option<Arc<dyn AuthHandler>> =
+        match (shared_secret, users.is_empty()) {
+            (Some(_), false) => return Err(anyhow!("users and shared_secret are exclusive")),
+            (Some(shared_secret), true) => {
+                Some(Arc::new(SharedSecretAuthHandler::new(shared_secret)))
+            }
+            (None, false) => Some(Arc::new(StaticAuthHandler::new(&realm, &users))),
+            (None, true) => None,
+        };
+    let auth_handler: Arc<dyn AuthHandler> = match (file.oauth_server_name, password_auth_handler) {
+        (Some(server_name), password_auth_handler) => {
+            let keys = parse_oauth_keys(&file.oauth_keys.unwrap_or_default())?;
+            let oauth_auth_handler = OAuthAuthHandler::new(server_name, keys);
+            match password_auth_handler {
+                Some(fallback) => Arc::new(oauth_auth_handler.with_fallback(fallback)),
+                None => Arc::new(oauth_auth_handler),
+            }
+        }
+        (None, Some(password_auth_handler)) => password_auth_handler,
+        (None, None) => {
+            return Err(anyhow!(
+                "either users, shared_secret or oauth_server_name is required"
+            ))
+        }
+    };
+    let relay_ip = match cli.relay_ip.or(file.relay_ip) {
+        Some(relay_ip) => relay_ip,
+        None => listeners
+            .first()
+            .map(|listener| listener.ip())
+            .ok_or_else(|| anyhow!("at least one listener is required"))?,
+    };
+
+    let batch_io = cli.batch_io || file.batch_io.unwrap_or(false);
+    let reuse_port = cli.reuse_port.or(file.reuse_port);
+    let mut conn_configs: Vec<Arc<dyn Conn + Send + Sync>> = vec![];
+    let mut reuse_port_listeners = vec![];
+    for listener in &listeners {
+        if let Some(sockets) = reuse_port {
+            reuse_port_listeners.push(ReusePortListener {
+                address: *listener,
+                sockets,
+                batch_io,
+            });
+            continue;
+        }
+        let socket = UdpSocket::bind(listener).await?;
+        tracing::info!(address = %socket.local_addr()?, "listening");
+        if batch_io {
+            let conn = BatchUdpConn::new(socket);
+            tracing::info!(
+                mmsg = conn.is_batch_enabled(),
+                gso = conn.is_gso_enabled(),
+                gro = conn.is_gro_enabled(),
+                "batch I/O"
+            );
+            conn_configs.push(Arc::new(conn));
+        } else {
+            conn_configs.push(Arc::new(UdpConn::new(socket)));
+        }
+    }
+
+    let mut config = ServerConfig::new(conn_configs, realm, auth_handler, relay_ip);
+    config.reuse_port_listeners = reuse_port_listeners;
+    let alternate_servers = if !cli.alternate_servers.is_empty() {
+        cli.alternate_servers
+    } else {
+        file.alternate_servers.unwrap_or_default()
+    };
+    if !alternate_servers.is_empty() {
+        let redirect_above = cli
+            .redirect_above
+            .or(file.redirect_above)
+            .unwrap_or(usize::MAX);
+        config.redirect_policy = Some(Arc::new(LoadBasedRedirectPolicy::new(
+            redirect_above,
+            Box::new(RoundRobinRedirectPolicy::new(
+                alternate_servers.into_iter().map(Redirect::new).collect(),
+            )),
+        )));
+    }
+    config.mobility = cli.mobility || file.mobility.unwrap_or(false);
+    if let Some(pcap) = cli.pcap.or(file.pcap) {
+        let pcap_users = if !cli.pcap_users.is_empty() {
+            cli.pcap_users
+        } else {
+            file.pcap_users.unwrap_or_default()
+        };
+        let filter = if pcap_users.is_empty() {
+            None
+        } else {
+            Some(CaptureFilter {
+                usernames: pcap_users,
+                allocation_ids: vec![],
+            })
+        };
+        tracing::info!(path = %pcap.display(), "capturing packets");
+        config.pcap = Some(PcapTap::create(&pcap, filter)?);
+    }
+    config.external_ip = cli.external_ip.or(file.external_ip);
+    config.relay_port_range = match (
+        cli.min_port.or(file.min_port),
+        cli.max_port.or(file.max_port),
+    ) {
+        (Some(start), Some(end)) => Some(PortRange { start, end }),
+        (None, None) => None,
+        _ => return Err(anyhow!("min_port and max_port must be set together")),
+    };
+    if let Some(default_lifetime) = cli.default_lifetime.or(file.default_lifetime) {
+        config.default_lifetime = Duration::from_secs(default_lifetime);
+    }
+    if let Some(max_lifetime) = cli.max_lifetime.or(file.max_lifetime) {
+        config.max_lifetime = Duration::from_secs(max_lifetime);
+    }
+    config.max_allocations = cli.max_allocations.or(file.max_allocations);
+    config.max_allocations_per_user = cli
+        .max_allocations_per_user
+        .or(file.max_allocations_per_user);
+    config.metrics_address = cli.metrics_address.or(file.metrics_address);
+    config.admin_address = cli.admin_address.or(file.admin_address);
+    if file.peer_acl.is_some() || file.peer_acl_default.is_some() {
+        config.peer_acl = Some(Arc::new(parse_peer_acl(
+            &file.peer_acl.unwrap_or_default(),
+            file.peer_acl_default.as_deref(),
+        )?));
+    }
+    if let Some(request_workers) = cli.request_workers.or(file.request_workers) {
+        config.request_workers = request_workers;
+    }
+    let drain_timeout = Duration::from_secs(
+        cli.drain_timeout
+            .or(file.drain_timeout)
+            .unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECS),
+    );
+
+    let server = Server::new(config).await?;
+    tracing::info!("Waiting for Ctrl-C...");
+    signal::ctrl_c().await?;
+    tracing::info!(timeout = ?drain_timeout, "draining allocations");
+    server.close_gracefully(drain_timeout).await?;
+    tracing::info!("closed");
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use crate::error::*;
+use stun::attribute::*;
+use stun::message::*;
+
+// RFC 5766 sec 14.1
+// ChannelBindで使うchannel number。上位2byteが番号で、下位2byteはRFFU(0)
+pub struct ChannelNumber(pub u16);
+
+const CHANNEL_NUMBER_SIZE: usize = 4;
+// RFC 5766 sec 11
+// clientが使えるchannel numberは0x4000-0x7FFFのみ
+pub const MIN_CHANNEL_NUMBER: u16 = 0x4000;
+pub const MAX_CHANNEL_NUMBER: u16 = 0x7FFF;
+
+impl ChannelNumber {
+    pub fn get_from(m: &Message) -> Result<Option<ChannelNumber>> {
+        let attribute = match m.attributes.iter().find(|e| e.typ == ATTR_CHANNEL_NUMBER) {
+            Some(attribute) => attribute,
+            None => return Ok(None),
+        };
+        if attribute.value.len() != CHANNEL_NUMBER_SIZE {
+            return Err(Error::ErrBadAttributeLength);
+        }
+        Ok(Some(ChannelNumber(u16::from_be_bytes([
+            attribute.value[0],
+            attribute.value[1],
+        ]))))
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (MIN_CHANNEL_NUMBER..=MAX_CHANNEL_NUMBER).contains(&self.0)
+    }
+}
+
+impl Setter for ChannelNumber {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let mut raw = vec![0u8; CHANNEL_NUMBER_SIZE];
+        raw[..2].copy_from_slice(&self.0.to_be_bytes());
+        let extra_attribute = Attribute::new(ATTR_CHANNEL_NUMBER, CHANNEL_NUMBER_SIZE as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
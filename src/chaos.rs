@@ -0,0 +1,366 @@
+use crate::util::{Conn, Error, Result};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+const DEFAULT_QUEUE_LIMIT: usize = 1000;
+
+// 送信するpacketに損失・重複・順序の入れ替わり・遅延・帯域制限を入れる設定。
+// rateは0.0から1.0の確率で、全て0なら何もしない
+#[derive(Debug, Clone)]
+pub struct ChaosConfig {
+    // 同じseedなら、同じ順で送ったpacketに同じことが起きる
+    pub seed: u64,
+    pub loss_rate: f64,
+    pub duplicate_rate: f64,
+    // reorder_rateで選ばれたpacketはreorder_delayだけ余計に遅らせ、後のpacketに追い越させる
+    pub reorder_rate: f64,
+    pub reorder_delay: Duration,
+    // 全てのpacketにかかる遅延と、それに足す0からjitterまでのランダムな遅延
+    pub delay: Duration,
+    pub jitter: Duration,
+    // 1秒あたりに送れるbyte数。Noneなら制限しない。超えた分は送れるようになるまで待たせる
+    pub bandwidth: Option<u64>,
+    // bandwidthの制限で待っているpacketの数の上限。溢れたpacketはルーターと同じように捨てる(drop-tail)
+    pub queue_limit: usize,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            seed: 0,
+            loss_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            reorder_delay: Duration::from_millis(20),
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+            queue_limit: DEFAULT_QUEUE_LIMIT,
+        }
+    }
+}
+
+impl ChaosConfig {
+    pub fn validate(&self) -> Result<()> {
+        let rates = [self.loss_rate, self.duplicate_rate, self.reorder_rate];
+        if rates.iter().any(|rate| !(0.0..=1.0).contains(rate)) {
+            return Err(Error::ErrInvalidChaosConfig);
+        }
+        if self.bandwidth == Some(0) || self.queue_limit == 0 {
+            return Err(Error::ErrInvalidChaosConfig);
+        }
+        Ok(())
+    }
+}
+
+// 任意のConnを包み、送信側でChaosConfigの障害を起こす。受信はそのまま通すので、
+// 両方向に障害を入れたい場合はclient側とserver側の両方のConnを包む。
+// 遅延させたpacketを送るtaskを動かすので、tokioのruntimeの中で作ること
+pub struct ChaosConn {
+    inner: Arc<dyn Conn + Send + Sync>,
+    config: ChaosConfig,
+    state: Mutex<ChaosState>,
+    // Noneならcloseされている
+    tx: Mutex<Option<mpsc::UnboundedSender<DelayedPacket>>>,
+}
+
+struct ChaosState {
+    rng: StdRng,
+    // 同じ時刻に送るpacketを、send_toを呼んだ順に送るための通し番号
+    seq: u64,
+    // bandwidthの制限で、次のpacketを送り始められる時刻
+    link_free_at: Instant,
+    // bandwidthの制限で待っているpacketが送り始められる時刻。古い順に並ぶ
+    backlog: VecDeque<Instant>,
+}
+
+struct DelayedPacket {
+    send_at: Instant,
+    seq: u64,
+    data: Vec<u8>,
+    target: SocketAddr,
+}
+
+impl PartialEq for DelayedPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DelayedPacket {}
+
+impl PartialOrd for DelayedPacket {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DelayedPacket {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.send_at, self.seq).cmp(&(other.send_at, other.seq))
+    }
+}
+
+impl ChaosConn {
+    pub fn new(inner: Arc<dyn Conn + Send + Sync>, config: ChaosConfig) -> Result<Self> {
+        config.validate()?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(ChaosConn::delivery_loop(Arc::clone(&inner), rx));
+        Ok(ChaosConn {
+            inner,
+            state: Mutex::new(ChaosState {
+                rng: StdRng::seed_from_u64(config.seed),
+                seq: 0,
+                link_free_at: Instant::now(),
+                backlog: VecDeque::new(),
+            }),
+            config,
+            tx: Mutex::new(Some(tx)),
+        })
+    }
+
+    // 送る時刻になったpacketから順にinnerで送る。closeでsenderが捨てられると、
+    // まだ送っていないpacketは捨てて終わる
+    async fn delivery_loop(
+        inner: Arc<dyn Conn + Send + Sync>,
+        mut rx: mpsc::UnboundedReceiver<DelayedPacket>,
+    ) {
+        let mut queue: BinaryHeap<Reverse<DelayedPacket>> = BinaryHeap::new();
+        loop {
+            let next_send_at = queue.peek().map(|Reverse(packet)| packet.send_at);
+            tokio::select! {
+                v = rx.recv() => match v {
+                    Some(packet) => queue.push(Reverse(packet)),
+                    None => break,
+                },
+                _ = sleep_until(next_send_at.unwrap_or_else(Instant::now)), if next_send_at.is_some() => {
+                    let now = Instant::now();
+                    while let Some(Reverse(packet)) = queue.peek() {
+                        if packet.send_at > now {
+                            break;
+                        }
+                        let Reverse(packet) = queue.pop().unwrap();
+                        if let Err(err) = inner.send_to(&packet.data, packet.target).await {
+                            tracing::debug!(target = %packet.target, error = %err, "chaos: failed to send");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // このpacketをいつ送るか、何回送るかを決める。損失ならNone
+    fn schedule(&self, len: usize) -> Option<Vec<(Instant, u64)>> {
+        let config = &self.config;
+        let mut state = self.state.lock().unwrap();
+        let ChaosState {
+            rng,
+            seq,
+            link_free_at,
+            backlog,
+        } = &mut *state;
+        // 損失しても乱数の消費量が変わらないように、先に全て引いておく
+        let is_lost = rng.gen_bool(config.loss_rate);
+        let is_duplicated = rng.gen_bool(config.duplicate_rate);
+        let is_reordered = rng.gen_bool(config.reorder_rate);
+        let jitters = [
+            random_jitter(rng, config.jitter),
+            random_jitter(rng, config.jitter),
+        ];
+        if is_lost {
+            return None;
+        }
+
+        let now = Instant::now();
+        while matches!(backlog.front(), Some(departs_at) if *departs_at <= now) {
+            backlog.pop_front();
+        }
+        let copies = if is_duplicated { 2 } else { 1 };
+        let mut schedule = Vec::with_capacity(copies);
+        for jitter in jitters.iter().take(copies) {
+            // 帯域を使い切るまでの時間だけ後ろにずれる。重複したpacketも帯域を使う
+            let departs_at = match config.bandwidth {
+                Some(bandwidth) => {
+                    if backlog.len() >= config.queue_limit {
+                        continue;
+                    }
+                    let transmission = Duration::from_nanos(len as u64 * 1_000_000_000 / bandwidth);
+                    *link_free_at = (*link_free_at).max(now) + transmission;
+                    backlog.push_back(*link_free_at);
+                    *link_free_at
+                }
+                None => now,
+            };
+            let mut send_at = departs_at + config.delay + *jitter;
+            if is_reordered {
+                send_at += config.reorder_delay;
+            }
+            schedule.push((send_at, *seq));
+            *seq += 1;
+        }
+        Some(schedule)
+    }
+}
+
+fn random_jitter(rng: &mut StdRng, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos(rng.gen_range(0..=jitter.as_nanos() as u64))
+}
+
+#[async_trait]
+impl Conn for ChaosConn {
+    async fn connect(&self, addr: SocketAddr) -> Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        self.inner.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let remote_addr = self.inner.remote_addr().await.ok_or(Error::ErrNoRemAddr)?;
+        self.send_to(buf, remote_addr).await
+    }
+
+    // 損失させたpacketも、UDPと同じように送れたことにする
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
+        let tx = match &*self.tx.lock().unwrap() {
+            Some(tx) => tx.clone(),
+            None => return Err(Error::ErrUseClosedNetworkConn),
+        };
+        for (send_at, seq) in self.schedule(buf.len()).unwrap_or_default() {
+            let _ = tx.send(DelayedPacket {
+                send_at,
+                seq,
+                data: buf.to_vec(),
+                target,
+            });
+        }
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr().await
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.tx.lock().unwrap().take();
+        self.inner.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::UdpConn;
+
+    async fn new_chaos_conn(config: ChaosConfig) -> Result<ChaosConn> {
+        let inner = UdpConn::bind("127.0.0.1:0").await?;
+        ChaosConn::new(Arc::new(inner), config)
+    }
+
+    // 損失ならNone、それ以外は送る各packetがreorder_delayだけ遅らされたかどうか
+    fn pattern(conn: &ChaosConn, count: usize) -> Vec<Option<Vec<bool>>> {
+        (0..count)
+            .map(|_| {
+                let threshold = Instant::now() + Duration::from_secs(30);
+                conn.schedule(100)
+                    .map(|schedule| schedule.iter().map(|(at, _)| *at > threshold).collect())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_same_seed_gives_same_sequence() -> Result<()> {
+        let config = ChaosConfig {
+            seed: 42,
+            loss_rate: 0.3,
+            duplicate_rate: 0.3,
+            reorder_rate: 0.3,
+            reorder_delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let a = new_chaos_conn(config.clone()).await?;
+        let b = new_chaos_conn(config.clone()).await?;
+        let c = new_chaos_conn(ChaosConfig { seed: 43, ..config }).await?;
+
+        let expected = pattern(&a, 200);
+        assert_eq!(pattern(&b, 200), expected);
+        assert_ne!(pattern(&c, 200), expected);
+        // 損失、重複、順序の入れ替えが全て起きていること
+        assert!(expected.iter().any(|p| p.is_none()));
+        assert!(expected.iter().flatten().any(|p| p.len() == 2));
+        assert!(expected
+            .iter()
+            .flatten()
+            .flatten()
+            .any(|reordered| *reordered));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_config() -> Result<()> {
+        for config in vec![
+            ChaosConfig {
+                loss_rate: 1.5,
+                ..Default::default()
+            },
+            ChaosConfig {
+                duplicate_rate: -0.1,
+                ..Default::default()
+            },
+            ChaosConfig {
+                reorder_rate: f64::NAN,
+                ..Default::default()
+            },
+            ChaosConfig {
+                bandwidth: Some(0),
+                ..Default::default()
+            },
+            ChaosConfig {
+                queue_limit: 0,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                new_chaos_conn(config).await,
+                Err(Error::ErrInvalidChaosConfig)
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_queue_limit_drops_tail() -> Result<()> {
+        // 1packetを送るのに1秒かかる帯域で、2packetまでしか待たせない
+        let conn = new_chaos_conn(ChaosConfig {
+            bandwidth: Some(100),
+            queue_limit: 2,
+            ..Default::default()
+        })
+        .await?;
+        let scheduled: Vec<usize> = (0..5)
+            .map(|_| conn.schedule(100).unwrap_or_default().len())
+            .collect();
+        assert_eq!(scheduled, vec![1, 1, 0, 0, 0]);
+        Ok(())
+    }
+}
@@ -1,22 +1,54 @@
+use crate::alternate_server::AlternateServer;
+use crate::auth::generate_auth_key;
 use crate::error::Error;
+use crate::integrity::MessageIntegrity;
+use crate::lifetime::Lifetime;
+use crate::mobility::MobilityTicket;
+use crate::oauth::{AccessTokenAttribute, AccessTokenCredential, ThirdPartyAuthorization};
+use crate::redact::MessageSummary;
+use crate::request::{get_nonce, get_realm};
 use crate::requested_transport::*;
+use crate::unknown_attributes::UnknownAttributes;
+use crate::util::{Conn, UdpConn};
+use crate::vnet::Net;
 use anyhow::Result;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use stun::attribute::*;
+use stun::error_code::{CODE_STALE_NONCE, CODE_TRY_ALTERNATE, CODE_UNAUTHORIZED};
 use stun::message::*;
 use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, watch, Mutex};
 
 const DEFAULT_RTO_IN_MS: u16 = 200;
+// RFC 5389 sec 7.2.1 のRc。requestを送る回数の上限
+const MAX_REQUEST_TRANSMISSIONS: u32 = 7;
+// RFC 5389 sec 7.2.1 のRm。最後に送ってからRTOのこの倍だけresponseを待つ
+const LAST_RESPONSE_WAIT_FACTOR: u32 = 16;
 const MAX_DATA_BUFFER_SIZE: usize = u16::MAX as usize; // message size limit for Chromium
 const MAX_READ_QUEUE_SIZE: usize = 1024;
+// 300 Try Alternateに従う回数の上限
+const MAX_REDIRECTS: usize = 3;
+// 401, 438で認証し直す回数の上限
+const MAX_AUTH_ATTEMPTS: usize = 2;
+// responseにLIFETIMEが無かった場合に想定するallocationの寿命
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
 
 pub struct ClientConfig {
     pub turn_server_address: String,
     pub username: String,
     pub password: String,
-    pub connection: Arc<UdpSocket>,
+    // RFC 7635
+    // Someならpasswordの代わりにACCESS-TOKENで認証する。usernameにはkidが入る
+    pub access_token: Option<AccessTokenCredential>,
+    // RFC 8016
+    // trueならAllocateでMOBILITY-TICKETを要求し、local addressが変わってもallocationを引き継ぐ
+    pub mobility: bool,
+    pub connection: Arc<dyn Conn + Send + Sync>,
+    // connectionがvnetのConnの場合、そのNet。mobilityで開き直すsocketもここにbindする
+    pub net: Option<Arc<Net>>,
 }
 
 pub struct Client {
@@ -31,180 +63,478 @@ impl Client {
         })
     }
     pub async fn listen(&self) -> Result<()> {
-        let client_internal = self.client_internal.lock().await;
+        let mut client_internal = self.client_internal.lock().await;
         client_internal.listen().await
     }
     pub async fn allocate(&self) -> Result<()> {
-        let config = {
-            let mut client_internal = self.client_internal.lock().await;
-            client_internal.allocate().await?;
-        };
-        Ok(())
+        let mut client_internal = self.client_internal.lock().await;
+        client_internal.allocate().await
+    }
+
+    pub async fn refresh(&self, lifetime: Duration) -> Result<()> {
+        let mut client_internal = self.client_internal.lock().await;
+        client_internal.refresh(lifetime).await
+    }
+
+    // listenのtaskとwatch_local_addressのtaskを止めて、connectionを閉じる。
+    // allocationは削除しないので、必要なら先にrefresh(Duration::ZERO)を送る
+    pub async fn close(&self) -> Result<()> {
+        let mut client_internal = self.client_internal.lock().await;
+        client_internal.close().await
+    }
+
+    // RFC 8016 sec 3.2
+    // intervalごとにserverへの経路のlocal IPを調べ、変わっていたら新しいsocketでallocationを移す。
+    // Wi-FiとLTEの切り替えなどで使う。allocateの後に呼ぶこと
+    pub fn watch_local_address(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let client_internal = Arc::clone(&self.client_internal);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let mut client_internal = client_internal.lock().await;
+                if client_internal.closed {
+                    break;
+                }
+                let local_ip = match client_internal.route_ip().await {
+                    Ok(local_ip) => local_ip,
+                    Err(err) => {
+                        // 経路が無い間は何もせず、次に繋がった時に移す
+                        tracing::debug!(error = %err, "no route to TURN server");
+                        continue;
+                    }
+                };
+                if client_internal.local_ip == Some(local_ip) {
+                    continue;
+                }
+                if let Err(err) = client_internal.migrate(local_ip).await {
+                    tracing::warn!(local_ip = %local_ip, error = %err, "failed to migrate allocation");
+                }
+            }
+        })
     }
 }
 
 struct ClientInternal {
-    connection: Arc<UdpSocket>,
+    connection: Arc<dyn Conn + Send + Sync>,
+    net: Option<Arc<Net>>,
     turn_server_address: String,
     username: String,
     password: String,
+    access_token: Option<AccessTokenCredential>,
+    mobility: bool,
+    // 401, 438で受け取ったもの。以降のrequestはこれで認証する
+    realm_and_nonce: Option<(String, String)>,
+    // serverから最後に受け取ったMOBILITY-TICKET
+    mobility_ticket: Option<Vec<u8>>,
+    // allocationを作った時、または最後に移した時のserverへの経路のlocal IP
+    local_ip: Option<IpAddr>,
+    lifetime: Duration,
+    // socketを切り替える時に古いsocketのlisten loopを止める
+    listen_close_tx: Option<watch::Sender<bool>>,
+    closed: bool,
+    // transaction_id -> responseを待っているrequestへのchannel
+    transactions: Arc<Mutex<HashMap<[u8; 12], mpsc::Sender<MpscResult>>>>,
 }
 impl ClientInternal {
     async fn new(config: ClientConfig) -> Result<Self> {
         // 受け取ったconfigを使ってClientInternalを作る
         Ok(ClientInternal {
             connection: Arc::clone(&config.connection),
+            net: config.net,
             turn_server_address: config.turn_server_address,
             username: config.username,
             password: config.password,
+            access_token: config.access_token,
+            mobility: config.mobility,
+            realm_and_nonce: None,
+            mobility_ticket: None,
+            local_ip: None,
+            lifetime: DEFAULT_LIFETIME,
+            listen_close_tx: None,
+            closed: false,
+            transactions: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
-    async fn listen(&self) -> Result<()> {
+    async fn listen(&mut self) -> Result<()> {
         let connection = Arc::clone(&self.connection);
+        let transactions = Arc::clone(&self.transactions);
+        let (close_tx, mut close_rx) = watch::channel(false);
+        // 前のsocketのlisten loopがあれば止める
+        if let Some(listen_close_tx) = self.listen_close_tx.replace(close_tx) {
+            let _ = listen_close_tx.send(true);
+        }
 
-        println!("listen...");
         tokio::spawn(async move {
             let mut buf = vec![0u8; MAX_DATA_BUFFER_SIZE];
             loop {
-                let (n, from) = match connection.recv_from(&mut buf).await {
-                    Ok((n, from)) => (n, from),
-                    Err(err) => {
-                        log::debug!("{}", err);
-                        break;
+                let (n, from) = tokio::select! {
+                    v = connection.recv_from(&mut buf) => match v {
+                        Ok((n, from)) => (n, from),
+                        Err(err) => {
+                            tracing::debug!(error = %err, "exit listen loop on error");
+                            break;
+                        }
+                    },
+                    did_change = close_rx.changed() => {
+                        if did_change.is_err() || *close_rx.borrow() {
+                            break;
+                        } else {
+                            continue;
+                        }
                     }
                 };
-                log::debug!("received {} bytes of udp from {}", n, from);
+                tracing::debug!(len = n, from = %from, "received udp");
                 // handle inbound packet
+                let raw = buf[..n].to_vec();
+                let message = match Message::decode_from_packet(&raw) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        tracing::debug!(from = %from, error = %err, "failed to decode packet");
+                        continue;
+                    }
+                };
+                // transaction_idが一致するrequestにresponseを渡す
+                let tx = {
+                    let mut transactions = transactions.lock().await;
+                    transactions.remove(&message.transaction_id.0)
+                };
+                if let Some(tx) = tx {
+                    let _ = tx
+                        .send(MpscResult {
+                            msg: message,
+                            raw,
+                            from,
+                            retries: 0,
+                            err: None,
+                        })
+                        .await;
+                }
             }
         });
         Ok(())
     }
 
-    async fn allocate(&self) -> Result<()> {
-        let (result_ch_tx, mut result_ch_rx): (
-            Option<mpsc::Sender<MpscResult>>,
-            Option<mpsc::Receiver<MpscResult>>,
-        ) = {
-            let (tx, rx) = mpsc::channel(1);
-            (Some(tx), Some(rx))
-        };
+    // RFC 5389 sec 11
+    // 300 Try Alternateが返ってきたらALTERNATE-SERVERに送り直す。
+    // 一度送ったサーバーにまた向かわされたらloopとみなして諦める
+    async fn allocate(&mut self) -> Result<()> {
+        let mut visited = vec![self.turn_server_address.clone()];
+        loop {
+            let mobility = self.mobility;
+            let (response, authenticated) = self
+                .send_with_auth(|| build_allocate_request(mobility))
+                .await?;
+            if get_error_code(&response) != Some(CODE_TRY_ALTERNATE.0) {
+                check_error_response(&response)?;
+                self.update_allocation(&response)?;
+                if self.mobility {
+                    if self.mobility_ticket.is_none() {
+                        tracing::warn!("TURN server does not support mobility");
+                    }
+                    self.local_ip = self.route_ip().await.ok();
+                }
+                return Ok(());
+            }
+            // RFC 8489 sec 10
+            // 認証したrequestへの300は、MESSAGE-INTEGRITYが正しい時だけ従う。
+            // そうでなければ偽のresponseで別のサーバーに誘導されうる
+            if !authenticated {
+                return Err(Error::ErrUnauthenticatedRedirect.into());
+            }
+            let alternate_server = match AlternateServer::get_from(&response)? {
+                Some(alternate_server) => alternate_server.address.to_string(),
+                None => return Err(Error::ErrNoAlternateServer.into()),
+            };
+            if visited.contains(&alternate_server) {
+                return Err(Error::ErrRedirectLoop(alternate_server).into());
+            }
+            if visited.len() > MAX_REDIRECTS {
+                return Err(Error::ErrTooManyRedirects.into());
+            }
+            tracing::info!(
+                from = %self.turn_server_address,
+                to = %alternate_server,
+                "redirected"
+            );
+            visited.push(alternate_server.clone());
+            self.turn_server_address = alternate_server;
+            // nonceはサーバーごとに発行されるので、新しいサーバーでは最初からやり直す
+            self.realm_and_nonce = None;
+        }
+    }
 
-        let mut allocate_request_message = Message::new(METHOD_ALLOCATE, CLASS_REQUEST);
-        let requested_transport = RequestedTransport {
-            protocol: PROTO_UDP,
+    // RFC 5766 sec 7.1
+    // allocationの期限を延長する。MOBILITY-TICKETがあれば付けるので、
+    // 別の5-tupleから送ればallocationがその5-tupleに移る
+    async fn refresh(&mut self, lifetime: Duration) -> Result<()> {
+        let mobility_ticket = self.mobility_ticket.clone();
+        let (response, _) = self
+            .send_with_auth(|| build_refresh_request(lifetime, mobility_ticket.clone()))
+            .await?;
+        check_error_response(&response)?;
+        self.update_allocation(&response)
+    }
+
+    // RFC 8016 sec 3.5
+    // 新しいlocal IPでsocketを開き直し、MOBILITY-TICKET付きのRefreshでallocationを移す
+    async fn migrate(&mut self, local_ip: IpAddr) -> Result<()> {
+        if self.mobility_ticket.is_none() {
+            return Err(Error::ErrNoMobilityTicket.into());
+        }
+        // 0.0.0.0にbindしていた場合はOSに経路を選ばせる
+        let bind_ip = match self.connection.local_addr().await?.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            _ => local_ip,
         };
-        println!("allocate_request_message: {:?}", allocate_request_message);
-        allocate_request_message.set_extra_attribute(Box::new(requested_transport))?;
-        println!("allocate_request_message: {:?}", allocate_request_message);
-
-        let allocate_request_message_packet = &allocate_request_message.encode_to_packet();
-        println!(
-            "allocate_request_message_packet: {:?}",
-            allocate_request_message_packet
+        let bind_addr = SocketAddr::new(bind_ip, 0);
+        let connection: Arc<dyn Conn + Send + Sync> = match &self.net {
+            Some(net) => Arc::new(net.bind(bind_addr)?),
+            None => Arc::new(UdpConn::bind(bind_addr).await?),
+        };
+        let old_connection = std::mem::replace(&mut self.connection, connection);
+        self.listen().await?;
+        let lifetime = self.lifetime;
+        self.refresh(lifetime).await?;
+        // 古いsocketのlisten loopはlistenで止めているので、もう使わない
+        if let Err(err) = old_connection.close().await {
+            tracing::debug!(error = %err, "failed to close old connection");
+        }
+        tracing::info!(
+            local_addr = %self.connection.local_addr().await?,
+            "migrated allocation"
         );
-        // send message
-        self.connection
-            .send_to(&allocate_request_message_packet, &self.turn_server_address)
-            .await?;
-        println!("allocate_request_message_packet sent!");
+        self.local_ip = Some(local_ip);
+        Ok(())
+    }
 
-        let received_message = if let Some(mut result_ch_rx) = result_ch_rx {
-            match result_ch_rx.recv().await {
-                Some(tr) => Ok(tr),
-                None => Err(Error::ErrReceiverClosed),
-            }
-        } else {
-            Err(Error::ErrReceiverClosed)
+    // serverへの経路で使われるlocal IP。UDPのconnectはpacketを送らずに経路だけ決める
+    async fn route_ip(&self) -> Result<IpAddr> {
+        let server = self.server_addr().await?;
+        if let Some(net) = &self.net {
+            return Ok(net.source_ip(server)?);
+        }
+        let bind_ip = match server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
         };
-        println!("received Message! => {:?}", received_message);
-
-        // この部分はlisten部分でやりたい
-        // let mut buf = [0; 100];
-        // let (n, addr) = match self.connection.recv_from(&mut buf).await {
-        //     Ok((n, addr)) => (n, addr),
-        //     Err(err) => {
-        //         panic!("{:?}", err);
-        //     }
-        // };
-        // let message = Message::decode_from_packet(&buf[..n].to_vec()).unwrap();
-        // println!("received packet: {:?}", message);
-        // match message.attributes.iter().find(|e| e.typ == ATTR_ERROR_CODE) {
-        //     Some(attribute) => attribute,
-        //     None => {
-        //         return Err(Error::ErrAllocateResponseIncludeNoErrorCodeAttribute.into());
-        //     }
-        // };
-        // // attributesの中に ERROCODE attirbute unauthorisedが返ってくるはず
-        // // send authorised message
-        // let mut second_allocate_request_message = Message::new(METHOD_ALLOCATE, CLASS_REQUEST);
-        // let requested_transport = RequestedTransport {
-        //     protocol: PROTO_UDP,
-        // };
-        // second_allocate_request_message.set_extra_attribute(Box::new(requested_transport))?;
-        // // set user
-        // let username = "uchida00";
-        // let user = Attribute::new(ATTR_USERNAME, 8, username.as_bytes().to_vec());
-        // second_allocate_request_message.attributes.push(user);
-
-        // // 受け取ったnonceを使う set nonce
-        // let nonce = match message.attributes.iter().find(|e| e.typ == ATTR_NONCE) {
-        //     Some(attribute) => attribute,
-        //     None => {
-        //         return Err(Error::ErrAllocateResponseIncludeNoErrorCodeAttribute.into());
-        //     }
-        // };
-        // second_allocate_request_message
-        //     .attributes
-        //     .push(nonce.clone());
-        // println!(
-        //     "second_allocate_request_message: {:?}",
-        //     second_allocate_request_message
-        // );
-
-        // // 受け取ったrealmを使う set realm
-        // let realm = match message.attributes.iter().find(|e| e.typ == ATTR_REALM) {
-        //     Some(attribute) => attribute,
-        //     None => {
-        //         return Err(Error::ErrAllocateResponseIncludeNoErrorCodeAttribute.into());
-        //     }
-        // };
-        // second_allocate_request_message
-        //     .attributes
-        //     .push(realm.clone());
-
-        // // set message integrity
-        // // set fingerprint
-        // let second_allocate_request_message_packet =
-        //     &second_allocate_request_message.encode_to_packet();
-        // println!(
-        //     "second_allocate_request_message_packet: {:?}",
-        //     second_allocate_request_message_packet
-        // );
-        // self.connection
-        //     .send_to(
-        //         &second_allocate_request_message_packet,
-        //         &self.turn_server_address,
-        //     )
-        //     .await?;
-
-        // let mut buf = [0; 100];
-        // println!("sended");
-        // let (n, addr) = match self.connection.recv_from(&mut buf).await {
-        //     Ok((n, addr)) => (n, addr),
-        //     Err(err) => {
-        //         panic!("{:?}", err);
-        //     }
-        // };
-        // println!("{:?} {:?}", n, addr);
+        let probe = UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await?;
+        probe.connect(server).await?;
+        Ok(probe.local_addr()?.ip())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        self.closed = true;
+        if let Some(listen_close_tx) = self.listen_close_tx.take() {
+            let _ = listen_close_tx.send(true);
+        }
+        // responseを待っているrequestはErrReceiverClosedになる
+        self.transactions.lock().await.clear();
+        self.connection.close().await?;
+        Ok(())
+    }
 
+    // turn_server_addressにはhost名も書けるので、送るたびに名前解決する
+    async fn server_addr(&self) -> Result<SocketAddr> {
+        match tokio::net::lookup_host(&self.turn_server_address)
+            .await?
+            .next()
+        {
+            Some(addr) => Ok(addr),
+            None => Err(Error::ErrFailedToResolveServer(self.turn_server_address.clone()).into()),
+        }
+    }
+
+    // Allocate, Refreshのsuccess responseからLIFETIMEとMOBILITY-TICKETを覚える
+    fn update_allocation(&mut self, response: &Message) -> Result<()> {
+        if let Some(lifetime) = Lifetime::get_from(response)? {
+            self.lifetime = lifetime.0;
+        }
+        if let Some(mobility_ticket) = MobilityTicket::get_from(response) {
+            self.mobility_ticket = Some(mobility_ticket.0);
+        }
         Ok(())
     }
+
+    // RFC 5389 sec 10.2.3
+    // 401, 438が返ってきたら、受け取ったREALMとNONCEで認証を付けて送り直す。
+    // transaction_idを変えるため、送り直すたびにbuildでmessageを作り直す
+    async fn send_with_auth<F>(&mut self, build: F) -> Result<(Message, bool)>
+    where
+        F: Fn() -> Result<Message>,
+    {
+        let mut auth_attempts = 0;
+        loop {
+            let (response, authenticated) = self.send_request(build()?).await?;
+            let code = get_error_code(&response);
+            if code != Some(CODE_UNAUTHORIZED.0) && code != Some(CODE_STALE_NONCE.0) {
+                return Ok((response, authenticated));
+            }
+            if auth_attempts >= MAX_AUTH_ATTEMPTS {
+                return Ok((response, authenticated));
+            }
+            if let Some(server_name) = ThirdPartyAuthorization::get_from(&response) {
+                tracing::debug!(server_name = %server_name.0, "third party authorization server");
+            }
+            match (get_realm(&response), get_nonce(&response)) {
+                (Some(realm), Some(nonce)) => self.realm_and_nonce = Some((realm, nonce)),
+                _ => return Ok((response, authenticated)),
+            }
+            auth_attempts += 1;
+        }
+    }
+
+    // responseと、それがrequestと同じkeyのMESSAGE-INTEGRITYで署名されていたかを返す
+    async fn send_request(&self, mut request_message: Message) -> Result<(Message, bool)> {
+        let (result_ch_tx, mut result_ch_rx) = mpsc::channel(1);
+        {
+            let mut transactions = self.transactions.lock().await;
+            transactions.insert(request_message.transaction_id.0, result_ch_tx);
+        }
+        let integrity = match &self.realm_and_nonce {
+            Some((realm, nonce)) => {
+                Some(self.set_credentials(&mut request_message, realm, nonce)?)
+            }
+            None => None,
+        };
+
+        let mut request_message_packet = request_message.encode_to_packet();
+        if let Some(integrity) = &integrity {
+            integrity.append_to(&mut request_message_packet);
+        }
+        let request_message_packet = &request_message_packet;
+        let server_addr = self.server_addr().await?;
+
+        // RFC 5389 sec 7.2.1
+        // RTOから始めて、responseが無ければ間隔を倍にしながらRc回まで送る。
+        // 最後に送ってからRm * RTO待っても無ければtimeoutにする
+        let initial_rto = Duration::from_millis(DEFAULT_RTO_IN_MS as u64);
+        let mut rto = initial_rto;
+        let mut received_message = None;
+        for attempt in 1..=MAX_REQUEST_TRANSMISSIONS {
+            self.connection
+                .send_to(request_message_packet, server_addr)
+                .await?;
+            tracing::debug!(
+                message = %MessageSummary(&request_message),
+                attempt,
+                "sent STUN request"
+            );
+            let wait = if attempt == MAX_REQUEST_TRANSMISSIONS {
+                initial_rto * LAST_RESPONSE_WAIT_FACTOR
+            } else {
+                rto
+            };
+            match tokio::time::timeout(wait, result_ch_rx.recv()).await {
+                Ok(Some(tr)) => {
+                    received_message = Some(tr);
+                    break;
+                }
+                Ok(None) => return Err(Error::ErrReceiverClosed.into()),
+                Err(_) => rto *= 2,
+            }
+        }
+        let received_message = match received_message {
+            Some(received_message) => received_message,
+            None => {
+                let mut transactions = self.transactions.lock().await;
+                transactions.remove(&request_message.transaction_id.0);
+                return Err(Error::ErrTransactionTimedOut.into());
+            }
+        };
+        let response = received_message.msg;
+        tracing::debug!(message = %MessageSummary(&response), "received STUN response");
+        let authenticated =
+            integrity.map_or(false, |integrity| integrity.check(&received_message.raw));
+        Ok((response, authenticated))
+    }
+
+    // USERNAME, REALM, NONCE(とACCESS-TOKEN)を入れ、MESSAGE-INTEGRITYのkeyを返す
+    fn set_credentials(
+        &self,
+        m: &mut Message,
+        realm: &str,
+        nonce: &str,
+    ) -> Result<MessageIntegrity> {
+        let username = match &self.access_token {
+            Some(access_token) => access_token.kid.as_str(),
+            None => self.username.as_str(),
+        };
+        for (typ, value) in [
+            (ATTR_USERNAME, username),
+            (ATTR_REALM, realm),
+            (ATTR_NONCE, nonce),
+        ] {
+            let raw = value.as_bytes().to_vec();
+            m.attributes
+                .push(Attribute::new(typ, raw.len() as u16, raw));
+        }
+        match &self.access_token {
+            // RFC 7635 sec 6.2
+            // tokenの中に入っているのと同じmac_keyでMESSAGE-INTEGRITYを計算する
+            Some(access_token) => {
+                m.set_extra_attribute(Box::new(AccessTokenAttribute(access_token.token.clone())))?;
+                Ok(MessageIntegrity(access_token.mac_key.clone()))
+            }
+            None => Ok(generate_auth_key(username, realm, &self.password)),
+        }
+    }
+}
+
+fn build_allocate_request(mobility: bool) -> Result<Message> {
+    let mut allocate_request_message = Message::new(METHOD_ALLOCATE, CLASS_REQUEST);
+    allocate_request_message.set_extra_attribute(Box::new(RequestedTransport {
+        protocol: PROTO_UDP,
+    }))?;
+    // RFC 8016 sec 3.1
+    // 空のMOBILITY-TICKETでmobilityを要求する
+    if mobility {
+        allocate_request_message.set_extra_attribute(Box::new(MobilityTicket(vec![])))?;
+    }
+    Ok(allocate_request_message)
+}
+
+fn build_refresh_request(lifetime: Duration, mobility_ticket: Option<Vec<u8>>) -> Result<Message> {
+    let mut refresh_request_message = Message::new(METHOD_REFRESH, CLASS_REQUEST);
+    refresh_request_message.set_extra_attribute(Box::new(Lifetime(lifetime)))?;
+    if let Some(mobility_ticket) = mobility_ticket {
+        refresh_request_message.set_extra_attribute(Box::new(MobilityTicket(mobility_ticket)))?;
+    }
+    Ok(refresh_request_message)
+}
+
+// error responseをErrorに変換する。420の場合はサーバーが理解できなかったattributeのtypeも返す
+pub fn check_error_response(m: &Message) -> std::result::Result<(), Error> {
+    if m.class != CLASS_ERROR {
+        return Ok(());
+    }
+    let code = get_error_code(m).unwrap_or(0);
+    if code == 420 {
+        let unknown_attributes = UnknownAttributes::get_from(m)
+            .map(|unknown_attributes| unknown_attributes.0)
+            .unwrap_or_default();
+        return Err(Error::ErrUnknownAttributes(unknown_attributes));
+    }
+    Err(Error::ErrErrorResponse(code))
+}
+
+// RFC 5389 sec 15.6
+// ERROR-CODEは 0(21bit) | class(3bit) | number(8bit) で、codeは class * 100 + number
+fn get_error_code(m: &Message) -> Option<u16> {
+    let attribute = m.attributes.iter().find(|e| e.typ == ATTR_ERROR_CODE)?;
+    if attribute.value.len() < 4 {
+        return None;
+    }
+    Some((attribute.value[2] & 0x07) as u16 * 100 + attribute.value[3] as u16)
 }
 
 #[derive(Debug)] //Clone
 pub struct MpscResult {
     pub msg: Message,
+    // MESSAGE-INTEGRITYはdecodeする前のbyte列で確かめる
+    pub raw: Vec<u8>,
     pub from: SocketAddr,
     pub retries: u16,
     pub err: Option<Error>,
@@ -214,9 +544,373 @@ impl Default for MpscResult {
     fn default() -> Self {
         MpscResult {
             msg: Message::default(),
+            raw: vec![],
             from: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
             retries: 0,
             err: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alternate_server::{Redirect, StaticRedirectPolicy};
+    use crate::auth::StaticAuthHandler;
+    use crate::server::{Server, ServerConfig};
+    use crate::vnet::{NetConfig, Router, RouterConfig};
+    use crate::xor_address::XorPeerAddress;
+    use stun::error_code::ErrorCodeAttribute;
+
+    const REALM: &str = "mobility-realm";
+    const SERVER_ADDRESS: &str = "10.0.0.1:3478";
+
+    // mobilityを有効にしたserverと、clientをつなぐNetを返す
+    async fn start_server() -> (Arc<Router>, Arc<Net>, Server) {
+        let router = Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_string(),
+            nat: None,
+        })
+        .unwrap();
+        let server_net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.1".to_string()],
+        })
+        .unwrap();
+        router.add_net(&server_net).unwrap();
+        let client_net = Net::new(NetConfig::default()).unwrap();
+        router.add_net(&client_net).unwrap();
+
+        let mut users = HashMap::new();
+        users.insert("alice".to_string(), "alice-password".to_string());
+        users.insert("bob".to_string(), "bob-password".to_string());
+        let conn: Arc<dyn Conn + Send + Sync> =
+            Arc::new(server_net.bind(SERVER_ADDRESS.parse().unwrap()).unwrap());
+        let mut config = ServerConfig::new(
+            vec![conn],
+            REALM.to_string(),
+            Arc::new(StaticAuthHandler::new(REALM, &users)),
+            "10.0.0.1".parse().unwrap(),
+        );
+        config.mobility = true;
+        config.vnet = Some(Arc::clone(&server_net));
+        let server = Server::new(config).await.unwrap();
+        (router, client_net, server)
+    }
+
+    async fn new_client(net: &Arc<Net>, username: &str, mobility: bool) -> Client {
+        let client = Client::new(ClientConfig {
+            turn_server_address: SERVER_ADDRESS.to_string(),
+            username: username.to_string(),
+            password: format!("{}-password", username),
+            access_token: None,
+            mobility,
+            connection: Arc::new(net.bind("0.0.0.0:0".parse().unwrap()).unwrap()),
+            net: Some(Arc::clone(net)),
+        })
+        .await
+        .unwrap();
+        client.listen().await.unwrap();
+        client
+    }
+
+    // 別のsocketのclientにticketを持たせてRefreshさせ、返ってきたエラーのcodeを返す
+    async fn refresh_with_ticket(client: &Client, ticket: Vec<u8>) -> Option<u16> {
+        client.client_internal.lock().await.mobility_ticket = Some(ticket);
+        match client.refresh(Duration::from_secs(600)).await {
+            Ok(()) => None,
+            Err(err) => match err.downcast_ref::<Error>() {
+                Some(Error::ErrErrorResponse(code)) => Some(*code),
+                _ => panic!("unexpected error: {}", err),
+            },
+        }
+    }
+
+    async fn allocate_with_mobility(net: &Arc<Net>) -> (Client, Vec<u8>) {
+        let client = new_client(net, "alice", true).await;
+        client.allocate().await.unwrap();
+        let ticket = client
+            .client_internal
+            .lock()
+            .await
+            .mobility_ticket
+            .clone()
+            .expect("server should issue a MOBILITY-TICKET");
+        (client, ticket)
+    }
+
+    // RFC 5766 sec 10
+    // client -> Send indication -> peer -> relayアドレス -> Data indication -> client
+    #[tokio::test]
+    async fn test_relay_between_client_and_peer() {
+        let (router, net, server) = start_server().await;
+        let peer_net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.3".to_string()],
+        })
+        .unwrap();
+        router.add_net(&peer_net).unwrap();
+        let peer_addr: SocketAddr = "10.0.0.3:5000".parse().unwrap();
+        let peer = peer_net.bind(peer_addr).unwrap();
+
+        let client = new_client(&net, "alice", false).await;
+        client.allocate().await.unwrap();
+        let mut internal = client.client_internal.lock().await;
+        let (response, authenticated) = internal
+            .send_with_auth(|| {
+                let mut m = Message::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST);
+                m.set_extra_attribute(Box::new(XorPeerAddress { address: peer_addr }))?;
+                Ok(m)
+            })
+            .await
+            .unwrap();
+        assert!(authenticated);
+        check_error_response(&response).unwrap();
+
+        // Data indicationはtransactionに対応しないので、listen loopを止めて直接読む
+        let _ = internal.listen_close_tx.take().unwrap().send(true);
+        let mut send_indication = Message::new(METHOD_SEND, CLASS_INDICATION);
+        send_indication
+            .set_extra_attribute(Box::new(XorPeerAddress { address: peer_addr }))
+            .unwrap();
+        send_indication
+            .attributes
+            .push(Attribute::new(AttrType(0x0013), 5, b"hello".to_vec()));
+        internal
+            .connection
+            .send_to(
+                &send_indication.encode_to_packet(),
+                SERVER_ADDRESS.parse().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let mut buf = vec![0u8; 1500];
+        let (n, relay_addr) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(relay_addr.ip(), "10.0.0.1".parse::<IpAddr>().unwrap());
+
+        // relay socketの受信領域に収まらないdatagramは、切り詰めて転送せずに捨てる
+        peer.send_to(&[0xAB; 1600], relay_addr).await.unwrap();
+        peer.send_to(b"world", relay_addr).await.unwrap();
+        let (n, from) = internal.connection.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, SERVER_ADDRESS.parse().unwrap());
+        let (data_from, data) = crate::relay::parse_send_indication(&buf[..n]).unwrap();
+        assert_eq!(data_from, peer_addr);
+        assert_eq!(data, b"world");
+        assert!(server
+            .metrics()
+            .render()
+            .contains("turn_invalid_packets_total{reason=\"peer_datagram_too_large\"} 1"));
+        drop(internal);
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_with_ticket() {
+        let (_router, net, server) = start_server().await;
+        let (_client, ticket) = allocate_with_mobility(&net).await;
+        let moved = new_client(&net, "alice", true).await;
+        assert_eq!(refresh_with_ticket(&moved, ticket).await, None);
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_with_tampered_ticket() {
+        let (_router, net, server) = start_server().await;
+        let (_client, mut ticket) = allocate_with_mobility(&net).await;
+        let last = ticket.len() - 1;
+        ticket[last] ^= 0x01;
+        let moved = new_client(&net, "alice", true).await;
+        assert_eq!(refresh_with_ticket(&moved, ticket).await, Some(405));
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_with_another_users_ticket() {
+        let (_router, net, server) = start_server().await;
+        let (_client, ticket) = allocate_with_mobility(&net).await;
+        let thief = new_client(&net, "bob", true).await;
+        assert_eq!(refresh_with_ticket(&thief, ticket).await, Some(405));
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate_onto_allocated_five_tuple() {
+        let (_router, net, server) = start_server().await;
+        let (_client, ticket) = allocate_with_mobility(&net).await;
+        // 移動先の5-tupleには既に別のallocationがある
+        let other = new_client(&net, "alice", false).await;
+        other.allocate().await.unwrap();
+        assert_eq!(refresh_with_ticket(&other, ticket).await, Some(437));
+        server.close().await.unwrap();
+    }
+
+    // 300 Try Alternateを試すためのnetwork。clientのNetだけを返し、serverは後から足す
+    fn new_redirect_network() -> (Arc<Router>, Arc<Net>) {
+        let router = Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_string(),
+            nat: None,
+        })
+        .unwrap();
+        let client_net = Net::new(NetConfig::default()).unwrap();
+        router.add_net(&client_net).unwrap();
+        (router, client_net)
+    }
+
+    // redirect_toがSomeなら、全てのAllocateを署名付きの300でそこに向かわせるserver
+    async fn start_server_at(
+        router: &Arc<Router>,
+        address: &str,
+        redirect_to: Option<&str>,
+    ) -> Server {
+        let address: SocketAddr = address.parse().unwrap();
+        let net = Net::new(NetConfig {
+            static_ips: vec![address.ip().to_string()],
+        })
+        .unwrap();
+        router.add_net(&net).unwrap();
+        let mut users = HashMap::new();
+        users.insert("alice".to_string(), "alice-password".to_string());
+        let conn: Arc<dyn Conn + Send + Sync> = Arc::new(net.bind(address).unwrap());
+        let mut config = ServerConfig::new(
+            vec![conn],
+            REALM.to_string(),
+            Arc::new(StaticAuthHandler::new(REALM, &users)),
+            address.ip(),
+        );
+        config.vnet = Some(net);
+        if let Some(redirect_to) = redirect_to {
+            config.redirect_policy = Some(Arc::new(StaticRedirectPolicy::new(Redirect::new(
+                redirect_to.parse().unwrap(),
+            ))));
+        }
+        Server::new(config).await.unwrap()
+    }
+
+    async fn new_client_to(net: &Arc<Net>, turn_server_address: &str) -> Client {
+        let client = Client::new(ClientConfig {
+            turn_server_address: turn_server_address.to_string(),
+            username: "alice".to_string(),
+            password: "alice-password".to_string(),
+            access_token: None,
+            mobility: false,
+            connection: Arc::new(net.bind("0.0.0.0:0".parse().unwrap()).unwrap()),
+            net: Some(Arc::clone(net)),
+        })
+        .await
+        .unwrap();
+        client.listen().await.unwrap();
+        client
+    }
+
+    async fn allocate_error(client: &Client) -> Error {
+        let err = client.allocate().await.expect_err("allocate should fail");
+        err.downcast::<Error>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_follow_try_alternate() {
+        let (router, net) = new_redirect_network();
+        let first = start_server_at(&router, "10.0.0.11:3478", Some("10.0.0.12:3478")).await;
+        let second = start_server_at(&router, "10.0.0.12:3478", None).await;
+
+        let client = new_client_to(&net, "10.0.0.11:3478").await;
+        client.allocate().await.unwrap();
+        assert_eq!(
+            client.client_internal.lock().await.turn_server_address,
+            "10.0.0.12:3478"
+        );
+        assert!(first.list_allocations().await.is_empty());
+        assert_eq!(second.list_allocations().await.len(), 1);
+        first.close().await.unwrap();
+        second.close().await.unwrap();
+    }
+
+    // RFC 8489 sec 10
+    // MESSAGE-INTEGRITYの無い300には従わない
+    #[tokio::test]
+    async fn test_unauthenticated_try_alternate_is_rejected() {
+        let (router, net) = new_redirect_network();
+        let fake_net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.11".to_string()],
+        })
+        .unwrap();
+        router.add_net(&fake_net).unwrap();
+        let fake = fake_net.bind("10.0.0.11:3478".parse().unwrap()).unwrap();
+        let fake_server = tokio::spawn(async move {
+            let mut buf = vec![0u8; 1500];
+            let (n, addr) = fake.recv_from(&mut buf).await.unwrap();
+            let request = Message::decode_from_packet(&buf[..n].to_vec()).unwrap();
+            let mut response = Message::new(METHOD_ALLOCATE, CLASS_ERROR);
+            response.transaction_id = request.transaction_id;
+            response
+                .set_extra_attribute(Box::new(ErrorCodeAttribute {
+                    code: CODE_TRY_ALTERNATE,
+                    reason: b"Try Alternate".to_vec(),
+                }))
+                .unwrap();
+            response
+                .set_extra_attribute(Box::new(AlternateServer {
+                    address: "10.0.0.66:3478".parse().unwrap(),
+                }))
+                .unwrap();
+            fake.send_to(&response.encode_to_packet(), addr)
+                .await
+                .unwrap();
+        });
+
+        let client = new_client_to(&net, "10.0.0.11:3478").await;
+        assert_eq!(
+            allocate_error(&client).await,
+            Error::ErrUnauthenticatedRedirect
+        );
+        assert_eq!(
+            client.client_internal.lock().await.turn_server_address,
+            "10.0.0.11:3478"
+        );
+        fake_server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_redirect_loop_is_detected() {
+        let (router, net) = new_redirect_network();
+        let first = start_server_at(&router, "10.0.0.11:3478", Some("10.0.0.12:3478")).await;
+        let second = start_server_at(&router, "10.0.0.12:3478", Some("10.0.0.11:3478")).await;
+
+        let client = new_client_to(&net, "10.0.0.11:3478").await;
+        assert_eq!(
+            allocate_error(&client).await,
+            Error::ErrRedirectLoop("10.0.0.11:3478".to_string())
+        );
+        first.close().await.unwrap();
+        second.close().await.unwrap();
+    }
+
+    // 10.0.0.11 -> .12 -> .13 -> .14 -> .15 と順にredirectし、.15だけがallocationを作る
+    #[tokio::test]
+    async fn test_redirects_are_limited() {
+        let (router, net) = new_redirect_network();
+        let mut servers = vec![];
+        for i in 11..15 {
+            let address = format!("10.0.0.{}:3478", i);
+            let next = format!("10.0.0.{}:3478", i + 1);
+            servers.push(start_server_at(&router, &address, Some(&next)).await);
+        }
+        servers.push(start_server_at(&router, "10.0.0.15:3478", None).await);
+
+        // MAX_REDIRECTS回までは従う
+        let client = new_client_to(&net, "10.0.0.12:3478").await;
+        client.allocate().await.unwrap();
+        assert_eq!(
+            client.client_internal.lock().await.turn_server_address,
+            "10.0.0.15:3478"
+        );
+
+        // それを超えると諦める
+        let client = new_client_to(&net, "10.0.0.11:3478").await;
+        assert_eq!(allocate_error(&client).await, Error::ErrTooManyRedirects);
+        assert_eq!(servers[4].list_allocations().await.len(), 1);
+        for server in &servers {
+            server.close().await.unwrap();
+        }
+    }
+}
@@ -13,6 +13,74 @@ pub enum Error {
     ErrReceiverClosed,
     #[error("turn: duplicated NONCE generated, discarding request")]
     ErrDuplicatedNonce,
+    #[error("turn: XOR address attribute is malformed")]
+    ErrXorAddressMalformed,
+    #[error("turn: invalid CIDR")]
+    ErrInvalidCidr,
+    #[error("turn: no USERNAME attribute in request")]
+    ErrNoUsernameAttribute,
+    #[error("turn: attribute has unexpected length")]
+    ErrBadAttributeLength,
+    #[error("turn: allocation already exists for 5-tuple")]
+    ErrDupeFiveTuple,
+    #[error("turn: no allocation found for 5-tuple")]
+    ErrNoAllocationFound,
+    #[error("turn: no such user")]
+    ErrNoSuchUser,
+    #[error("turn: credential has expired")]
+    ErrCredentialExpired,
+    #[error("turn: allocation quota reached")]
+    ErrAllocationQuotaReached,
+    #[error("turn: at least one listener is required")]
+    ErrNoListeners,
+    #[error("turn: realm must not be empty")]
+    ErrRealmEmpty,
+    #[error("turn: relay address must not be unspecified")]
+    ErrRelayAddressUnspecified,
+    #[error("turn: default lifetime must be positive and not exceed max lifetime")]
+    ErrInvalidLifetime,
+    #[error("turn: allocation quota must be positive")]
+    ErrInvalidQuota,
+    #[error("turn: server did not understand attributes {0:?}")]
+    ErrUnknownAttributes(Vec<u16>),
+    #[error("turn: error response {0}")]
+    ErrErrorResponse(u16),
+    #[error("turn: packet is too short")]
+    ErrPacketTooShort,
+    #[error("turn: admin API must listen on a loopback address")]
+    ErrAdminAddressNotLoopback,
+    #[error("turn: at least one request worker is required")]
+    ErrNoRequestWorkers,
+    #[error("turn: a reuse port listener needs at least one socket")]
+    ErrNoReusePortSockets,
+    #[error("turn: 300 Try Alternate without ALTERNATE-SERVER")]
+    ErrNoAlternateServer,
+    #[error("turn: redirected back to {0}")]
+    ErrRedirectLoop(String),
+    #[error("turn: too many redirects")]
+    ErrTooManyRedirects,
+    #[error("turn: 300 Try Alternate without a valid MESSAGE-INTEGRITY")]
+    ErrUnauthenticatedRedirect,
+    #[error("turn: transaction timed out without a response")]
+    ErrTransactionTimedOut,
+    #[error("turn: no XOR-PEER-ADDRESS attribute in request")]
+    ErrNoXorPeerAddress,
+    #[error("turn: no DATA attribute in indication")]
+    ErrNoDataAttribute,
+    #[error("turn: channel number is missing or out of range")]
+    ErrInvalidChannelNumber,
+    #[error("turn: channel number or peer is already bound to another channel")]
+    ErrChannelConflict,
+    #[error("turn: ACCESS-TOKEN could not be decrypted or is malformed")]
+    ErrInvalidAccessToken,
+    #[error("turn: access token key must be 16 or 32 bytes")]
+    ErrInvalidAccessTokenKey,
+    #[error("turn: MOBILITY-TICKET could not be decrypted or has expired")]
+    ErrInvalidMobilityTicket,
+    #[error("turn: the server did not issue a MOBILITY-TICKET for this allocation")]
+    ErrNoMobilityTicket,
+    #[error("turn: could not resolve TURN server address {0}")]
+    ErrFailedToResolveServer(String),
     #[error("{0}")]
     Stun(#[from] stun::error::Error),
     #[error("{0}")]
@@ -21,6 +89,21 @@ pub enum Error {
     Other(String),
 }
 
+impl Error {
+    // requestのattributeが壊れている場合のエラー。400 Bad Requestを返すべきもの
+    pub(crate) fn is_bad_request(&self) -> bool {
+        matches!(
+            self,
+            Error::ErrXorAddressMalformed
+                | Error::ErrNoUsernameAttribute
+                | Error::ErrBadAttributeLength
+                | Error::ErrNoXorPeerAddress
+                | Error::ErrInvalidChannelNumber
+                | Error::ErrChannelConflict
+        )
+    }
+}
+
 // SystemTimeErrorなど、ライブラリのエラーなども、独自定義Errorにラッピングしてあげる
 impl From<SystemTimeError> for Error {
     fn from(e: SystemTimeError) -> Self {
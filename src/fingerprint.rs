@@ -0,0 +1,84 @@
+use crate::integrity::*;
+
+// RFC 5389 sec 15.5
+// FINGERPRINTはFINGERPRINTの直前までのCRC-32を0x5354554eでXORした値。
+// 同じportで他のプロトコル(DTLSやRTPなど)と多重化する時に、STUNのpacketを見分けるのに使う
+pub(crate) const ATTR_FINGERPRINT_TYPE: u16 = 0x8028;
+const FINGERPRINT_SIZE: usize = 4;
+const FINGERPRINT_XOR_VALUE: u32 = 0x5354554e;
+
+pub fn has_fingerprint(packet: &[u8]) -> bool {
+    find_attribute(packet, ATTR_FINGERPRINT_TYPE).is_some()
+}
+
+// FINGERPRINTが無ければtrue。ある場合は最後のattributeであり、値が一致していればtrue
+pub fn check_fingerprint(packet: &[u8]) -> bool {
+    let offset = match find_attribute(packet, ATTR_FINGERPRINT_TYPE) {
+        Some(offset) => offset,
+        None => return true,
+    };
+    let value_start = offset + ATTRIBUTE_HEADER_SIZE;
+    if packet.len() != value_start + FINGERPRINT_SIZE {
+        return false;
+    }
+    let mut signed = packet[..offset].to_vec();
+    set_message_length(&mut signed, packet.len());
+    let expected = fingerprint(&signed);
+    packet[value_start..] == expected.to_be_bytes()
+}
+
+// encode済みのpacketの末尾にFINGERPRINTを付け足す。MESSAGE-INTEGRITYより後に付けること
+pub fn append_fingerprint(packet: &mut Vec<u8>) {
+    let total = packet.len() + ATTRIBUTE_HEADER_SIZE + FINGERPRINT_SIZE;
+    set_message_length(packet, total);
+    let value = fingerprint(packet);
+    packet.extend_from_slice(&ATTR_FINGERPRINT_TYPE.to_be_bytes());
+    packet.extend_from_slice(&(FINGERPRINT_SIZE as u16).to_be_bytes());
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+fn fingerprint(data: &[u8]) -> u32 {
+    crc32fast::hash(data) ^ FINGERPRINT_XOR_VALUE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrity::tests::{SAMPLE_IPV4_RESPONSE, SAMPLE_REQUEST};
+
+    // RFC 5769 sec 2.1, 2.2
+    #[test]
+    fn test_check_rfc5769_samples() {
+        assert!(has_fingerprint(SAMPLE_REQUEST));
+        assert!(check_fingerprint(SAMPLE_REQUEST));
+        assert!(check_fingerprint(SAMPLE_IPV4_RESPONSE));
+
+        let mut tampered = SAMPLE_REQUEST.to_vec();
+        tampered[24] ^= 0x01;
+        assert!(!check_fingerprint(&tampered));
+        // FINGERPRINTの後ろにattributeがあるものは受け付けない
+        let mut trailing = SAMPLE_REQUEST.to_vec();
+        trailing.extend_from_slice(&[0x80, 0x22, 0x00, 0x00]);
+        set_message_length(&mut trailing, SAMPLE_REQUEST.len() + 4);
+        assert!(!check_fingerprint(&trailing));
+    }
+
+    #[test]
+    fn test_append_reproduces_rfc5769_samples() {
+        for sample in [SAMPLE_REQUEST, SAMPLE_IPV4_RESPONSE] {
+            let offset = find_attribute(sample, ATTR_FINGERPRINT_TYPE).unwrap();
+            let mut packet = sample[..offset].to_vec();
+            append_fingerprint(&mut packet);
+            assert_eq!(packet, sample);
+        }
+    }
+
+    #[test]
+    fn test_packet_without_fingerprint() {
+        let offset = find_attribute(SAMPLE_REQUEST, ATTR_FINGERPRINT_TYPE).unwrap();
+        let mut packet = SAMPLE_REQUEST[..offset].to_vec();
+        set_message_length(&mut packet, offset);
+        assert!(!has_fingerprint(&packet));
+        assert!(check_fingerprint(&packet));
+    }
+}
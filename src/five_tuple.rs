@@ -0,0 +1,18 @@
+use crate::requested_transport::Protocol;
+use std::fmt;
+use std::net::SocketAddr;
+
+// RFC 5766 sec 2
+// clientとserverの間の通信を識別する (transport protocol, client address, server address) の組
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FiveTuple {
+    pub protocol: Protocol,
+    pub src_addr: SocketAddr,
+    pub dst_addr: SocketAddr,
+}
+
+impl fmt::Display for FiveTuple {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}_{}", self.protocol.0, self.src_addr, self.dst_addr)
+    }
+}
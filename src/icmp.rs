@@ -0,0 +1,252 @@
+use crate::error::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use stun::attribute::*;
+use stun::message::*;
+use tokio::net::UdpSocket;
+
+// RFC 8656 sec 18.13
+// peerに送ったpacketに対するICMP errorを、Data indicationでclientに伝える
+pub const ATTR_ICMP: AttrType = AttrType(0x8004);
+pub(crate) const ICMP_SIZE: usize = 8;
+
+// Reserved(2) | ICMP Type(1) | ICMP Code(1) | Error Data(4)。
+// Error DataはFragmentation Needed/Packet Too BigのMTUなど、typeによって意味が変わる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Icmp {
+    pub icmp_type: u8,
+    pub code: u8,
+    pub error_data: u32,
+}
+
+impl Icmp {
+    pub fn get_from(m: &Message) -> Result<Option<Icmp>> {
+        let attribute = match m.attributes.iter().find(|e| e.typ == ATTR_ICMP) {
+            Some(attribute) => attribute,
+            None => return Ok(None),
+        };
+        let raw = &attribute.value;
+        if raw.len() != ICMP_SIZE {
+            return Err(Error::ErrBadAttributeLength);
+        }
+        Ok(Some(Icmp {
+            icmp_type: raw[2],
+            code: raw[3],
+            error_data: u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]),
+        }))
+    }
+
+    pub(crate) fn encode(&self) -> [u8; ICMP_SIZE] {
+        let mut raw = [0u8; ICMP_SIZE];
+        raw[2] = self.icmp_type;
+        raw[3] = self.code;
+        raw[4..].copy_from_slice(&self.error_data.to_be_bytes());
+        raw
+    }
+}
+
+impl Setter for Icmp {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let extra_attribute = Attribute::new(ATTR_ICMP, ICMP_SIZE as u16, self.encode().to_vec());
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
+
+// relay socketが受け取ったICMP errorと、その原因になったpacketの宛先のpeer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpError {
+    pub peer: SocketAddr,
+    pub icmp: Icmp,
+}
+
+// LinuxではIP_RECVERRを有効にしたrelay socketのerror queueからICMP errorを読み出す。
+// それ以外のOSではICMPを受け取れないので、enableがNoneを返す
+pub(crate) struct IcmpErrorQueue {
+    // fdだけを持っていると、socketが閉じられた後に同じ番号で開かれた別のsocketを読んでしまう。
+    // Weakにしておけば、relay socketのcloseを妨げず、閉じた後は何も読まない
+    #[cfg(target_os = "linux")]
+    socket: std::sync::Weak<UdpSocket>,
+}
+
+impl IcmpErrorQueue {
+    #[cfg(target_os = "linux")]
+    pub(crate) fn enable(socket: &Arc<UdpSocket>) -> Option<Self> {
+        use std::os::unix::io::AsRawFd;
+        let is_ipv6 = socket.local_addr().ok()?.is_ipv6();
+        match sys::enable_recv_err(socket.as_raw_fd(), is_ipv6) {
+            Ok(()) => Some(IcmpErrorQueue {
+                socket: Arc::downgrade(socket),
+            }),
+            Err(err) => {
+                tracing::debug!(error = %err, "failed to enable IP_RECVERR");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn enable(_socket: &Arc<UdpSocket>) -> Option<Self> {
+        None
+    }
+
+    // 溜まっているICMP errorを全て読み出す。無ければ空
+    #[cfg(target_os = "linux")]
+    pub(crate) fn drain(&self) -> Vec<IcmpError> {
+        use std::os::unix::io::AsRawFd;
+        // 読んでいる間はsocketが閉じられないように、upgradeしたものを持っておく
+        match self.socket.upgrade() {
+            Some(socket) => sys::recv_errors(socket.as_raw_fd()),
+            None => vec![],
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn drain(&self) -> Vec<IcmpError> {
+        vec![]
+    }
+}
+
+// IP_RECVERRが有効なsocketでは、ICMP errorを受けた後のrecv_fromが1度だけエラーを返す。
+// これらはsocket自体の異常ではないので、relayを続けてよい
+#[cfg(target_os = "linux")]
+pub(crate) fn is_icmp_error(err: &crate::util::Error) -> bool {
+    match err {
+        crate::util::Error::Io(err) => matches!(
+            err.0.raw_os_error(),
+            Some(libc::ECONNREFUSED)
+                | Some(libc::EHOSTUNREACH)
+                | Some(libc::ENETUNREACH)
+                | Some(libc::EHOSTDOWN)
+                | Some(libc::EMSGSIZE)
+                | Some(libc::EPROTO)
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_icmp_error(_err: &crate::util::Error) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::{Icmp, IcmpError};
+    use crate::batch_conn::sys::from_sockaddr;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::RawFd;
+    use std::ptr;
+
+    // linux/errqueue.h
+    const SO_EE_ORIGIN_ICMP: u8 = 2;
+    const SO_EE_ORIGIN_ICMP6: u8 = 3;
+    // RFC 8656 sec 11.5
+    // clientに伝えるのはDestination Unreachable, Time Exceeded(, IPv6のPacket Too Big)だけ
+    const ICMP_DEST_UNREACHABLE: u8 = 3;
+    const ICMP_TIME_EXCEEDED: u8 = 11;
+    const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+    const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+    const ICMPV6_TIME_EXCEEDED: u8 = 3;
+    // ICMP errorには元のpacketの先頭が付いてくるが、使わないので小さくてよい
+    const PAYLOAD_SIZE: usize = 64;
+    // sock_extended_errと送信元のsockaddrが入る大きさ。cmsghdrのalignmentに合わせてu64で確保する
+    const CONTROL_WORDS: usize = 32;
+
+    pub(super) fn enable_recv_err(fd: RawFd, is_ipv6: bool) -> io::Result<()> {
+        let (level, name) = if is_ipv6 {
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+        } else {
+            (libc::IPPROTO_IP, libc::IP_RECVERR)
+        };
+        let enabled: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &enabled as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub(super) fn recv_errors(fd: RawFd) -> Vec<IcmpError> {
+        let mut errors = vec![];
+        loop {
+            let mut payload = [0u8; PAYLOAD_SIZE];
+            let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+            let mut control = [0u64; CONTROL_WORDS];
+            let mut iov = libc::iovec {
+                iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+                iov_len: payload.len(),
+            };
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = &mut name as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iov;
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = mem::size_of_val(&control) as _;
+            let ret =
+                unsafe { libc::recvmsg(fd, &mut hdr, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+            if ret < 0 {
+                // EAGAINならerror queueが空になった
+                break;
+            }
+            // error queueのmsg_nameには、ICMP errorの原因になったpacketの宛先が入る
+            let peer = match from_sockaddr(&name) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            if let Some(icmp) = extended_err(&hdr) {
+                errors.push(IcmpError { peer, icmp });
+            }
+        }
+        errors
+    }
+
+    fn extended_err(hdr: &libc::msghdr) -> Option<Icmp> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                let is_recv_err = ((*cmsg).cmsg_level == libc::IPPROTO_IP
+                    && (*cmsg).cmsg_type == libc::IP_RECVERR)
+                    || ((*cmsg).cmsg_level == libc::IPPROTO_IPV6
+                        && (*cmsg).cmsg_type == libc::IPV6_RECVERR);
+                if is_recv_err {
+                    let err = ptr::read_unaligned(
+                        libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err
+                    );
+                    // ローカルで起きたエラー(送信バッファが溢れたなど)や、
+                    // それ以外のtypeはclientに伝えない
+                    let is_relayed = match err.ee_origin {
+                        SO_EE_ORIGIN_ICMP => {
+                            matches!(err.ee_type, ICMP_DEST_UNREACHABLE | ICMP_TIME_EXCEEDED)
+                        }
+                        SO_EE_ORIGIN_ICMP6 => matches!(
+                            err.ee_type,
+                            ICMPV6_DEST_UNREACHABLE | ICMPV6_PACKET_TOO_BIG | ICMPV6_TIME_EXCEEDED
+                        ),
+                        _ => false,
+                    };
+                    if !is_relayed {
+                        return None;
+                    }
+                    return Some(Icmp {
+                        icmp_type: err.ee_type,
+                        code: err.ee_code,
+                        error_data: err.ee_info,
+                    });
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,200 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+// RFC 5389 sec 15.4
+// MESSAGE-INTEGRITYはヘッダのlengthをMESSAGE-INTEGRITYまで含めた値にしてから、
+// その直前までのbyte列をHMAC-SHA1で計算する。stunのMessageを経由すると
+// attributeの並びやpaddingが変わりうるので、受信したpacketのbyte列をそのまま使う
+pub(crate) const MESSAGE_HEADER_SIZE: usize = 20;
+pub(crate) const ATTRIBUTE_HEADER_SIZE: usize = 4;
+pub(crate) const ATTR_MESSAGE_INTEGRITY_TYPE: u16 = 0x0008;
+const MESSAGE_INTEGRITY_SIZE: usize = 20;
+
+// long-term credentialのkey。MD5(username ":" realm ":" password)
+#[derive(Clone, PartialEq, Eq)]
+pub struct MessageIntegrity(pub Vec<u8>);
+
+impl MessageIntegrity {
+    pub fn check(&self, packet: &[u8]) -> bool {
+        let offset = match find_attribute(packet, ATTR_MESSAGE_INTEGRITY_TYPE) {
+            Some(offset) => offset,
+            None => return false,
+        };
+        let value_start = offset + ATTRIBUTE_HEADER_SIZE;
+        if packet.len() < value_start + MESSAGE_INTEGRITY_SIZE {
+            return false;
+        }
+        let mut signed = packet[..offset].to_vec();
+        set_message_length(&mut signed, value_start + MESSAGE_INTEGRITY_SIZE);
+        let expected = self.hmac(&signed);
+        // 比較にかかる時間から値を推測されないように全byteを比較する
+        expected
+            .iter()
+            .zip(&packet[value_start..value_start + MESSAGE_INTEGRITY_SIZE])
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+
+    // encode済みのpacketの末尾にMESSAGE-INTEGRITYを付け足す
+    pub fn append_to(&self, packet: &mut Vec<u8>) {
+        let total = packet.len() + ATTRIBUTE_HEADER_SIZE + MESSAGE_INTEGRITY_SIZE;
+        set_message_length(packet, total);
+        let mac = self.hmac(packet);
+        packet.extend_from_slice(&ATTR_MESSAGE_INTEGRITY_TYPE.to_be_bytes());
+        packet.extend_from_slice(&(MESSAGE_INTEGRITY_SIZE as u16).to_be_bytes());
+        packet.extend_from_slice(&mac);
+    }
+
+    fn hmac(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.0).expect("HMAC can take key of any size");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+// ヘッダのmessage length(attributeの合計長)を書き換える
+pub(crate) fn set_message_length(packet: &mut [u8], total_len: usize) {
+    let length = (total_len - MESSAGE_HEADER_SIZE) as u16;
+    packet[2..4].copy_from_slice(&length.to_be_bytes());
+}
+
+// packetに含まれる全てのattributeのtypeを順番に返す
+pub(crate) fn attribute_types(packet: &[u8]) -> Vec<u16> {
+    let mut types = vec![];
+    let mut offset = MESSAGE_HEADER_SIZE;
+    while offset + ATTRIBUTE_HEADER_SIZE <= packet.len() {
+        types.push(u16::from_be_bytes([packet[offset], packet[offset + 1]]));
+        let length = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]) as usize;
+        offset += ATTRIBUTE_HEADER_SIZE + ((length + 3) & !3);
+    }
+    types
+}
+
+// typ のattributeのvalueを、paddingを除いて返す
+pub(crate) fn find_attribute_value(packet: &[u8], typ: u16) -> Option<&[u8]> {
+    let offset = find_attribute(packet, typ)?;
+    let length = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]) as usize;
+    let value_start = offset + ATTRIBUTE_HEADER_SIZE;
+    packet.get(value_start..value_start + length)
+}
+
+// typ のattributeが始まるoffsetを返す
+pub(crate) fn find_attribute(packet: &[u8], typ: u16) -> Option<usize> {
+    let mut offset = MESSAGE_HEADER_SIZE;
+    while offset + ATTRIBUTE_HEADER_SIZE <= packet.len() {
+        let attribute_type = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+        let length = u16::from_be_bytes([packet[offset + 2], packet[offset + 3]]) as usize;
+        if attribute_type == typ {
+            return Some(offset);
+        }
+        // valueは4byte境界までpaddingされている
+        offset += ATTRIBUTE_HEADER_SIZE + ((length + 3) & !3);
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::auth::generate_auth_key;
+    use crate::fingerprint::append_fingerprint;
+
+    // RFC 5769 sec 2.1 Sample Request (short-term credential)
+    #[rustfmt::skip]
+    pub(crate) const SAMPLE_REQUEST: &[u8] = &[
+        0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42,
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+        // SOFTWARE "STUN test client"
+        0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74, 0x65, 0x73,
+        0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74,
+        // PRIORITY
+        0x00, 0x24, 0x00, 0x04, 0x6e, 0x00, 0x01, 0xff,
+        // ICE-CONTROLLED
+        0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36,
+        // USERNAME "evtj:h6vY"。paddingは0ではなく空白
+        0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76,
+        0x59, 0x20, 0x20, 0x20,
+        // MESSAGE-INTEGRITY
+        0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56,
+        0x78, 0x1e, 0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5, 0x71, 0xa2,
+        // FINGERPRINT
+        0x80, 0x28, 0x00, 0x04, 0xe5, 0x7a, 0x3b, 0xcf,
+    ];
+
+    // RFC 5769 sec 2.2 Sample IPv4 Response
+    #[rustfmt::skip]
+    pub(crate) const SAMPLE_IPV4_RESPONSE: &[u8] = &[
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42,
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+        // SOFTWARE "test vector "
+        0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+        0x74, 0x6f, 0x72, 0x20,
+        // XOR-MAPPED-ADDRESS 192.0.2.1:32853
+        0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+        // MESSAGE-INTEGRITY
+        0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7,
+        // FINGERPRINT
+        0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    // RFC 5769 sec 2.4 Sample Request with Long-Term Authentication
+    #[rustfmt::skip]
+    const SAMPLE_LONG_TERM_REQUEST: &[u8] = &[
+        0x00, 0x01, 0x00, 0x60, 0x21, 0x12, 0xa4, 0x42,
+        0x78, 0xad, 0x34, 0x33, 0xc6, 0xad, 0x72, 0xc0, 0x29, 0xda, 0x41, 0x2e,
+        // USERNAME "マトリックス"
+        0x00, 0x06, 0x00, 0x12, 0xe3, 0x83, 0x9e, 0xe3, 0x83, 0x88, 0xe3, 0x83,
+        0xaa, 0xe3, 0x83, 0x83, 0xe3, 0x82, 0xaf, 0xe3, 0x82, 0xb9, 0x00, 0x00,
+        // NONCE "f//499k954d6OL34oL9FSTvy64sA"
+        0x00, 0x15, 0x00, 0x1c, 0x66, 0x2f, 0x2f, 0x34, 0x39, 0x39, 0x6b, 0x39,
+        0x35, 0x34, 0x64, 0x36, 0x4f, 0x4c, 0x33, 0x34, 0x6f, 0x4c, 0x39, 0x46,
+        0x53, 0x54, 0x76, 0x79, 0x36, 0x34, 0x73, 0x41,
+        // REALM "example.org"
+        0x00, 0x14, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+        0x6f, 0x72, 0x67, 0x00,
+        // MESSAGE-INTEGRITY
+        0x00, 0x08, 0x00, 0x14, 0xf6, 0x70, 0x24, 0x65, 0x6d, 0xd6, 0x4a, 0x3e,
+        0x02, 0xb8, 0xe0, 0x71, 0x2e, 0x85, 0xc9, 0xa2, 0x8c, 0xa8, 0x96, 0x66,
+    ];
+
+    // short-term credentialではpasswordがそのままkeyになる
+    fn short_term_key() -> MessageIntegrity {
+        MessageIntegrity(b"VOkJxbRl1RmTxUk/WvJxBt".to_vec())
+    }
+
+    // passwordはSASLprepで"The\u{00AD}M\u{00AA}trIX"から"TheMatrIX"になったもの
+    fn long_term_key() -> MessageIntegrity {
+        generate_auth_key("マトリックス", "example.org", "TheMatrIX")
+    }
+
+    #[test]
+    fn test_check_rfc5769_samples() {
+        assert!(short_term_key().check(SAMPLE_REQUEST));
+        assert!(short_term_key().check(SAMPLE_IPV4_RESPONSE));
+        assert!(long_term_key().check(SAMPLE_LONG_TERM_REQUEST));
+
+        assert!(!long_term_key().check(SAMPLE_REQUEST));
+        let mut tampered = SAMPLE_LONG_TERM_REQUEST.to_vec();
+        tampered[30] ^= 0x01;
+        assert!(!long_term_key().check(&tampered));
+    }
+
+    // MESSAGE-INTEGRITYより前のbyte列から、同じpacketを組み立て直せる
+    #[test]
+    fn test_append_reproduces_rfc5769_samples() {
+        for (sample, key) in [
+            (SAMPLE_REQUEST, short_term_key()),
+            (SAMPLE_IPV4_RESPONSE, short_term_key()),
+            (SAMPLE_LONG_TERM_REQUEST, long_term_key()),
+        ] {
+            let offset = find_attribute(sample, ATTR_MESSAGE_INTEGRITY_TYPE).unwrap();
+            let mut packet = sample[..offset].to_vec();
+            key.append_to(&mut packet);
+            if packet.len() < sample.len() {
+                append_fingerprint(&mut packet);
+            }
+            assert_eq!(packet, sample);
+        }
+    }
+}
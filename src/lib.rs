@@ -1,6 +1,31 @@
+pub mod acl;
+pub mod admin;
+pub mod allocation;
+pub mod alternate_server;
+pub mod auth;
+pub mod batch_conn;
+pub mod channel_number;
+pub mod chaos;
 pub mod client;
 pub mod error;
+pub mod fingerprint;
+pub mod five_tuple;
+pub mod icmp;
+pub mod integrity;
+pub mod lifetime;
+pub mod metrics;
+pub mod mobility;
+pub mod oauth;
+pub mod observer;
+pub mod pcap;
+pub mod redact;
+pub mod relay;
 pub mod requested_transport;
+pub mod reuse_port;
 pub mod server;
+pub mod transaction_cache;
+pub mod unknown_attributes;
 pub mod util;
-pub mod request;
\ No newline at end of file
+pub mod vnet;
+pub mod request;
+pub mod xor_address;
@@ -0,0 +1,38 @@
+use crate::error::*;
+use std::time::Duration;
+use stun::attribute::*;
+use stun::message::*;
+
+// RFC 5766 sec 14.2
+// allocationの残り時間(秒)。Allocate/Refreshのrequestとresponseで使われる
+pub struct Lifetime(pub Duration);
+
+const LIFETIME_SIZE: usize = 4;
+
+impl Lifetime {
+    pub fn get_from(m: &Message) -> Result<Option<Lifetime>> {
+        let attribute = match m.attributes.iter().find(|e| e.typ == ATTR_LIFETIME) {
+            Some(attribute) => attribute,
+            None => return Ok(None),
+        };
+        if attribute.value.len() != LIFETIME_SIZE {
+            return Err(Error::ErrBadAttributeLength);
+        }
+        let seconds = u32::from_be_bytes([
+            attribute.value[0],
+            attribute.value[1],
+            attribute.value[2],
+            attribute.value[3],
+        ]);
+        Ok(Some(Lifetime(Duration::from_secs(seconds as u64))))
+    }
+}
+
+impl Setter for Lifetime {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let raw = (self.0.as_secs() as u32).to_be_bytes().to_vec();
+        let extra_attribute = Attribute::new(ATTR_LIFETIME, LIFETIME_SIZE as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
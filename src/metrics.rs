@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use stun::message::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+// relayの向き。ToPeerはclient -> peer, ToClientはpeer -> client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayDirection {
+    ToPeer,
+    ToClient,
+}
+
+impl RelayDirection {
+    fn label(&self) -> &'static str {
+        match self {
+            RelayDirection::ToPeer => "to_peer",
+            RelayDirection::ToClient => "to_client",
+        }
+    }
+}
+
+// サーバーの状態をPrometheusのtext formatで公開するためのカウンタとゲージ
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<HashMap<(&'static str, &'static str), u64>>,
+    error_responses: Mutex<HashMap<u16, u64>>,
+    invalid_packets: Mutex<HashMap<&'static str, u64>>,
+    auth_failures: AtomicU64,
+    handler_errors: AtomicU64,
+    retransmissions: AtomicU64,
+    migrations: AtomicU64,
+    icmp_relayed: AtomicU64,
+    active_allocations: AtomicI64,
+    active_permissions: AtomicI64,
+    active_channels: AtomicI64,
+    bytes_to_peer: AtomicU64,
+    bytes_to_client: AtomicU64,
+    packets_to_peer: AtomicU64,
+    packets_to_client: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn record_request(&self, method: Method, class: MessageClass) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests
+            .entry((method_label(method), class_label(class)))
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_error_response(&self, code: u16) {
+        let mut error_responses = self.error_responses.lock().unwrap();
+        *error_responses.entry(code).or_insert(0) += 1;
+    }
+
+    // 捨てたpacketや400を返したrequestを理由ごとに数える
+    pub fn record_invalid_packet(&self, reason: &'static str) {
+        let mut invalid_packets = self.invalid_packets.lock().unwrap();
+        *invalid_packets.entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn record_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 受け取ったpacketではなく、サーバー側の処理(socketへの送信など)が失敗した回数
+    pub fn record_handler_error(&self) {
+        self.handler_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // transaction cacheから前回のresponseを返した再送requestを数える
+    pub fn record_retransmission(&self) {
+        self.retransmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // MOBILITY-TICKETで別の5-tupleに移ったallocationを数える
+    pub fn allocation_migrated(&self) {
+        self.migrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // peerへのpacketに対するICMP errorをclientに伝えた回数
+    pub fn record_icmp_relayed(&self) {
+        self.icmp_relayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_relayed(&self, direction: RelayDirection, bytes: usize) {
+        let (bytes_counter, packets_counter) = match direction {
+            RelayDirection::ToPeer => (&self.bytes_to_peer, &self.packets_to_peer),
+            RelayDirection::ToClient => (&self.bytes_to_client, &self.packets_to_client),
+        };
+        bytes_counter.fetch_add(bytes as u64, Ordering::Relaxed);
+        packets_counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn allocation_created(&self) {
+        self.active_allocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn allocation_deleted(&self) {
+        self.active_allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn permission_created(&self) {
+        self.active_permissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn permission_deleted(&self) {
+        self.active_permissions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn channel_bound(&self) {
+        self.active_channels.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn channel_deleted(&self) {
+        self.active_channels.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Prometheus text exposition format (version 0.0.4) に変換する
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP turn_requests_total STUN/TURN messages received by method and class."
+        );
+        let _ = writeln!(out, "# TYPE turn_requests_total counter");
+        for ((method, class), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "turn_requests_total{{method=\"{}\",class=\"{}\"}} {}",
+                escape_label_value(method),
+                escape_label_value(class),
+                count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP turn_error_responses_total Error responses sent by error code."
+        );
+        let _ = writeln!(out, "# TYPE turn_error_responses_total counter");
+        for (code, count) in self.error_responses.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "turn_error_responses_total{{code=\"{}\"}} {}",
+                code, count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP turn_invalid_packets_total Packets discarded or answered with 400 by reason."
+        );
+        let _ = writeln!(out, "# TYPE turn_invalid_packets_total counter");
+        for (reason, count) in self.invalid_packets.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "turn_invalid_packets_total{{reason=\"{}\"}} {}",
+                escape_label_value(reason),
+                count
+            );
+        }
+
+        write_metric(
+            &mut out,
+            "turn_auth_failures_total",
+            "Requests rejected because MESSAGE-INTEGRITY did not verify.",
+            "counter",
+            self.auth_failures.load(Ordering::Relaxed) as i64,
+        );
+        write_metric(
+            &mut out,
+            "turn_handler_errors_total",
+            "Packets whose handling failed on the server side, e.g. a response could not be sent.",
+            "counter",
+            self.handler_errors.load(Ordering::Relaxed) as i64,
+        );
+        write_metric(
+            &mut out,
+            "turn_retransmissions_total",
+            "Retransmitted requests answered from the transaction cache.",
+            "counter",
+            self.retransmissions.load(Ordering::Relaxed) as i64,
+        );
+        write_metric(
+            &mut out,
+            "turn_allocation_migrations_total",
+            "Allocations moved to a new 5-tuple with a MOBILITY-TICKET.",
+            "counter",
+            self.migrations.load(Ordering::Relaxed) as i64,
+        );
+        write_metric(
+            &mut out,
+            "turn_icmp_relayed_total",
+            "ICMP errors from peers forwarded to clients in Data indications.",
+            "counter",
+            self.icmp_relayed.load(Ordering::Relaxed) as i64,
+        );
+        write_metric(
+            &mut out,
+            "turn_active_allocations",
+            "Allocations currently alive.",
+            "gauge",
+            self.active_allocations.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "turn_active_permissions",
+            "Permissions currently installed.",
+            "gauge",
+            self.active_permissions.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "turn_active_channels",
+            "Channel bindings currently alive.",
+            "gauge",
+            self.active_channels.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP turn_relayed_bytes_total Payload bytes relayed by direction."
+        );
+        let _ = writeln!(out, "# TYPE turn_relayed_bytes_total counter");
+        for (direction, counter) in [
+            (RelayDirection::ToPeer, &self.bytes_to_peer),
+            (RelayDirection::ToClient, &self.bytes_to_client),
+        ] {
+            let _ = writeln!(
+                out,
+                "turn_relayed_bytes_total{{direction=\"{}\"}} {}",
+                escape_label_value(direction.label()),
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP turn_relayed_packets_total Packets relayed by direction."
+        );
+        let _ = writeln!(out, "# TYPE turn_relayed_packets_total counter");
+        for (direction, counter) in [
+            (RelayDirection::ToPeer, &self.packets_to_peer),
+            (RelayDirection::ToClient, &self.packets_to_client),
+        ] {
+            let _ = writeln!(
+                out,
+                "turn_relayed_packets_total{{direction=\"{}\"}} {}",
+                escape_label_value(direction.label()),
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, typ: &str, value: i64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, typ);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// text formatのlabelの値では、バックスラッシュ、ダブルクォート、改行をエスケープする
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn method_label(method: Method) -> &'static str {
+    match method {
+        METHOD_BINDING => "binding",
+        METHOD_ALLOCATE => "allocate",
+        METHOD_REFRESH => "refresh",
+        METHOD_SEND => "send",
+        METHOD_DATA => "data",
+        METHOD_CREATE_PERMISSION => "create_permission",
+        METHOD_CHANNEL_BIND => "channel_bind",
+        _ => "unknown",
+    }
+}
+
+fn class_label(class: MessageClass) -> &'static str {
+    match class {
+        CLASS_REQUEST => "request",
+        CLASS_INDICATION => "indication",
+        CLASS_SUCCESS_RESPONSE => "success_response",
+        CLASS_ERROR => "error_response",
+        _ => "unknown",
+    }
+}
+
+// GET /metrics に対してrender()の結果を返すだけの最小限のHTTPサーバー
+pub async fn serve_metrics(
+    metrics: Arc<Metrics>,
+    address: SocketAddr,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!(
+        address = %listener.local_addr()?,
+        "serving metrics on /metrics"
+    );
+    // 各接続のtaskがcloneを持つ。全てdropされたら処理中の接続が無くなったことが分かる
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    loop {
+        let (mut stream, _) = tokio::select! {
+            v = listener.accept() => v?,
+            did_change = shutdown_rx.changed() => {
+                if did_change.is_err() || *shutdown_rx.borrow() {
+                    break;
+                } else {
+                    continue;
+                }
+            }
+        };
+        let metrics = Arc::clone(&metrics);
+        let mut shutdown_rx = shutdown_rx.clone();
+        let done_tx = done_tx.clone();
+        tokio::spawn(async move {
+            let _done_tx = done_tx;
+            tokio::select! {
+                _ = handle_metrics_connection(&mut stream, &metrics) => {}
+                _ = shutdown_rx.changed() => {}
+            }
+        });
+    }
+    drop(done_tx);
+    let _ = done_rx.recv().await;
+    Ok(())
+}
+
+async fn handle_metrics_connection(stream: &mut TcpStream, metrics: &Metrics) {
+    let mut buf = vec![0u8; 1024];
+    let n = match stream.read(&mut buf).await {
+        Ok(n) => n,
+        Err(err) => {
+            tracing::debug!(error = %err, "failed to read metrics request");
+            return;
+        }
+    };
+    let response = if buf[..n].starts_with(b"GET /metrics ") {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        tracing::debug!(error = %err, "failed to write metrics response");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_line<'a>(rendered: &'a str, prefix: &str) -> Option<&'a str> {
+        rendered.lines().find(|line| line.starts_with(prefix))
+    }
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("no_permission"), "no_permission");
+        assert_eq!(escape_label_value("a\\b \"c\"\nd"), "a\\\\b \\\"c\\\"\\nd");
+    }
+
+    #[test]
+    fn test_render_escapes_labels() {
+        let metrics = Metrics::new();
+        metrics.record_invalid_packet("quote\"back\\slash\nnewline");
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "turn_invalid_packets_total{reason=\"quote\\\"back\\\\slash\\nnewline\"} 1\n"
+        ));
+        // 1つのsampleは1行に収まる
+        assert!(rendered.lines().all(|line| !line.ends_with("slash")));
+    }
+
+    #[test]
+    fn test_render_counters() {
+        let metrics = Metrics::new();
+        metrics.record_request(METHOD_ALLOCATE, CLASS_REQUEST);
+        metrics.record_request(METHOD_ALLOCATE, CLASS_REQUEST);
+        metrics.record_error_response(401);
+        metrics.record_invalid_packet("bad_fingerprint");
+        metrics.record_auth_failure();
+        metrics.record_handler_error();
+        metrics.record_handler_error();
+        metrics.record_relayed(RelayDirection::ToPeer, 100);
+        metrics.record_relayed(RelayDirection::ToPeer, 20);
+        metrics.record_relayed(RelayDirection::ToClient, 5);
+        let rendered = metrics.render();
+
+        for (prefix, expected) in [
+            (
+                "turn_requests_total{method=\"allocate\",class=\"request\"}",
+                "2",
+            ),
+            ("turn_error_responses_total{code=\"401\"}", "1"),
+            (
+                "turn_invalid_packets_total{reason=\"bad_fingerprint\"}",
+                "1",
+            ),
+            ("turn_auth_failures_total ", "1"),
+            ("turn_handler_errors_total ", "2"),
+            ("turn_retransmissions_total ", "0"),
+            ("turn_relayed_bytes_total{direction=\"to_peer\"}", "120"),
+            ("turn_relayed_bytes_total{direction=\"to_client\"}", "5"),
+            ("turn_relayed_packets_total{direction=\"to_peer\"}", "2"),
+        ] {
+            let line = sample_line(&rendered, prefix).unwrap_or_else(|| panic!("{}", prefix));
+            assert_eq!(line.rsplit(' ').next(), Some(expected), "{}", line);
+        }
+        assert!(rendered.contains("# TYPE turn_handler_errors_total counter\n"));
+        // サーバー側の失敗は不正なpacketとは別に数える
+        assert!(!rendered.contains("reason=\"handler_error\""));
+    }
+
+    #[test]
+    fn test_render_gauges() {
+        let metrics = Metrics::new();
+        metrics.allocation_created();
+        metrics.allocation_created();
+        metrics.allocation_deleted();
+        metrics.permission_created();
+        metrics.channel_bound();
+        metrics.channel_deleted();
+        let rendered = metrics.render();
+
+        for (name, expected) in [
+            ("turn_active_allocations", "1"),
+            ("turn_active_permissions", "1"),
+            ("turn_active_channels", "0"),
+        ] {
+            assert!(rendered.contains(&format!("# TYPE {} gauge\n", name)));
+            assert!(rendered.contains(&format!("\n{} {}\n", name, expected)));
+        }
+    }
+}
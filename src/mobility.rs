@@ -0,0 +1,161 @@
+use crate::error::*;
+use crate::oauth::{aead_decrypt, aead_encrypt};
+use std::time::{Duration, SystemTime};
+use stun::attribute::*;
+use stun::error_code::ErrorCode;
+use stun::message::*;
+
+// RFC 8016 sec 3.3
+// Allocateでは空のまま送ってmobilityを要求し、responseとRefreshではticketを運ぶ
+pub const ATTR_MOBILITY_TICKET: AttrType = AttrType(0x8030);
+// RFC 8016 sec 3.4
+// ticketが無効、またはticketのallocationを別のユーザーが移そうとした
+pub const CODE_MOBILITY_FORBIDDEN: ErrorCode = ErrorCode(405);
+
+const NONCE_SIZE: usize = 12;
+const KEY_SIZE: usize = 32;
+// allocation_id(8) | expires_at(8) | username
+const TICKET_HEADER_SIZE: usize = 16;
+
+pub struct MobilityTicket(pub Vec<u8>);
+
+impl MobilityTicket {
+    pub fn get_from(m: &Message) -> Option<MobilityTicket> {
+        m.attributes
+            .iter()
+            .find(|e| e.typ == ATTR_MOBILITY_TICKET)
+            .map(|attribute| MobilityTicket(attribute.value.clone()))
+    }
+}
+
+impl Setter for MobilityTicket {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let extra_attribute =
+            Attribute::new(ATTR_MOBILITY_TICKET, self.0.len() as u16, self.0.clone());
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
+
+// ticketの中身。5-tupleは移動で変わるので、allocationはidで探す
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TicketContents {
+    pub allocation_id: u64,
+    pub username: String,
+    pub expires_at: SystemTime,
+}
+
+// RFC 8016 sec 3.5
+// ticketの形式はサーバーが自由に決めてよいので、
+// nonce(12) | AES-256-GCM(allocation_id | expires_at | username) とする。
+// keyはサーバーの起動ごとに作る。allocationも再起動で消えるので困らない
+pub struct MobilityTicketSealer {
+    key: Vec<u8>,
+}
+
+impl Default for MobilityTicketSealer {
+    fn default() -> Self {
+        MobilityTicketSealer::new()
+    }
+}
+
+impl MobilityTicketSealer {
+    pub fn new() -> Self {
+        MobilityTicketSealer {
+            key: (0..KEY_SIZE).map(|_| rand::random::<u8>()).collect(),
+        }
+    }
+
+    // allocationの残りの寿命だけ有効なticketを作る
+    pub fn seal(&self, allocation_id: u64, username: &str, lifetime: Duration) -> Result<Vec<u8>> {
+        let expires_at = (SystemTime::now() + lifetime)
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        let mut plaintext = Vec::with_capacity(TICKET_HEADER_SIZE + username.len());
+        plaintext.extend_from_slice(&allocation_id.to_be_bytes());
+        plaintext.extend_from_slice(&expires_at.to_be_bytes());
+        plaintext.extend_from_slice(username.as_bytes());
+
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let encrypted = aead_encrypt(&self.key, &nonce, &plaintext, &[])?;
+        let mut ticket = Vec::with_capacity(NONCE_SIZE + encrypted.len());
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&encrypted);
+        Ok(ticket)
+    }
+
+    // 改竄されたticketや期限切れのticketはエラー
+    pub fn open(&self, ticket: &[u8]) -> Result<TicketContents> {
+        if ticket.len() < NONCE_SIZE {
+            return Err(Error::ErrInvalidMobilityTicket);
+        }
+        let plaintext = aead_decrypt(&self.key, &ticket[..NONCE_SIZE], &ticket[NONCE_SIZE..], &[])
+            .map_err(|_| Error::ErrInvalidMobilityTicket)?;
+        if plaintext.len() < TICKET_HEADER_SIZE {
+            return Err(Error::ErrInvalidMobilityTicket);
+        }
+        let mut allocation_id = [0u8; 8];
+        allocation_id.copy_from_slice(&plaintext[..8]);
+        let mut expires_at = [0u8; 8];
+        expires_at.copy_from_slice(&plaintext[8..TICKET_HEADER_SIZE]);
+        let contents = TicketContents {
+            allocation_id: u64::from_be_bytes(allocation_id),
+            username: String::from_utf8_lossy(&plaintext[TICKET_HEADER_SIZE..]).to_string(),
+            expires_at: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(u64::from_be_bytes(expires_at)),
+        };
+        if contents.expires_at <= SystemTime::now() {
+            return Err(Error::ErrInvalidMobilityTicket);
+        }
+        Ok(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket_round_trip() {
+        let sealer = MobilityTicketSealer::new();
+        let ticket = sealer.seal(42, "alice", Duration::from_secs(600)).unwrap();
+        let contents = sealer.open(&ticket).unwrap();
+        assert_eq!(contents.allocation_id, 42);
+        assert_eq!(contents.username, "alice");
+    }
+
+    #[test]
+    fn test_tampered_ticket() {
+        let sealer = MobilityTicketSealer::new();
+        let ticket = sealer.seal(42, "alice", Duration::from_secs(600)).unwrap();
+        for i in [0, NONCE_SIZE, ticket.len() - 1] {
+            let mut tampered = ticket.clone();
+            tampered[i] ^= 0x01;
+            assert_eq!(sealer.open(&tampered), Err(Error::ErrInvalidMobilityTicket));
+        }
+        assert_eq!(
+            sealer.open(&ticket[..NONCE_SIZE - 1]),
+            Err(Error::ErrInvalidMobilityTicket)
+        );
+    }
+
+    #[test]
+    fn test_ticket_from_another_server() {
+        // keyは起動ごとに作るので、再起動前のticketは開けない
+        let ticket = MobilityTicketSealer::new()
+            .seal(42, "alice", Duration::from_secs(600))
+            .unwrap();
+        assert_eq!(
+            MobilityTicketSealer::new().open(&ticket),
+            Err(Error::ErrInvalidMobilityTicket)
+        );
+    }
+
+    #[test]
+    fn test_expired_ticket() {
+        // serverはallocationの残りの寿命で作るので、寿命の尽きたallocationのticketと同じ
+        let sealer = MobilityTicketSealer::new();
+        let ticket = sealer.seal(42, "alice", Duration::from_secs(0)).unwrap();
+        assert_eq!(sealer.open(&ticket), Err(Error::ErrInvalidMobilityTicket));
+    }
+}
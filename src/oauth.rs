@@ -0,0 +1,434 @@
+use crate::auth::AuthHandler;
+use crate::error::*;
+use crate::integrity::MessageIntegrity;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use stun::attribute::*;
+use stun::message::*;
+
+// RFC 7635 sec 6.2
+// AES-GCMのnonceは12byte
+const NONCE_SIZE: usize = 12;
+// timestampは上位48bitが秒、下位16bitが1/64000秒
+const TIMESTAMP_FRACTIONS_PER_SECOND: u64 = 64000;
+// ACCESS-TOKENを発行したサーバーとの時計のずれをこれだけ許す
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+// RFC 7635 sec 6.1
+// 401のresponseで、clientがACCESS-TOKENを取りに行く認可サーバーの名前を伝える
+pub struct ThirdPartyAuthorization(pub String);
+
+impl ThirdPartyAuthorization {
+    pub fn get_from(m: &Message) -> Option<ThirdPartyAuthorization> {
+        m.attributes
+            .iter()
+            .find(|e| e.typ == ATTR_THIRD_PARTY_AUTHORIZATION)
+            .map(|attribute| {
+                ThirdPartyAuthorization(String::from_utf8_lossy(&attribute.value).to_string())
+            })
+    }
+}
+
+impl Setter for ThirdPartyAuthorization {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let raw = self.0.as_bytes().to_vec();
+        let extra_attribute = Attribute::new(ATTR_THIRD_PARTY_AUTHORIZATION, raw.len() as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
+
+// RFC 7635 sec 6.2
+// 暗号化されたACCESS-TOKENをそのまま運ぶ。復号はAccessToken::decryptで行う
+pub struct AccessTokenAttribute(pub Vec<u8>);
+
+impl AccessTokenAttribute {
+    pub fn get_from(m: &Message) -> Option<AccessTokenAttribute> {
+        m.attributes
+            .iter()
+            .find(|e| e.typ == ATTR_ACCESS_TOKEN)
+            .map(|attribute| AccessTokenAttribute(attribute.value.clone()))
+    }
+}
+
+impl Setter for AccessTokenAttribute {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let extra_attribute =
+            Attribute::new(ATTR_ACCESS_TOKEN, self.0.len() as u16, self.0.clone());
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
+
+// 認可サーバーとTURNサーバーで共有する、ACCESS-TOKENを暗号化するkey。kidで識別する
+#[derive(Clone)]
+pub struct AccessTokenKey {
+    pub kid: String,
+    // 16byteならAES-128-GCM、32byteならAES-256-GCM
+    pub key: Vec<u8>,
+}
+
+// RFC 7635 sec 6.2
+// ACCESS-TOKENの中身。mac_keyをMESSAGE-INTEGRITYのkeyとして使う
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessToken {
+    pub mac_key: Vec<u8>,
+    pub timestamp: SystemTime,
+    pub lifetime: Duration,
+}
+
+impl AccessToken {
+    // nonce_length(2) | nonce | AES-GCM(key_length(2) | mac_key | timestamp(8) | lifetime(4))
+    // server_nameはAEADのassociated dataとして使う
+    pub fn encrypt(&self, key: &[u8], nonce: &[u8], server_name: &str) -> Result<Vec<u8>> {
+        if nonce.len() != NONCE_SIZE {
+            return Err(Error::ErrInvalidAccessToken);
+        }
+        let mut plaintext = Vec::with_capacity(2 + self.mac_key.len() + 12);
+        plaintext.extend_from_slice(&(self.mac_key.len() as u16).to_be_bytes());
+        plaintext.extend_from_slice(&self.mac_key);
+        plaintext.extend_from_slice(&encode_timestamp(self.timestamp)?.to_be_bytes());
+        plaintext.extend_from_slice(&(self.lifetime.as_secs() as u32).to_be_bytes());
+        let encrypted = aead_encrypt(key, nonce, &plaintext, server_name.as_bytes())?;
+
+        let mut token = Vec::with_capacity(2 + nonce.len() + encrypted.len());
+        token.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+        token.extend_from_slice(nonce);
+        token.extend_from_slice(&encrypted);
+        Ok(token)
+    }
+
+    pub fn decrypt(token: &[u8], key: &[u8], server_name: &str) -> Result<AccessToken> {
+        if token.len() < 2 {
+            return Err(Error::ErrInvalidAccessToken);
+        }
+        let nonce_length = u16::from_be_bytes([token[0], token[1]]) as usize;
+        if nonce_length != NONCE_SIZE || token.len() < 2 + nonce_length {
+            return Err(Error::ErrInvalidAccessToken);
+        }
+        let nonce = &token[2..2 + nonce_length];
+        let plaintext = aead_decrypt(
+            key,
+            nonce,
+            &token[2 + nonce_length..],
+            server_name.as_bytes(),
+        )?;
+
+        if plaintext.len() < 2 {
+            return Err(Error::ErrInvalidAccessToken);
+        }
+        let key_length = u16::from_be_bytes([plaintext[0], plaintext[1]]) as usize;
+        if plaintext.len() != 2 + key_length + 8 + 4 {
+            return Err(Error::ErrInvalidAccessToken);
+        }
+        let mac_key = plaintext[2..2 + key_length].to_vec();
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&plaintext[2 + key_length..2 + key_length + 8]);
+        let mut lifetime = [0u8; 4];
+        lifetime.copy_from_slice(&plaintext[2 + key_length + 8..]);
+        Ok(AccessToken {
+            mac_key,
+            timestamp: decode_timestamp(u64::from_be_bytes(timestamp)),
+            lifetime: Duration::from_secs(u32::from_be_bytes(lifetime) as u64),
+        })
+    }
+
+    // 発行時刻が未来すぎず、期限が切れていなければ有効
+    pub fn is_valid(&self, now: SystemTime) -> bool {
+        if self.timestamp > now + MAX_CLOCK_SKEW {
+            return false;
+        }
+        self.timestamp + self.lifetime + MAX_CLOCK_SKEW > now
+    }
+}
+
+fn encode_timestamp(timestamp: SystemTime) -> Result<u64> {
+    let since_epoch = timestamp.duration_since(SystemTime::UNIX_EPOCH)?;
+    let fraction =
+        since_epoch.subsec_nanos() as u64 * TIMESTAMP_FRACTIONS_PER_SECOND / 1_000_000_000;
+    Ok((since_epoch.as_secs() << 16) | fraction)
+}
+
+fn decode_timestamp(timestamp: u64) -> SystemTime {
+    let seconds = timestamp >> 16;
+    let nanos = (timestamp & 0xFFFF) * 1_000_000_000 / TIMESTAMP_FRACTIONS_PER_SECOND;
+    SystemTime::UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_nanos(nanos)
+}
+
+pub(crate) fn aead_encrypt(
+    key: &[u8],
+    nonce: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    let nonce = Nonce::from_slice(nonce);
+    let encrypted = match key.len() {
+        16 => Aes128Gcm::new_from_slice(key)
+            .map_err(|_| Error::ErrInvalidAccessTokenKey)?
+            .encrypt(nonce, payload),
+        32 => Aes256Gcm::new_from_slice(key)
+            .map_err(|_| Error::ErrInvalidAccessTokenKey)?
+            .encrypt(nonce, payload),
+        _ => return Err(Error::ErrInvalidAccessTokenKey),
+    };
+    encrypted.map_err(|_| Error::ErrInvalidAccessToken)
+}
+
+pub(crate) fn aead_decrypt(
+    key: &[u8],
+    nonce: &[u8],
+    encrypted: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let payload = Payload {
+        msg: encrypted,
+        aad,
+    };
+    let nonce = Nonce::from_slice(nonce);
+    let plaintext = match key.len() {
+        16 => Aes128Gcm::new_from_slice(key)
+            .map_err(|_| Error::ErrInvalidAccessTokenKey)?
+            .decrypt(nonce, payload),
+        32 => Aes256Gcm::new_from_slice(key)
+            .map_err(|_| Error::ErrInvalidAccessTokenKey)?
+            .decrypt(nonce, payload),
+        _ => return Err(Error::ErrInvalidAccessTokenKey),
+    };
+    // 鍵が違うか改竄されていればタグの検証に失敗する
+    plaintext.map_err(|_| Error::ErrInvalidAccessToken)
+}
+
+// RFC 7635
+// USERNAMEにkidが入ったrequestのACCESS-TOKENを復号し、その中のmac_keyで認証する。
+// ACCESS-TOKENの無いrequestはfallbackのAuthHandlerに任せる
+//
+// tokenにはユーザーを表すものが入っていないので、serverから見たユーザー名はkidになる。
+// 同じkidのtokenを持つclientは、ユーザーごとのallocation数の上限やAclSubject::Userの上では
+// 全て1人のユーザーとして扱われる。ユーザーごとに分けたい場合は認可サーバーがユーザーごとにkidを発行する
+pub struct OAuthAuthHandler {
+    // THIRD-PARTY-AUTHORIZATIONとしてclientに伝え、AEADのassociated dataにも使う
+    server_name: String,
+    // kid -> key
+    keys: HashMap<String, Vec<u8>>,
+    fallback: Option<Arc<dyn AuthHandler>>,
+}
+
+impl OAuthAuthHandler {
+    pub fn new(server_name: String, keys: Vec<AccessTokenKey>) -> Self {
+        OAuthAuthHandler {
+            server_name,
+            keys: keys.into_iter().map(|key| (key.kid, key.key)).collect(),
+            fallback: None,
+        }
+    }
+
+    // long-term credentialのclientも同時に受け付ける
+    pub fn with_fallback(mut self, fallback: Arc<dyn AuthHandler>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+}
+
+impl AuthHandler for OAuthAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<MessageIntegrity> {
+        match &self.fallback {
+            Some(fallback) => fallback.auth_handle(username, realm, src_addr),
+            None => Err(Error::ErrNoSuchUser),
+        }
+    }
+
+    fn access_token_handle(
+        &self,
+        kid: &str,
+        access_token: &[u8],
+        _src_addr: SocketAddr,
+    ) -> Result<MessageIntegrity> {
+        let key = self.keys.get(kid).ok_or(Error::ErrNoSuchUser)?;
+        let token = AccessToken::decrypt(access_token, key, &self.server_name)?;
+        if !token.is_valid(SystemTime::now()) {
+            return Err(Error::ErrCredentialExpired);
+        }
+        Ok(MessageIntegrity(token.mac_key))
+    }
+
+    fn third_party_authorization(&self) -> Option<String> {
+        Some(self.server_name.clone())
+    }
+}
+
+// clientが認可サーバーから受け取ったACCESS-TOKENと、その中に入っているmac_key
+#[derive(Clone)]
+pub struct AccessTokenCredential {
+    pub kid: String,
+    pub token: Vec<u8>,
+    pub mac_key: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientConfig};
+    use crate::server::{Server, ServerConfig};
+    use crate::util::Conn;
+    use crate::vnet::{Net, NetConfig, Router, RouterConfig};
+
+    const SERVER_NAME: &str = "auth.example.com";
+    const KEY: [u8; 16] = [0x11; 16];
+    const NONCE: [u8; NONCE_SIZE] = [0x22; NONCE_SIZE];
+
+    fn token(timestamp: SystemTime) -> AccessToken {
+        AccessToken {
+            mac_key: vec![0x33; 20],
+            timestamp,
+            lifetime: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn test_access_token_round_trip() {
+        for key in [vec![0x11; 16], vec![0x11; 32]] {
+            let access_token = token(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+            let encrypted = access_token.encrypt(&key, &NONCE, SERVER_NAME).unwrap();
+            let decrypted = AccessToken::decrypt(&encrypted, &key, SERVER_NAME).unwrap();
+            assert_eq!(decrypted, access_token);
+        }
+    }
+
+    #[test]
+    fn test_access_token_wrong_key() {
+        let encrypted = token(SystemTime::now())
+            .encrypt(&KEY, &NONCE, SERVER_NAME)
+            .unwrap();
+        assert_eq!(
+            AccessToken::decrypt(&encrypted, &[0x44; 16], SERVER_NAME),
+            Err(Error::ErrInvalidAccessToken)
+        );
+        assert_eq!(
+            AccessToken::decrypt(&encrypted, &[0x11; 15], SERVER_NAME),
+            Err(Error::ErrInvalidAccessTokenKey)
+        );
+    }
+
+    #[test]
+    fn test_access_token_wrong_server_name() {
+        // server_nameはassociated dataなので、別のサーバー宛てのtokenは復号できない
+        let encrypted = token(SystemTime::now())
+            .encrypt(&KEY, &NONCE, SERVER_NAME)
+            .unwrap();
+        assert_eq!(
+            AccessToken::decrypt(&encrypted, &KEY, "other.example.com"),
+            Err(Error::ErrInvalidAccessToken)
+        );
+    }
+
+    #[test]
+    fn test_access_token_truncated() {
+        let encrypted = token(SystemTime::now())
+            .encrypt(&KEY, &NONCE, SERVER_NAME)
+            .unwrap();
+        for len in [0, 1, 2, 2 + NONCE_SIZE, encrypted.len() - 1] {
+            assert_eq!(
+                AccessToken::decrypt(&encrypted[..len], &KEY, SERVER_NAME),
+                Err(Error::ErrInvalidAccessToken),
+                "length {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn test_access_token_is_valid() {
+        let now = SystemTime::now();
+        assert!(token(now).is_valid(now));
+        assert!(token(now + MAX_CLOCK_SKEW / 2).is_valid(now));
+        // 発行時刻が未来すぎる
+        assert!(!token(now + MAX_CLOCK_SKEW * 2).is_valid(now));
+        // lifetimeとclock skewを過ぎている
+        let expired = now - Duration::from_secs(3600) - MAX_CLOCK_SKEW * 2;
+        assert!(!token(expired).is_valid(now));
+    }
+
+    // 同じkidで発行された別々のtokenは、ユーザーごとの上限の上では同じユーザーになる
+    #[tokio::test]
+    async fn test_tokens_with_same_kid_share_user_quota() {
+        let router = Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_string(),
+            nat: None,
+        })
+        .unwrap();
+        let server_net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.1".to_string()],
+        })
+        .unwrap();
+        router.add_net(&server_net).unwrap();
+        let client_net = Net::new(NetConfig::default()).unwrap();
+        router.add_net(&client_net).unwrap();
+
+        let realm = "oauth-realm";
+        let auth_handler = OAuthAuthHandler::new(
+            SERVER_NAME.to_string(),
+            vec![AccessTokenKey {
+                kid: "kid".to_string(),
+                key: KEY.to_vec(),
+            }],
+        );
+        let conn: Arc<dyn Conn + Send + Sync> =
+            Arc::new(server_net.bind("10.0.0.1:3478".parse().unwrap()).unwrap());
+        let mut config = ServerConfig::new(
+            vec![conn],
+            realm.to_string(),
+            Arc::new(auth_handler),
+            "10.0.0.1".parse().unwrap(),
+        );
+        config.max_allocations_per_user = Some(1);
+        config.vnet = Some(Arc::clone(&server_net));
+        let server = Server::new(config).await.unwrap();
+
+        let mut results = vec![];
+        for mac_key in [vec![0x55; 20], vec![0x66; 20]] {
+            let access_token = AccessToken {
+                mac_key: mac_key.clone(),
+                timestamp: SystemTime::now(),
+                lifetime: Duration::from_secs(3600),
+            };
+            let client = Client::new(ClientConfig {
+                turn_server_address: "10.0.0.1:3478".to_string(),
+                username: String::new(),
+                password: String::new(),
+                access_token: Some(AccessTokenCredential {
+                    kid: "kid".to_string(),
+                    token: access_token.encrypt(&KEY, &NONCE, SERVER_NAME).unwrap(),
+                    mac_key,
+                }),
+                mobility: false,
+                connection: Arc::new(client_net.bind("0.0.0.0:0".parse().unwrap()).unwrap()),
+                net: Some(Arc::clone(&client_net)),
+            })
+            .await
+            .unwrap();
+            client.listen().await.unwrap();
+            results.push(client.allocate().await);
+            client.close().await.unwrap();
+        }
+        assert!(results[0].is_ok());
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::ErrErrorResponse(486))
+        );
+        server.close().await.unwrap();
+    }
+}
@@ -0,0 +1,29 @@
+use crate::five_tuple::FiveTuple;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// 課金や監視のためにサーバー内部のイベントを外に通知する
+#[derive(Debug, Clone)]
+pub struct AllocationEvent {
+    pub username: String,
+    pub five_tuple: FiveTuple,
+    // allocationが作られる前のイベント(認証失敗など)ではNone
+    pub relayed_address: Option<SocketAddr>,
+    // client -> peer 方向にrelayしたbyte数
+    pub bytes_sent: u64,
+    // peer -> client 方向にrelayしたbyte数
+    pub bytes_received: u64,
+}
+
+// コールバックはrequestの処理中に同期的に呼ばれるので、重い処理はchannelなどで別のtaskに渡すこと
+pub trait ServerObserver: Send + Sync {
+    fn on_allocation_created(&self, _event: &AllocationEvent, _lifetime: Duration) {}
+    fn on_allocation_refreshed(&self, _event: &AllocationEvent, _lifetime: Duration) {}
+    fn on_allocation_deleted(&self, _event: &AllocationEvent) {}
+    fn on_permission_created(&self, _event: &AllocationEvent, _peer: SocketAddr) {}
+    fn on_channel_bound(&self, _event: &AllocationEvent, _peer: SocketAddr, _channel_number: u16) {}
+    fn on_auth_failure(&self, _event: &AllocationEvent) {}
+    fn on_quota_rejected(&self, _event: &AllocationEvent) {}
+    // RFC 8016のmobilityでallocationがold_five_tupleからevent.five_tupleに移った
+    fn on_allocation_migrated(&self, _event: &AllocationEvent, _old_five_tuple: FiveTuple) {}
+}
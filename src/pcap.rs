@@ -0,0 +1,499 @@
+use crate::util::{Conn, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+// pcapng (draft-ietf-opsawg-pcapng) のblock type
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+// IPv4/IPv6のheaderから始まるpacket。Ethernetのheaderは作らない
+const LINKTYPE_RAW: u16 = 101;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+// 書き出しを待っているpacketの数の上限。溢れたらrelayを遅らせないように捨てる
+const WRITE_QUEUE_SIZE: usize = 4096;
+
+// どのallocationのpacketを記録するか。usernamesとallocation_idsのどちらかに当たれば記録する
+#[derive(Debug, Clone, Default)]
+pub struct CaptureFilter {
+    pub usernames: Vec<String>,
+    pub allocation_ids: Vec<u64>,
+}
+
+impl CaptureFilter {
+    fn matches(&self, allocation_id: u64, username: &str) -> bool {
+        self.allocation_ids.contains(&allocation_id)
+            || self.usernames.iter().any(|name| name == username)
+    }
+}
+
+struct TapAllocation {
+    username: String,
+    client_addr: SocketAddr,
+}
+
+struct TapState {
+    // Noneなら全てのpacketを記録する
+    filter: Option<CaptureFilter>,
+    // allocation id -> そのallocationのusernameとclientのaddress
+    allocations: HashMap<u64, TapAllocation>,
+    // client address -> そのclientのallocation id。listenerが複数あれば1つのaddressに複数ある
+    by_client_addr: HashMap<SocketAddr, Vec<u64>>,
+}
+
+impl TapState {
+    fn index_client_addr(&mut self, allocation_id: u64, client_addr: SocketAddr) {
+        self.by_client_addr
+            .entry(client_addr)
+            .or_default()
+            .push(allocation_id);
+    }
+
+    fn unindex_client_addr(&mut self, allocation_id: u64, client_addr: SocketAddr) {
+        if let Some(ids) = self.by_client_addr.get_mut(&client_addr) {
+            ids.retain(|id| *id != allocation_id);
+            if ids.is_empty() {
+                self.by_client_addr.remove(&client_addr);
+            }
+        }
+    }
+}
+
+// 記録した時刻(UNIX epochからのマイクロ秒)とpacket
+struct CapturedPacket {
+    timestamp: u64,
+    packet: Vec<u8>,
+}
+
+// serverが送受信したUDPのpayloadに、IP/UDPのheaderを付けてpcapngに書き出す。
+// client側はlistenerのsocket、peer側はrelay socketで記録する。
+// filterがある場合、client側はallocationを作った後のpacketだけが記録される。
+// 書き出しはblockingのtaskで行い、送受信しているtaskはqueueに積むだけにする
+pub struct PcapTap {
+    tx: mpsc::Sender<CapturedPacket>,
+    state: Mutex<TapState>,
+    dropped: AtomicU64,
+}
+
+impl PcapTap {
+    pub fn create<P: AsRef<Path>>(path: P, filter: Option<CaptureFilter>) -> Result<Arc<PcapTap>> {
+        let file = File::create(path)?;
+        PcapTap::new(Box::new(BufWriter::new(file)), filter)
+    }
+
+    // 書き出すtaskを動かすので、tokioのruntimeの中で作ること。
+    // PcapTapが捨てられると、queueに残っているpacketを書き出してからtaskが終わる
+    pub fn new(
+        mut writer: Box<dyn Write + Send>,
+        filter: Option<CaptureFilter>,
+    ) -> Result<Arc<PcapTap>> {
+        write_header(&mut writer)?;
+        writer.flush()?;
+        let (tx, rx) = mpsc::channel(WRITE_QUEUE_SIZE);
+        tokio::task::spawn_blocking(move || write_loop(writer, rx));
+        Ok(Arc::new(PcapTap {
+            tx,
+            state: Mutex::new(TapState {
+                filter,
+                allocations: HashMap::new(),
+                by_client_addr: HashMap::new(),
+            }),
+            dropped: AtomicU64::new(0),
+        }))
+    }
+
+    // queueが溢れて記録できなかったpacketの数
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // 動いている間にfilterを変える。既にあるallocationにも次のpacketから効く
+    pub fn set_filter(&self, filter: Option<CaptureFilter>) {
+        self.state.lock().unwrap().filter = filter;
+    }
+
+    pub(crate) fn register_allocation(
+        &self,
+        allocation_id: u64,
+        username: &str,
+        client_addr: SocketAddr,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.allocations.insert(
+            allocation_id,
+            TapAllocation {
+                username: username.to_string(),
+                client_addr,
+            },
+        ) {
+            state.unindex_client_addr(allocation_id, old.client_addr);
+        }
+        state.index_client_addr(allocation_id, client_addr);
+    }
+
+    // RFC 8016のmobilityでclientのaddressが変わった時に呼ぶ
+    pub(crate) fn update_client_addr(&self, allocation_id: u64, client_addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        let old_client_addr = match state.allocations.get_mut(&allocation_id) {
+            Some(allocation) => std::mem::replace(&mut allocation.client_addr, client_addr),
+            None => return,
+        };
+        state.unindex_client_addr(allocation_id, old_client_addr);
+        state.index_client_addr(allocation_id, client_addr);
+    }
+
+    pub(crate) fn unregister_allocation(&self, allocation_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(allocation) = state.allocations.remove(&allocation_id) {
+            state.unindex_client_addr(allocation_id, allocation.client_addr);
+        }
+    }
+
+    fn should_capture(&self, side: TapSide, remote: SocketAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        let filter = match &state.filter {
+            Some(filter) => filter,
+            None => return true,
+        };
+        match side {
+            TapSide::Client => state
+                .by_client_addr
+                .get(&remote)
+                .into_iter()
+                .flatten()
+                .filter_map(|id| Some((*id, state.allocations.get(id)?)))
+                .any(|(id, allocation)| filter.matches(id, &allocation.username)),
+            TapSide::Peer(allocation_id) => state
+                .allocations
+                .get(&allocation_id)
+                .map_or(false, |allocation| {
+                    filter.matches(allocation_id, &allocation.username)
+                }),
+        }
+    }
+
+    fn capture(&self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let packet = build_udp_packet(src, dst, payload);
+        if self
+            .tx
+            .try_send(CapturedPacket { timestamp, packet })
+            .is_err()
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// queueが空になるたびにflushする。PcapTapが捨てられてqueueが閉じたら終わる
+fn write_loop(mut writer: Box<dyn Write + Send>, mut rx: mpsc::Receiver<CapturedPacket>) {
+    while let Some(captured) = rx.blocking_recv() {
+        let mut result = write_packet(&mut *writer, captured.timestamp, &captured.packet);
+        while let Ok(captured) = rx.try_recv() {
+            result = result
+                .and_then(|_| write_packet(&mut *writer, captured.timestamp, &captured.packet));
+        }
+        // 書けなくてもrelayは止めない
+        if let Err(err) = result.and_then(|_| writer.flush()) {
+            tracing::warn!(error = %err, "failed to write pcap");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TapSide {
+    // listenerのsocket。相手はclient
+    Client,
+    // このallocationのrelay socket。相手はpeer
+    Peer(u64),
+}
+
+// Connを包み、送受信したdatagramをPcapTapに記録する
+pub(crate) struct TapConn {
+    inner: Arc<dyn Conn + Send + Sync>,
+    tap: Arc<PcapTap>,
+    side: TapSide,
+}
+
+impl TapConn {
+    pub(crate) fn client_side(inner: Arc<dyn Conn + Send + Sync>, tap: Arc<PcapTap>) -> Self {
+        TapConn {
+            inner,
+            tap,
+            side: TapSide::Client,
+        }
+    }
+
+    pub(crate) fn peer_side(
+        inner: Arc<dyn Conn + Send + Sync>,
+        tap: Arc<PcapTap>,
+        allocation_id: u64,
+    ) -> Self {
+        TapConn {
+            inner,
+            tap,
+            side: TapSide::Peer(allocation_id),
+        }
+    }
+
+    async fn capture_sent(&self, buf: &[u8], target: SocketAddr) {
+        if !self.tap.should_capture(self.side, target) {
+            return;
+        }
+        if let Ok(local_addr) = self.inner.local_addr().await {
+            self.tap.capture(local_addr, target, buf);
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for TapConn {
+    async fn connect(&self, addr: SocketAddr) -> Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let (n, from) = self.inner.recv_from(buf).await?;
+        if self.tap.should_capture(self.side, from) {
+            if let Ok(local_addr) = self.inner.local_addr().await {
+                self.tap.capture(from, local_addr, &buf[..n]);
+            }
+        }
+        Ok((n, from))
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.send(buf).await?;
+        if let Some(remote_addr) = self.inner.remote_addr().await {
+            self.capture_sent(&buf[..n], remote_addr).await;
+        }
+        Ok(n)
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
+        let n = self.inner.send_to(buf, target).await?;
+        self.capture_sent(&buf[..n], target).await;
+        Ok(n)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr().await
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.inner.close().await
+    }
+
+    async fn send_batch(&self, packets: &[(&[u8], SocketAddr)]) -> Result<usize> {
+        let n = self.inner.send_batch(packets).await?;
+        for (buf, target) in &packets[..n] {
+            self.capture_sent(buf, *target).await;
+        }
+        Ok(n)
+    }
+}
+
+fn write_header(writer: &mut dyn Write) -> io::Result<()> {
+    // Section Header Block: section lengthは-1(不明)にして、optionは付けない
+    let mut shb = vec![];
+    shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    shb.extend_from_slice(&1u16.to_le_bytes());
+    shb.extend_from_slice(&0u16.to_le_bytes());
+    shb.extend_from_slice(&(-1i64).to_le_bytes());
+    write_block(writer, SECTION_HEADER_BLOCK, &shb)?;
+    // Interface Description Block: if_tsresolを付けないので、timestampはマイクロ秒になる
+    let mut idb = vec![];
+    idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    idb.extend_from_slice(&0u16.to_le_bytes());
+    idb.extend_from_slice(&0u32.to_le_bytes());
+    write_block(writer, INTERFACE_DESCRIPTION_BLOCK, &idb)
+}
+
+fn write_packet(writer: &mut dyn Write, timestamp: u64, packet: &[u8]) -> io::Result<()> {
+    let mut epb = Vec::with_capacity(20 + packet.len() + 3);
+    epb.extend_from_slice(&0u32.to_le_bytes());
+    epb.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    epb.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    epb.extend_from_slice(packet);
+    write_block(writer, ENHANCED_PACKET_BLOCK, &epb)
+}
+
+// block type | block total length | body (4 byte境界までpadding) | block total length
+fn write_block(writer: &mut dyn Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total_length = (12 + body.len() + padding) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_length.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&[0u8; 3][..padding])?;
+    writer.write_all(&total_length.to_le_bytes())
+}
+
+// 送信元と宛先のfamilyが違う場合は、IPv4をIPv4-mapped IPv6にしてIPv6で書く
+fn build_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_length = UDP_HEADER_SIZE + payload.len();
+    let mut udp = Vec::with_capacity(udp_length);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_length as u16).to_be_bytes());
+    udp.extend_from_slice(&0u16.to_be_bytes());
+    udp.extend_from_slice(payload);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut pseudo_header = vec![];
+            pseudo_header.extend_from_slice(&src_ip.octets());
+            pseudo_header.extend_from_slice(&dst_ip.octets());
+            pseudo_header.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo_header.extend_from_slice(&(udp_length as u16).to_be_bytes());
+            set_udp_checksum(&mut udp, &pseudo_header);
+
+            let mut packet = Vec::with_capacity(IPV4_HEADER_SIZE + udp_length);
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((IPV4_HEADER_SIZE + udp_length) as u16).to_be_bytes());
+            // identificationは0で、Don't Fragmentを立てる
+            packet.extend_from_slice(&[0, 0, 0x40, 0, DEFAULT_TTL, IPPROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            let checksum = internet_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&udp);
+            packet
+        }
+        (src_ip, dst_ip) => {
+            let src_ip = to_ipv6(src_ip).octets();
+            let dst_ip = to_ipv6(dst_ip).octets();
+            let mut pseudo_header = vec![];
+            pseudo_header.extend_from_slice(&src_ip);
+            pseudo_header.extend_from_slice(&dst_ip);
+            pseudo_header.extend_from_slice(&(udp_length as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+            set_udp_checksum(&mut udp, &pseudo_header);
+
+            let mut packet = Vec::with_capacity(IPV6_HEADER_SIZE + udp_length);
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_length as u16).to_be_bytes());
+            packet.extend_from_slice(&[IPPROTO_UDP, DEFAULT_TTL]);
+            packet.extend_from_slice(&src_ip);
+            packet.extend_from_slice(&dst_ip);
+            packet.extend_from_slice(&udp);
+            packet
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+// RFC 768
+// 計算結果が0の場合は、checksum無しと区別するために0xFFFFにする
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let mut data = Vec::with_capacity(pseudo_header.len() + udp.len());
+    data.extend_from_slice(pseudo_header);
+    data.extend_from_slice(udp);
+    let checksum = match internet_checksum(&data) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+// RFC 1071
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice_only() -> Option<CaptureFilter> {
+        Some(CaptureFilter {
+            usernames: vec!["alice".to_string()],
+            allocation_ids: vec![],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_client_side_follows_client_addr() -> Result<()> {
+        let tap = PcapTap::new(Box::new(io::sink()), alice_only())?;
+        let alice: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let bob: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let moved: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        tap.register_allocation(1, "alice", alice);
+        tap.register_allocation(2, "bob", bob);
+        assert!(tap.should_capture(TapSide::Client, alice));
+        assert!(!tap.should_capture(TapSide::Client, bob));
+        assert!(tap.should_capture(TapSide::Peer(1), bob));
+        assert!(!tap.should_capture(TapSide::Peer(2), alice));
+
+        tap.update_client_addr(1, moved);
+        assert!(!tap.should_capture(TapSide::Client, alice));
+        assert!(tap.should_capture(TapSide::Client, moved));
+
+        tap.unregister_allocation(1);
+        assert!(!tap.should_capture(TapSide::Client, moved));
+        assert!(tap
+            .state
+            .lock()
+            .unwrap()
+            .by_client_addr
+            .get(&moved)
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_same_client_addr_on_two_listeners() -> Result<()> {
+        let tap = PcapTap::new(Box::new(io::sink()), alice_only())?;
+        let client: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        tap.register_allocation(1, "bob", client);
+        tap.register_allocation(2, "alice", client);
+        assert!(tap.should_capture(TapSide::Client, client));
+        tap.unregister_allocation(2);
+        assert!(!tap.should_capture(TapSide::Client, client));
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use stun::message::Message;
+
+const REDACTION_KEY_SIZE: usize = 20;
+
+// trueならログのusernameを、同じユーザーだと分かるhashに置き換える
+static REDACT_USERNAMES: AtomicBool = AtomicBool::new(false);
+// usernameのHMACのkey。設定されていなければ、最初に使う時にprocessごとの乱数で作る
+static REDACTION_KEY: RwLock<Option<Vec<u8>>> = RwLock::new(None);
+
+// ログの出力先を共有している場合など、usernameも個人情報として扱いたい時に有効にする。
+// keyやnonce、MESSAGE-INTEGRITYはこの設定に関係なくログに出さない
+pub fn set_redact_usernames(enabled: bool) {
+    REDACT_USERNAMES.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_redacting_usernames() -> bool {
+    REDACT_USERNAMES.load(Ordering::Relaxed)
+}
+
+// 再起動や複数のserverをまたいで同じusernameを同じhashにしたい時に、共通のsaltを設定する。
+// 設定しなければprocessごとに違うhashになる。saltを知らなければhashからusernameを総当たりできない
+pub fn set_redaction_salt(salt: &[u8]) {
+    *REDACTION_KEY.write().unwrap() = Some(salt.to_vec());
+}
+
+fn redaction_mac() -> Hmac<Sha1> {
+    if let Some(key) = &*REDACTION_KEY.read().unwrap() {
+        return Hmac::<Sha1>::new_from_slice(key).expect("HMAC can take key of any size");
+    }
+    let mut key = REDACTION_KEY.write().unwrap();
+    let key = key.get_or_insert_with(|| {
+        let mut key = vec![0u8; REDACTION_KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    });
+    Hmac::<Sha1>::new_from_slice(key).expect("HMAC can take key of any size")
+}
+
+// ログに書くusername。redactしている場合は "user-" とHMAC-SHA1の先頭8桁になる
+pub struct Username<'a>(pub &'a str);
+
+impl fmt::Display for Username<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !is_redacting_usernames() {
+            return f.write_str(self.0);
+        }
+        let mut mac = redaction_mac();
+        mac.update(self.0.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let prefix = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+        write!(f, "user-{:08x}", prefix)
+    }
+}
+
+// messageの種類、transaction_id、attributeの型だけを書く。attributeの値は書かないので、
+// USERNAMEやNONCE、MESSAGE-INTEGRITYがログに残らない
+pub struct MessageSummary<'a>(pub &'a Message);
+
+impl fmt::Display for MessageSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} transaction_id={} attributes=[",
+            self.0.method,
+            self.0.class,
+            TransactionId(&self.0.transaction_id.0)
+        )?;
+        for (i, attribute) in self.0.attributes.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "0x{:04x}", attribute.typ.0)?;
+        }
+        f.write_str("]")
+    }
+}
+
+pub struct TransactionId<'a>(pub &'a [u8]);
+
+impl fmt::Display for TransactionId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // REDACT_USERNAMESとREDACTION_KEYはprocess全体で共有するので、1つのtestで確かめる
+    #[test]
+    fn test_redacted_username_depends_on_salt() {
+        set_redact_usernames(true);
+        let alice = Username("alice").to_string();
+        assert!(alice.starts_with("user-") && alice.len() == "user-".len() + 8);
+        assert_eq!(Username("alice").to_string(), alice);
+        assert_ne!(Username("bob").to_string(), alice);
+
+        set_redaction_salt(b"salt-1");
+        let salted = Username("alice").to_string();
+        assert_ne!(salted, alice);
+        set_redaction_salt(b"salt-2");
+        assert_ne!(Username("alice").to_string(), salted);
+        set_redaction_salt(b"salt-1");
+        assert_eq!(Username("alice").to_string(), salted);
+
+        set_redact_usernames(false);
+        assert_eq!(Username("alice").to_string(), "alice");
+    }
+}
@@ -0,0 +1,227 @@
+use crate::allocation::Allocation;
+use crate::error::*;
+use crate::icmp::*;
+use crate::integrity::*;
+use crate::metrics::*;
+use crate::server::INBOUND_MTU;
+use crate::util::Conn;
+use crate::xor_address::*;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Arc;
+
+// data pathではstunのMessageを経由せず、受信したbyte列をそのまま読み書きする。
+// Messageへのdecode/encodeはattributeごとにVecを作るので、packetごとのallocationを避けられない
+pub(crate) const CHANNEL_DATA_HEADER_SIZE: usize = 4;
+pub(crate) const SEND_INDICATION_TYPE: u16 = 0x0016;
+const DATA_INDICATION_TYPE: u16 = 0x0017;
+const ATTR_XOR_PEER_ADDRESS_TYPE: u16 = 0x0012;
+const ATTR_DATA_TYPE: u16 = 0x0013;
+// Data indicationのheaderの最大長(XOR-PEER-ADDRESSがIPv6の場合)。
+// peerから受信する時はこの分だけ空けておき、payloadの直前にheaderを書き込む
+const RELAY_HEADROOM: usize =
+    MESSAGE_HEADER_SIZE + ATTRIBUTE_HEADER_SIZE + IPV6_ADDRESS_SIZE + ATTRIBUTE_HEADER_SIZE;
+// DATA attributeのvalueを4byte境界に揃えるためのpadding
+const MAX_PADDING: usize = 3;
+// peerからの受信にはINBOUND_MTUより1byte大きい領域を使う。
+// UDPは領域に収まらない分を黙って切り捨てるので、これを使い切ったdatagramは切り詰められている
+const PEER_RECEIVE_SIZE: usize = INBOUND_MTU + 1;
+
+// RFC 5766 sec 11.4
+// ChannelDataのchannel numberとpayloadを返す。UDPでは末尾にpaddingが付いていてもよい
+pub(crate) fn parse_channel_data(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < CHANNEL_DATA_HEADER_SIZE {
+        return None;
+    }
+    let channel_number = u16::from_be_bytes([packet[0], packet[1]]);
+    let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    let data = packet.get(CHANNEL_DATA_HEADER_SIZE..CHANNEL_DATA_HEADER_SIZE + length)?;
+    Some((channel_number, data))
+}
+
+// RFC 5766 sec 10.2
+// Send indicationのXOR-PEER-ADDRESSとDATAを、packetをコピーせずに取り出す
+pub(crate) fn parse_send_indication(packet: &[u8]) -> Result<(SocketAddr, &[u8])> {
+    if packet.len() < MESSAGE_HEADER_SIZE {
+        return Err(Error::ErrPacketTooShort);
+    }
+    let transaction_id = &packet[8..MESSAGE_HEADER_SIZE];
+    let peer = match find_attribute_value(packet, ATTR_XOR_PEER_ADDRESS_TYPE) {
+        Some(raw) => decode_xor_address(raw, transaction_id)?,
+        None => return Err(Error::ErrNoXorPeerAddress),
+    };
+    let data = find_attribute_value(packet, ATTR_DATA_TYPE).ok_or(Error::ErrNoDataAttribute)?;
+    Ok((peer, data))
+}
+
+// buf[RELAY_HEADROOM..RELAY_HEADROOM + len] のpayloadの直前にChannelDataのheaderを書き込み、
+// 送信するべき範囲を返す
+fn frame_channel_data(buf: &mut [u8], channel_number: u16, len: usize) -> Range<usize> {
+    let start = RELAY_HEADROOM - CHANNEL_DATA_HEADER_SIZE;
+    buf[start..start + 2].copy_from_slice(&channel_number.to_be_bytes());
+    buf[start + 2..start + 4].copy_from_slice(&(len as u16).to_be_bytes());
+    start..RELAY_HEADROOM + len
+}
+
+// RFC 5766 sec 10.3
+// buf[RELAY_HEADROOM..RELAY_HEADROOM + len] のpayloadをData indicationで包み、送信するべき範囲を返す
+fn frame_data_indication(buf: &mut [u8], peer: SocketAddr, len: usize) -> Range<usize> {
+    let address_size = xor_address_size(peer);
+    let start = RELAY_HEADROOM
+        - (MESSAGE_HEADER_SIZE + ATTRIBUTE_HEADER_SIZE + address_size + ATTRIBUTE_HEADER_SIZE);
+    let padded_end = RELAY_HEADROOM + ((len + MAX_PADDING) & !MAX_PADDING);
+    for b in &mut buf[RELAY_HEADROOM + len..padded_end] {
+        *b = 0;
+    }
+
+    let mut transaction_id = [0u8; TRANSACTION_ID_SIZE];
+    rand::thread_rng().fill(&mut transaction_id);
+    buf[start..start + 2].copy_from_slice(&DATA_INDICATION_TYPE.to_be_bytes());
+    set_message_length(&mut buf[start..], padded_end - start);
+    buf[start + 4..start + 8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    buf[start + 8..start + MESSAGE_HEADER_SIZE].copy_from_slice(&transaction_id);
+
+    let peer_attribute = start + MESSAGE_HEADER_SIZE;
+    buf[peer_attribute..peer_attribute + 2]
+        .copy_from_slice(&ATTR_XOR_PEER_ADDRESS_TYPE.to_be_bytes());
+    buf[peer_attribute + 2..peer_attribute + 4]
+        .copy_from_slice(&(address_size as u16).to_be_bytes());
+    encode_xor_address_into(
+        peer,
+        &transaction_id,
+        &mut buf[peer_attribute + ATTRIBUTE_HEADER_SIZE..],
+    );
+
+    let data_attribute = peer_attribute + ATTRIBUTE_HEADER_SIZE + address_size;
+    buf[data_attribute..data_attribute + 2].copy_from_slice(&ATTR_DATA_TYPE.to_be_bytes());
+    buf[data_attribute + 2..data_attribute + 4].copy_from_slice(&(len as u16).to_be_bytes());
+    start..padded_end
+}
+
+// RFC 8656 sec 11.5
+// ICMP errorはDATAの代わりにICMP attributeを入れたData indicationで送る。
+// 滅多に起きないので、data pathと違ってpacketごとに確保してよい
+fn build_icmp_data_indication(peer: SocketAddr, icmp: &Icmp) -> Vec<u8> {
+    let address_size = xor_address_size(peer);
+    let len = MESSAGE_HEADER_SIZE
+        + ATTRIBUTE_HEADER_SIZE
+        + address_size
+        + ATTRIBUTE_HEADER_SIZE
+        + ICMP_SIZE;
+    let mut packet = vec![0u8; len];
+
+    let mut transaction_id = [0u8; TRANSACTION_ID_SIZE];
+    rand::thread_rng().fill(&mut transaction_id);
+    packet[0..2].copy_from_slice(&DATA_INDICATION_TYPE.to_be_bytes());
+    set_message_length(&mut packet, len);
+    packet[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    packet[8..MESSAGE_HEADER_SIZE].copy_from_slice(&transaction_id);
+
+    let peer_attribute = MESSAGE_HEADER_SIZE;
+    packet[peer_attribute..peer_attribute + 2]
+        .copy_from_slice(&ATTR_XOR_PEER_ADDRESS_TYPE.to_be_bytes());
+    packet[peer_attribute + 2..peer_attribute + 4]
+        .copy_from_slice(&(address_size as u16).to_be_bytes());
+    encode_xor_address_into(
+        peer,
+        &transaction_id,
+        &mut packet[peer_attribute + ATTRIBUTE_HEADER_SIZE..],
+    );
+
+    let icmp_attribute = peer_attribute + ATTRIBUTE_HEADER_SIZE + address_size;
+    packet[icmp_attribute..icmp_attribute + 2].copy_from_slice(&ATTR_ICMP.0.to_be_bytes());
+    packet[icmp_attribute + 2..icmp_attribute + 4]
+        .copy_from_slice(&(ICMP_SIZE as u16).to_be_bytes());
+    packet[icmp_attribute + ATTRIBUTE_HEADER_SIZE..].copy_from_slice(&icmp.encode());
+    packet
+}
+
+// relay socketのerror queueに溜まったICMP errorを、permissionのあるpeerの分だけclientに伝える
+async fn relay_icmp_errors(allocation: &Allocation, metrics: &Metrics, errors: Vec<IcmpError>) {
+    for error in errors {
+        if !allocation.has_permission(error.peer).await {
+            metrics.record_invalid_packet("no_permission");
+            continue;
+        }
+        tracing::debug!(
+            icmp_type = error.icmp.icmp_type,
+            code = error.icmp.code,
+            peer = %error.peer,
+            "relaying ICMP to client"
+        );
+        let packet = build_icmp_data_indication(error.peer, &error.icmp);
+        let (client_addr, turn_socket) = allocation.client();
+        if let Err(err) = turn_socket.send_to(&packet, client_addr).await {
+            tracing::debug!(client = %client_addr, error = %err, "failed to relay ICMP to client");
+            continue;
+        }
+        metrics.record_icmp_relayed();
+    }
+}
+
+// peerから届いたpacketをclientに転送する。allocationが削除されてrelay_socketが閉じられると止まる。
+// bufはallocationごとに1つだけ確保し、packetごとには確保しない
+pub(crate) async fn relay_loop(allocation: Arc<Allocation>, metrics: Arc<Metrics>) {
+    let mut buf = vec![0u8; RELAY_HEADROOM + PEER_RECEIVE_SIZE + MAX_PADDING];
+    let payload = RELAY_HEADROOM..RELAY_HEADROOM + PEER_RECEIVE_SIZE;
+    loop {
+        let (n, peer) = match allocation
+            .relay_socket
+            .recv_from(&mut buf[payload.clone()])
+            .await
+        {
+            Ok(v) => v,
+            // IP_RECVERRが有効なsocketでは、peerからICMP errorが届くとrecv_fromがエラーになる
+            Err(err) if allocation.icmp_errors.is_some() && is_icmp_error(&err) => {
+                if let Some(icmp_errors) = &allocation.icmp_errors {
+                    relay_icmp_errors(&allocation, &metrics, icmp_errors.drain()).await;
+                }
+                continue;
+            }
+            Err(err) => {
+                tracing::debug!(
+                    relay = %allocation.relay_addr,
+                    error = %err,
+                    "exit relay loop on error"
+                );
+                break;
+            }
+        };
+        // 切り詰められたdatagramを転送すると壊れたdataがclientに届くので捨てる
+        if n > INBOUND_MTU {
+            tracing::debug!(
+                peer = %peer,
+                relay = %allocation.relay_addr,
+                max = INBOUND_MTU,
+                "discarding packet: datagram too large"
+            );
+            metrics.record_invalid_packet("peer_datagram_too_large");
+            continue;
+        }
+        // RFC 5766 sec 10.3
+        // permissionの無いpeerからのpacketは黙って捨てる
+        if !allocation.has_permission(peer).await {
+            tracing::debug!(
+                peer = %peer,
+                relay = %allocation.relay_addr,
+                "discarding packet: no permission"
+            );
+            metrics.record_invalid_packet("no_permission");
+            continue;
+        }
+        // channelがbindされていればChannelData、そうでなければData indicationで送る
+        let range = match allocation.get_channel_number(peer).await {
+            Some(channel_number) => frame_channel_data(&mut buf, channel_number, n),
+            None => frame_data_indication(&mut buf, peer, n),
+        };
+        // mobilityで5-tupleが変わっていることがあるので、packetごとに送り先を読む
+        let (client_addr, turn_socket) = allocation.client();
+        if let Err(err) = turn_socket.send_to(&buf[range], client_addr).await {
+            tracing::debug!(client = %client_addr, error = %err, "failed to relay to client");
+            continue;
+        }
+        allocation.add_bytes_received(n);
+        metrics.record_relayed(RelayDirection::ToClient, n);
+    }
+}
@@ -1,64 +1,113 @@
+use crate::acl::*;
+use crate::allocation::*;
+use crate::alternate_server::*;
+use crate::auth::AuthHandler;
+use crate::channel_number::ChannelNumber;
 use crate::error::*;
+use crate::fingerprint::*;
+use crate::five_tuple::FiveTuple;
+use crate::integrity::{MessageIntegrity, MESSAGE_HEADER_SIZE};
+use crate::lifetime::Lifetime;
+use crate::metrics::*;
+use crate::mobility::*;
+use crate::oauth::{AccessTokenAttribute, ThirdPartyAuthorization};
+use crate::observer::*;
+use crate::redact::{MessageSummary, TransactionId, Username};
+use crate::relay::*;
+use crate::requested_transport::*;
+use crate::transaction_cache::TransactionCache;
+use crate::unknown_attributes::UnknownAttributes;
 use crate::util::Conn;
+use crate::xor_address::*;
+use bytes::Bytes;
 use md5::{Digest, Md5};
 use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::SystemTime;
-use stun::attribute::{Nonce, Realm, ATTR_MESSAGE_INTEGRITY, ATTR_NONCE, ATTR_REALM};
+use std::time::{Duration, SystemTime};
+use stun::attribute::{
+    Nonce, Realm, ATTR_MESSAGE_INTEGRITY, ATTR_NONCE, ATTR_REALM, ATTR_USERNAME,
+};
 use stun::error_code::*;
-use stun::integrity::*;
 use stun::message::*;
 use tokio::sync::Mutex;
 use tokio::time::Instant;
+use tracing::Instrument;
 
-pub struct Request {
-    conn: Arc<dyn Conn + Send + Sync>,
-    packet: Vec<u8>,
+// RFC 5766 sec 6.2
+// LIFETIMEが指定されなかった場合は10分、最大でも1時間とする
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+pub const MAX_LIFETIME: Duration = Duration::from_secs(3600);
+// 発行したnonceの有効期限。これを過ぎたnonceを使ったrequestには438 Stale Nonceを返す
+const NONCE_LIFETIME: Duration = Duration::from_secs(3600);
+
+// サーバー全体で共有する状態。read_loopが1つ作り、全てのRequestはこれを借用する
+pub(crate) struct RequestState {
+    pub(crate) realm: String,
+    pub(crate) nonces: Mutex<HashMap<String, Instant>>,
+    pub(crate) auth_handler: Arc<dyn AuthHandler>,
+    pub(crate) peer_acl: Option<Arc<PeerAcl>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) allocation_manager: Arc<AllocationManager>,
+    pub(crate) observer: Option<Arc<dyn ServerObserver>>,
+    pub(crate) transaction_cache: Arc<TransactionCache>,
+    pub(crate) redirect_policy: Option<Arc<dyn RedirectPolicy>>,
+    // RFC 8016のmobilityを有効にする場合にticketを作る。Noneならticketを無視する
+    pub(crate) mobility: Option<Arc<MobilityTicketSealer>>,
+    pub(crate) default_lifetime: Duration,
+    pub(crate) max_lifetime: Duration,
+}
+
+// 受信した1つのdatagramの処理。connとstateは借用するだけなので、packetごとに確保するものは無い
+pub struct Request<'a> {
+    conn: &'a Arc<dyn Conn + Send + Sync>,
+    // read_loopの受信bufferの一部をそのまま参照している
+    packet: Bytes,
     src_address: SocketAddr,
     kind: RequestType,
-    pub realm: String,
-    nonces: Arc<Mutex<HashMap<String, Instant>>>,
+    state: &'a RequestState,
+    // authenticate_requestで認証できたkey。handlerがエラーで返すresponseにも使う
+    message_integrity: Option<MessageIntegrity>,
 }
 
-impl Request {
-    pub fn new(
-        conn: Arc<dyn Conn + Send + Sync>,
-        packet: Vec<u8>,
+impl<'a> Request<'a> {
+    pub(crate) fn new(
+        conn: &'a Arc<dyn Conn + Send + Sync>,
+        packet: Bytes,
         addr: SocketAddr,
+        state: &'a RequestState,
     ) -> Result<Self> {
-        let request = if Request::is_channel_data(packet.to_vec()) {
-            Request {
-                conn: conn,
-                packet: packet.to_vec(),
-                src_address: addr,
-                kind: CHANNEL_DATA,
-                realm: String::new(),
-                nonces: Arc::new(Mutex::new(HashMap::new())),
-            }
+        // STUNのheaderもChannelDataのheaderも4byteより短くはならない
+        if packet.len() < MIN_PACKET_SIZE {
+            return Err(Error::ErrPacketTooShort);
+        }
+        let kind = if Request::is_channel_data(&packet) {
+            CHANNEL_DATA
+        } else if packet[0] & 0xC0 == 0 {
+            // RFC 5389 sec 6: STUN messageは先頭2bitが0
+            STUN_PACKET
         } else {
-            Request {
-                conn: conn,
-                packet: packet.to_vec(),
-                src_address: addr,
-                kind: STUN_PACKET,
-                realm: String::new(),
-                nonces: Arc::new(Mutex::new(HashMap::new())),
-            }
+            return Err(Error::ErrRequestTypeUnknown);
         };
-        match request.kind {
-            STUN_PACKET => Ok(request),
-            CHANNEL_DATA => Ok(request),
-            _ => Err(Error::ErrRequestTypeUnknown),
-        }
+        Ok(Request {
+            conn,
+            packet,
+            src_address: addr,
+            kind,
+            state,
+            message_integrity: None,
+        })
     }
 
     // RFC 5766 sec 11.4
     // The ChannelData message is used to carry application data between the client and the server.
-    pub fn is_channel_data(packet: Vec<u8>) -> bool {
-        let num: u16 = u16::from_be_bytes([packet[0], packet[1]]);
-        let is_channel: bool = match num {
+    // ChannelDataは先頭2bitが01 (channel numberは0x4000-0x7FFF)
+    pub fn is_channel_data(packet: &[u8]) -> bool {
+        if packet.len() < MIN_PACKET_SIZE {
+            return false;
+        }
+        let is_channel: bool = match packet[0] >> 6 {
             0b01 => true,
             _ => false,
         };
@@ -66,28 +115,188 @@ impl Request {
     }
     pub async fn handle_request(&mut self) -> Result<()> {
         match self.kind {
-            STUN_PACKET => self.handle_turn_packet().await,
+            STUN_PACKET => {
+                // methodとtransaction_idはdecodeした後にrecordする
+                let span = tracing::info_span!(
+                    "transaction",
+                    client = %self.src_address,
+                    method = tracing::field::Empty,
+                    transaction_id = tracing::field::Empty
+                );
+                self.handle_turn_packet().instrument(span).await
+            }
             _ => self.handle_channel_data().await,
         }
     }
     pub async fn handle_turn_packet(&mut self) -> Result<()> {
-        println!("handling turn packet!");
-        let mut message =
-            Message::decode_from_packet(&self.packet).expect("Cant decode STUN Message");
-        println!("decode from turn packet into stun mesage => {:?}", message);
+        // FINGERPRINTが一致しないものはSTUNのmessageではないので、decodeする前に黙って捨てる
+        if !check_fingerprint(&self.packet) {
+            tracing::debug!(
+                src = %self.src_address,
+                "discarding message with bad FINGERPRINT"
+            );
+            self.state.metrics.record_invalid_packet("bad_fingerprint");
+            return Ok(());
+        }
+        // RFC 5389 sec 7.3.1, 7.3.2
+        // 理解できないcomprehension-requiredのattributeは全てのmethodで確かめる。
+        // requestには420を返し、indicationは黙って捨てる
+        let unknown_attributes = UnknownAttributes::find_in(&self.packet);
+        // Send indicationはdata pathなので、Messageにdecodeせずにそのままpeerに転送する
+        if self.packet.len() >= MESSAGE_HEADER_SIZE
+            && u16::from_be_bytes([self.packet[0], self.packet[1]]) == SEND_INDICATION_TYPE
+        {
+            if !unknown_attributes.is_empty() {
+                return self.discard_unknown_attributes(&unknown_attributes);
+            }
+            return self.handle_send_indication().await;
+        }
+        // RFC 5389 sec 7.3
+        // headerやattributeの形が壊れているmessageはtransaction_idも信用できないので、返信せずに捨てる
+        // control pathはMessageへのdecodeでどのみちallocateするので、ここではコピーしてよい
+        let mut message = match Message::decode_from_packet(&self.packet.to_vec()) {
+            Ok(message) => message,
+            Err(err) => {
+                tracing::warn!(
+                    src = %self.src_address,
+                    error = %err,
+                    "discarding malformed STUN message"
+                );
+                self.state.metrics.record_invalid_packet("malformed_stun");
+                return Ok(());
+            }
+        };
+        ignore_attributes_after_integrity(&mut message);
+        let span = tracing::Span::current();
+        span.record("method", &tracing::field::debug(&message.method));
+        span.record(
+            "transaction_id",
+            &tracing::field::display(TransactionId(&message.transaction_id.0)),
+        );
+        tracing::debug!(message = %MessageSummary(&message), "received STUN message");
+        self.state
+            .metrics
+            .record_request(message.method, message.class);
         if message.class == CLASS_INDICATION {
-            Ok(())
-        } else if message.class == CLASS_REQUEST {
+            if !unknown_attributes.is_empty() {
+                return self.discard_unknown_attributes(&unknown_attributes);
+            }
             match message.method {
+                // RFC 5389 sec 10.1.2
+                // Binding indicationはNATのbindingを維持するためのkeepaliveなので何もしない
+                METHOD_BINDING => {
+                    tracing::debug!(src = %self.src_address, "received binding indication");
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        } else if message.class == CLASS_REQUEST {
+            // RFC 5766 sec 6.2
+            // 再送されたrequestには、処理をやり直さずに前回と同じresponseを返す
+            if self.replay_cached_response(&message).await? {
+                return Ok(());
+            }
+            if !unknown_attributes.is_empty() {
+                return self
+                    .reject_unknown_attributes(&message, unknown_attributes)
+                    .await;
+            }
+            let result = match message.method {
+                METHOD_BINDING => self.handle_binding_request(&message).await,
                 METHOD_ALLOCATE => self.handle_allocate_request(&message).await,
+                METHOD_REFRESH => self.handle_refresh_request(&message).await,
+                METHOD_CREATE_PERMISSION => self.handle_create_permission_request(&message).await,
+                METHOD_CHANNEL_BIND => self.handle_channel_bind_request(&message).await,
                 _ => Ok(()),
+            };
+            // messageとしては読めたがattributeの中身がおかしいrequestには400を返す。
+            // 認証済みのrequestなら、そのkeyでMESSAGE-INTEGRITYを付ける
+            match result {
+                Err(err) if err.is_bad_request() => {
+                    let message_integrity = self.message_integrity.take();
+                    tracing::warn!(src = %self.src_address, error = %err, "bad request");
+                    self.state.metrics.record_invalid_packet("bad_request");
+                    self.respond_with_error(
+                        &message,
+                        message.method,
+                        CODE_BAD_REQUEST,
+                        b"Bad Request",
+                        message_integrity.as_ref(),
+                    )
+                    .await
+                }
+                result => result,
             }
         } else {
             Ok(())
         }
     }
+    // RFC 5766 sec 11.6
+    // bindされていないchannelや、permissionの無いpeer宛てのChannelDataは黙って捨てる
     pub async fn handle_channel_data(&mut self) -> Result<()> {
-        Ok(())
+        let (channel_number, data) = match parse_channel_data(&self.packet) {
+            Some(v) => v,
+            None => {
+                self.state
+                    .metrics
+                    .record_invalid_packet("malformed_channel_data");
+                return Ok(());
+            }
+        };
+        let allocation = match self.current_allocation().await? {
+            Some(allocation) => allocation,
+            None => return Ok(()),
+        };
+        let peer = match allocation.get_channel_peer(channel_number).await {
+            Some(peer) => peer,
+            None => {
+                tracing::debug!(
+                    src = %self.src_address,
+                    channel = format_args!("{:#06x}", channel_number),
+                    "discarding ChannelData: channel is not bound"
+                );
+                self.state.metrics.record_invalid_packet("unbound_channel");
+                return Ok(());
+            }
+        };
+        relay_to_peer(&allocation, &self.state.metrics, peer, data).await
+    }
+
+    // RFC 5766 sec 10.2
+    // Send indicationには返信しないので、不正なものは全て黙って捨てる
+    pub async fn handle_send_indication(&mut self) -> Result<()> {
+        self.state
+            .metrics
+            .record_request(METHOD_SEND, CLASS_INDICATION);
+        let (peer, data) = match parse_send_indication(&self.packet) {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::debug!(
+                    src = %self.src_address,
+                    error = %err,
+                    "discarding Send indication"
+                );
+                self.state.metrics.record_invalid_packet("malformed_send");
+                return Ok(());
+            }
+        };
+        let allocation = match self.current_allocation().await? {
+            Some(allocation) => allocation,
+            None => return Ok(()),
+        };
+        relay_to_peer(&allocation, &self.state.metrics, peer, data).await
+    }
+
+    // data pathで使う。allocationが無ければpacketを捨てる
+    async fn current_allocation(&self) -> Result<Option<Arc<Allocation>>> {
+        let allocation_manager = &self.state.allocation_manager;
+        let five_tuple = self.five_tuple().await?;
+        let allocation = allocation_manager.get_allocation(&five_tuple).await;
+        if allocation.is_none() {
+            tracing::debug!(five_tuple = %five_tuple, "discarding data: no allocation");
+            self.state.metrics.record_invalid_packet("no_allocation");
+        }
+        Ok(allocation)
     }
     pub async fn authenticate_request(
         &mut self,
@@ -96,73 +305,803 @@ impl Request {
     ) -> Result<Option<MessageIntegrity>> {
         // RFC5389 10.2.2
         if !message.contains(ATTR_MESSAGE_INTEGRITY) {
-            self.respond_with_nonce(message, method, CODE_UNAUTHORIZED)
+            self.respond_with_nonce(message, method, CODE_UNAUTHORIZED, b"Unauthorized")
+                .await?;
+            return Ok(None);
+        }
+
+        // USERNAME, REALM, NONCEが揃っていなければ400
+        let (username, realm, nonce) = match (
+            get_username(message).ok(),
+            get_realm(message),
+            get_nonce(message),
+        ) {
+            (Some(username), Some(realm), Some(nonce)) => (username, realm, nonce),
+            _ => {
+                self.respond_with_error(message, method, CODE_BAD_REQUEST, b"Bad Request", None)
+                    .await?;
+                return Ok(None);
+            }
+        };
+
+        // RFC 5389 sec 10.2.2
+        // このサーバーのREALM以外で認証させない。clientが送ったREALMはACLの評価にも使わないが、
+        // 別のREALMのkeyで認証できてしまわないように、ここで401と新しいnonceを返す
+        if realm != self.state.realm {
+            tracing::warn!(username = %Username(&username), "authentication with another realm");
+            self.record_auth_failure(&username).await;
+            self.respond_with_nonce(message, method, CODE_UNAUTHORIZED, b"Unauthorized")
                 .await?;
             return Ok(None);
         }
-        return Ok(None);
+
+        // 発行していない、または期限切れのnonceなら438で新しいnonceを渡す
+        if !self.is_nonce_valid(&nonce).await {
+            self.respond_with_nonce(message, method, CODE_STALE_NONCE, b"Stale Nonce")
+                .await?;
+            return Ok(None);
+        }
+
+        // RFC 7635 sec 6.2
+        // ACCESS-TOKENがあれば、USERNAMEをkidとしてtokenを復号し、その中のmac_keyで検証する
+        let auth_handler = &self.state.auth_handler;
+        let key = match AccessTokenAttribute::get_from(message) {
+            Some(access_token) => {
+                auth_handler.access_token_handle(&username, &access_token.0, self.src_address)
+            }
+            None => auth_handler.auth_handle(&username, &realm, self.src_address),
+        };
+        match key {
+            Ok(message_integrity) if message_integrity.check(&self.packet) => {
+                self.message_integrity = Some(message_integrity.clone());
+                Ok(Some(message_integrity))
+            }
+            _ => {
+                tracing::warn!(username = %Username(&username), "authentication failed");
+                self.record_auth_failure(&username).await;
+                self.respond_with_nonce(message, method, CODE_UNAUTHORIZED, b"Unauthorized")
+                    .await?;
+                Ok(None)
+            }
+        }
     }
 
-    pub async fn handle_allocate_request(&mut self, message: &Message) -> Result<()> {
-        println!("handling allocate message => {:?}", message);
+    async fn is_nonce_valid(&self, nonce: &str) -> bool {
+        let nonces = self.state.nonces.lock().await;
+        match nonces.get(nonce) {
+            Some(issued_at) => issued_at.elapsed() < NONCE_LIFETIME,
+            None => false,
+        }
+    }
+
+    async fn record_auth_failure(&self, username: &str) {
+        self.state.metrics.record_auth_failure();
+        if let Some(observer) = &self.state.observer {
+            if let Ok(five_tuple) = self.five_tuple().await {
+                observer.on_auth_failure(&AllocationEvent {
+                    username: username.to_string(),
+                    five_tuple,
+                    relayed_address: None,
+                    bytes_sent: 0,
+                    bytes_received: 0,
+                });
+            }
+        }
+    }
 
+    // RFC 5389 sec 10.1
+    // 普通のSTUNサーバーとして、認証なしでclientのserver reflexive addressを返す
+    pub async fn handle_binding_request(&mut self, message: &Message) -> Result<()> {
+        let mut response_message = Message::new(METHOD_BINDING, CLASS_SUCCESS_RESPONSE);
+        response_message.transaction_id = message.transaction_id;
+        response_message.set_extra_attribute(Box::new(XorMappedAddress {
+            address: self.src_address,
+        }))?;
+        self.send_response(&response_message, None).await
+    }
+
+    pub async fn handle_allocate_request(&mut self, message: &Message) -> Result<()> {
         // 1.message_integrityの取得
         let message_integrity =
             if let Some(mi) = self.authenticate_request(message, METHOD_ALLOCATE).await? {
                 mi
             } else {
-                println!("no MessageIntegrity");
+                tracing::debug!("request was not authenticated");
                 return Ok(());
             };
+        let allocation_manager = Arc::clone(&self.state.allocation_manager);
+
+        // 別のサーバーに向かわせる場合は300 Try Alternate。draining中なら508の代わりになる
+        if let Some(redirect_policy) = &self.state.redirect_policy {
+            let load = ServerLoad {
+                allocations: allocation_manager.allocation_count().await,
+                draining: allocation_manager.is_draining(),
+            };
+            let username = get_username(message)?;
+            if let Some(redirect) = redirect_policy.redirect(&username, self.src_address, &load) {
+                return self
+                    .respond_with_redirect(message, &message_integrity, redirect)
+                    .await;
+            }
+        }
+
+        // graceful shutdown中は新しいallocationを受け付けない
+        if allocation_manager.is_draining() {
+            return self
+                .respond_with_error(
+                    message,
+                    METHOD_ALLOCATE,
+                    CODE_INSUFFICIENT_CAPACITY,
+                    b"Insufficient Capacity",
+                    Some(&message_integrity),
+                )
+                .await;
+        }
+
+        // 2.同じ5-tupleのallocationが既にあれば437 Allocation Mismatch
+        let five_tuple = self.five_tuple().await?;
+        if allocation_manager
+            .get_allocation(&five_tuple)
+            .await
+            .is_some()
+        {
+            return self
+                .respond_with_error(
+                    message,
+                    METHOD_ALLOCATE,
+                    CODE_ALLOC_MISMATCH,
+                    b"Allocation Mismatch",
+                    Some(&message_integrity),
+                )
+                .await;
+        }
+
+        // 3.REQUESTED-TRANSPORTはUDPのみサポートする
+        match RequestedTransport::get_from(message)? {
+            Some(requested_transport) if requested_transport.protocol == PROTO_UDP => {}
+            Some(_) => {
+                return self
+                    .respond_with_error(
+                        message,
+                        METHOD_ALLOCATE,
+                        CODE_UNSUPPORTED_TRANS_PROTO,
+                        b"Unsupported Transport Protocol",
+                        Some(&message_integrity),
+                    )
+                    .await;
+            }
+            None => {
+                return self
+                    .respond_with_error(
+                        message,
+                        METHOD_ALLOCATE,
+                        CODE_BAD_REQUEST,
+                        b"Bad Request",
+                        Some(&message_integrity),
+                    )
+                    .await;
+            }
+        }
+
+        // 4.allocationを作ってrelayアドレスを返す
+        let lifetime = self.requested_lifetime(message)?;
+        let username = get_username(message)?;
+        let allocation = match allocation_manager
+            .create_allocation(five_tuple, username, lifetime, Arc::clone(self.conn))
+            .await
+        {
+            Ok(allocation) => allocation,
+            Err(Error::ErrAllocationQuotaReached) => {
+                return self
+                    .respond_with_error(
+                        message,
+                        METHOD_ALLOCATE,
+                        CODE_ALLOC_QUOTA_REACHED,
+                        b"Allocation Quota Reached",
+                        Some(&message_integrity),
+                    )
+                    .await;
+            }
+            Err(err) => {
+                tracing::error!(five_tuple = %five_tuple, error = %err, "failed to create allocation");
+                return self
+                    .respond_with_error(
+                        message,
+                        METHOD_ALLOCATE,
+                        CODE_INSUFFICIENT_CAPACITY,
+                        b"Insufficient Capacity",
+                        Some(&message_integrity),
+                    )
+                    .await;
+            }
+        };
+
+        let mut response_message = Message::new(METHOD_ALLOCATE, CLASS_SUCCESS_RESPONSE);
+        response_message.transaction_id = message.transaction_id;
+        response_message.set_extra_attribute(Box::new(XorRelayedAddress {
+            address: allocation.relay_addr,
+        }))?;
+        response_message.set_extra_attribute(Box::new(Lifetime(lifetime)))?;
+        response_message.set_extra_attribute(Box::new(XorMappedAddress {
+            address: self.src_address,
+        }))?;
+        // RFC 8016 sec 3.4
+        // 空のMOBILITY-TICKETでmobilityを要求されたら、最初のticketを返す
+        if let Some(mobility) = &self.state.mobility {
+            if MobilityTicket::get_from(message).is_some() {
+                allocation.enable_mobility();
+                let ticket = mobility.seal(allocation.id, &allocation.username, lifetime)?;
+                response_message.set_extra_attribute(Box::new(MobilityTicket(ticket)))?;
+            }
+        }
+        self.send_response(&response_message, Some(&message_integrity))
+            .await
+    }
+
+    // RFC 5766 sec 7.2
+    // LIFETIMEが0ならallocationを削除し、それ以外なら期限を延長する
+    pub async fn handle_refresh_request(&mut self, message: &Message) -> Result<()> {
+        let message_integrity =
+            if let Some(mi) = self.authenticate_request(message, METHOD_REFRESH).await? {
+                mi
+            } else {
+                tracing::debug!("request was not authenticated");
+                return Ok(());
+            };
+        let allocation_manager = Arc::clone(&self.state.allocation_manager);
+
+        let five_tuple = self.five_tuple().await?;
+        // LIFETIMEが0のRefreshだけは、デフォルト値に切り上げずにallocationの削除として扱う
+        let is_delete = matches!(
+            Lifetime::get_from(message)?,
+            Some(Lifetime(lifetime)) if lifetime.as_secs() == 0
+        );
+        let lifetime = if is_delete {
+            Duration::from_secs(0)
+        } else {
+            self.requested_lifetime(message)?
+        };
+
+        // RFC 8016 sec 3.5
+        // MOBILITY-TICKET付きのRefreshが別の5-tupleから来たら、ticketのallocationをこの5-tupleに移す
+        let ticket = MobilityTicket::get_from(message).filter(|ticket| !ticket.0.is_empty());
+        if let (Some(mobility), Some(ticket)) = (self.state.mobility.clone(), ticket) {
+            let username = get_username(message)?;
+            match self
+                .migrate_with_ticket(
+                    &allocation_manager,
+                    &mobility,
+                    &ticket.0,
+                    five_tuple,
+                    &username,
+                )
+                .await
+            {
+                Ok(()) => {}
+                Err(Error::ErrDupeFiveTuple) => {
+                    return self
+                        .respond_with_error(
+                            message,
+                            METHOD_REFRESH,
+                            CODE_ALLOC_MISMATCH,
+                            b"Allocation Mismatch",
+                            Some(&message_integrity),
+                        )
+                        .await;
+                }
+                Err(err) => {
+                    tracing::debug!(five_tuple = %five_tuple, error = %err, "refusing mobility");
+                    return self
+                        .respond_with_error(
+                            message,
+                            METHOD_REFRESH,
+                            CODE_MOBILITY_FORBIDDEN,
+                            b"Mobility Forbidden",
+                            Some(&message_integrity),
+                        )
+                        .await;
+                }
+            }
+        }
+
+        let refreshed = if is_delete {
+            allocation_manager
+                .delete_allocation(&five_tuple)
+                .await
+                .is_some()
+        } else {
+            allocation_manager
+                .refresh_allocation(&five_tuple, lifetime)
+                .await
+                .is_ok()
+        };
+        if !refreshed {
+            return self
+                .respond_with_error(
+                    message,
+                    METHOD_REFRESH,
+                    CODE_ALLOC_MISMATCH,
+                    b"Allocation Mismatch",
+                    Some(&message_integrity),
+                )
+                .await;
+        }
+
+        let mut response_message = Message::new(METHOD_REFRESH, CLASS_SUCCESS_RESPONSE);
+        response_message.transaction_id = message.transaction_id;
+        response_message.set_extra_attribute(Box::new(Lifetime(lifetime)))?;
+        // mobilityが有効なallocationには、Refreshのたびに新しいticketを返す
+        if let (Some(mobility), Some(allocation)) = (
+            &self.state.mobility,
+            allocation_manager.get_allocation(&five_tuple).await,
+        ) {
+            if allocation.is_mobility_enabled() {
+                let ticket = mobility.seal(allocation.id, &allocation.username, lifetime)?;
+                response_message.set_extra_attribute(Box::new(MobilityTicket(ticket)))?;
+            }
+        }
+        self.send_response(&response_message, Some(&message_integrity))
+            .await
+    }
+
+    // ticketを発行したallocationを、同じユーザーが別の5-tupleから使おうとしている時だけ移す
+    async fn migrate_with_ticket(
+        &self,
+        allocation_manager: &AllocationManager,
+        mobility: &MobilityTicketSealer,
+        ticket: &[u8],
+        five_tuple: FiveTuple,
+        username: &str,
+    ) -> Result<()> {
+        let contents = mobility.open(ticket)?;
+        let allocation = allocation_manager
+            .get_allocation_by_id(contents.allocation_id)
+            .await
+            .ok_or(Error::ErrNoAllocationFound)?;
+        if contents.username != username
+            || allocation.username != username
+            || !allocation.is_mobility_enabled()
+        {
+            return Err(Error::ErrInvalidMobilityTicket);
+        }
+        // 移動後に再送されたRefreshなど、既にこの5-tupleに移っていれば何もしない
+        if allocation.five_tuple() == five_tuple {
+            return Ok(());
+        }
+        allocation_manager
+            .migrate_allocation(&allocation, five_tuple, Arc::clone(self.conn))
+            .await
+    }
+
+    // RFC 5766 sec 9.2
+    // CreatePermissionはXOR-PEER-ADDRESSに含まれる全てのpeerにpermissionを作る。
+    // 1つでもACLで拒否されたpeerがあれば、どのpermissionも作らずに403を返す
+    pub async fn handle_create_permission_request(&mut self, message: &Message) -> Result<()> {
+        let message_integrity = if let Some(mi) = self
+            .authenticate_request(message, METHOD_CREATE_PERMISSION)
+            .await?
+        {
+            mi
+        } else {
+            tracing::debug!("request was not authenticated");
+            return Ok(());
+        };
+
+        let peer_addresses = XorPeerAddress::get_all_from(message)?;
+        let username = get_username(message)?;
+        for peer_address in &peer_addresses {
+            if !self.is_peer_allowed(&username, peer_address.address) {
+                tracing::info!(
+                    peer = %peer_address.address,
+                    username = %Username(&username),
+                    "peer is denied by ACL"
+                );
+                return self
+                    .respond_with_error(
+                        message,
+                        METHOD_CREATE_PERMISSION,
+                        CODE_FORBIDDEN,
+                        b"Forbidden",
+                        Some(&message_integrity),
+                    )
+                    .await;
+            }
+        }
+        let allocation_manager = Arc::clone(&self.state.allocation_manager);
+        let five_tuple = self.five_tuple().await?;
+        let allocation = match allocation_manager.get_allocation(&five_tuple).await {
+            Some(allocation) => allocation,
+            None => {
+                return self
+                    .respond_with_error(
+                        message,
+                        METHOD_CREATE_PERMISSION,
+                        CODE_ALLOC_MISMATCH,
+                        b"Allocation Mismatch",
+                        Some(&message_integrity),
+                    )
+                    .await;
+            }
+        };
+        for peer_address in &peer_addresses {
+            allocation_manager
+                .create_permission(&allocation, peer_address.address)
+                .await;
+        }
+
+        let mut response_message = Message::new(METHOD_CREATE_PERMISSION, CLASS_SUCCESS_RESPONSE);
+        response_message.transaction_id = message.transaction_id;
+        self.send_response(&response_message, Some(&message_integrity))
+            .await
+    }
+
+    // RFC 5766 sec 11.2
+    // channel numberとpeerを結びつけ、以降はChannelDataでrelayできるようにする
+    pub async fn handle_channel_bind_request(&mut self, message: &Message) -> Result<()> {
+        let message_integrity = if let Some(mi) = self
+            .authenticate_request(message, METHOD_CHANNEL_BIND)
+            .await?
+        {
+            mi
+        } else {
+            tracing::debug!("request was not authenticated");
+            return Ok(());
+        };
+
+        let channel_number = match ChannelNumber::get_from(message)? {
+            Some(channel_number) if channel_number.is_valid() => channel_number.0,
+            _ => return Err(Error::ErrInvalidChannelNumber),
+        };
+        let peer = match XorPeerAddress::get_all_from(message)?.first() {
+            Some(peer_address) => peer_address.address,
+            None => return Err(Error::ErrNoXorPeerAddress),
+        };
+        let username = get_username(message)?;
+        if !self.is_peer_allowed(&username, peer) {
+            tracing::info!(peer = %peer, username = %Username(&username), "peer is denied by ACL");
+            return self
+                .respond_with_error(
+                    message,
+                    METHOD_CHANNEL_BIND,
+                    CODE_FORBIDDEN,
+                    b"Forbidden",
+                    Some(&message_integrity),
+                )
+                .await;
+        }
+        let allocation_manager = Arc::clone(&self.state.allocation_manager);
+        let five_tuple = self.five_tuple().await?;
+        let allocation = match allocation_manager.get_allocation(&five_tuple).await {
+            Some(allocation) => allocation,
+            None => {
+                return self
+                    .respond_with_error(
+                        message,
+                        METHOD_CHANNEL_BIND,
+                        CODE_ALLOC_MISMATCH,
+                        b"Allocation Mismatch",
+                        Some(&message_integrity),
+                    )
+                    .await;
+            }
+        };
+        allocation_manager
+            .bind_channel(&allocation, channel_number, peer)
+            .await?;
+
+        let mut response_message = Message::new(METHOD_CHANNEL_BIND, CLASS_SUCCESS_RESPONSE);
+        response_message.transaction_id = message.transaction_id;
+        self.send_response(&response_message, Some(&message_integrity))
+            .await
+    }
+
+    async fn five_tuple(&self) -> Result<FiveTuple> {
+        Ok(FiveTuple {
+            protocol: PROTO_UDP,
+            src_addr: self.src_address,
+            dst_addr: self.conn.local_addr().await?,
+        })
+    }
+
+    // 認証済みのrequestへのresponseにはMESSAGE-INTEGRITYを付ける
+    async fn send_response(
+        &mut self,
+        response_message: &Message,
+        message_integrity: Option<&MessageIntegrity>,
+    ) -> Result<()> {
+        let mut response_message_packet = response_message.encode_to_packet();
+        if let Some(message_integrity) = message_integrity {
+            message_integrity.append_to(&mut response_message_packet);
+        }
+        // requestにFINGERPRINTが付いていれば、responseにも付ける
+        if has_fingerprint(&self.packet) {
+            append_fingerprint(&mut response_message_packet);
+        }
+        self.conn
+            .send_to(&response_message_packet, self.src_address)
+            .await?;
+        // 処理をやり直すと結果が変わる、認証済みのrequestへのresponseだけを覚える。
+        // Bindingや未認証の401はやり直しても同じなので、偽の送信元で埋められないように覚えない
+        let is_stateful = matches!(
+            response_message.method,
+            METHOD_ALLOCATE | METHOD_REFRESH | METHOD_CREATE_PERMISSION | METHOD_CHANNEL_BIND
+        );
+        if message_integrity.is_some() && is_stateful {
+            self.state
+                .transaction_cache
+                .insert(
+                    self.five_tuple().await?,
+                    response_message.transaction_id.0,
+                    response_message_packet,
+                )
+                .await;
+        }
         Ok(())
     }
 
+    // cacheにresponseがあれば送り直してtrueを返す
+    async fn replay_cached_response(&self, message: &Message) -> Result<bool> {
+        let five_tuple = self.five_tuple().await?;
+        let response = match self
+            .state
+            .transaction_cache
+            .get(five_tuple, message.transaction_id.0)
+            .await
+        {
+            Some(response) => response,
+            None => return Ok(false),
+        };
+        tracing::debug!(
+            five_tuple = %five_tuple,
+            "replaying cached response to retransmitted request"
+        );
+        self.state.metrics.record_retransmission();
+        self.conn.send_to(&response, self.src_address).await?;
+        Ok(true)
+    }
+
+    // RFC 5766 sec 6.2
+    // LIFETIMEが無ければデフォルト値を使う。あれば上限で切り詰め、デフォルト値より短ければデフォルト値にする
+    fn requested_lifetime(&self, message: &Message) -> Result<Duration> {
+        match Lifetime::get_from(message)? {
+            Some(Lifetime(lifetime)) => Ok(lifetime
+                .min(self.state.max_lifetime)
+                .max(self.state.default_lifetime)),
+            None => Ok(self.state.default_lifetime),
+        }
+    }
+
+    // peer_aclが設定されていなければ全てのpeerを許可する。
+    // realmはclientが送ったREALMではなく、認証に使ったこのサーバーのrealmで評価する
+    fn is_peer_allowed(&self, username: &str, peer: SocketAddr) -> bool {
+        match &self.state.peer_acl {
+            Some(acl) => acl.check(username, &self.state.realm, peer) == AclAction::Allow,
+            None => true,
+        }
+    }
+
+    async fn respond_with_error(
+        &mut self,
+        message: &Message,
+        method: Method,
+        response_code: ErrorCode,
+        reason: &[u8],
+        message_integrity: Option<&MessageIntegrity>,
+    ) -> Result<()> {
+        self.state.metrics.record_error_response(response_code.0);
+        let mut response_message = Message::new(method, CLASS_ERROR);
+        response_message.transaction_id = message.transaction_id;
+        response_message.set_extra_attribute(Box::new(ErrorCodeAttribute {
+            code: response_code,
+            reason: reason.to_vec(),
+        }))?;
+        self.send_response(&response_message, message_integrity)
+            .await
+    }
+
+    // RFC 5389 sec 11
+    // 認証済みのAllocateにだけ返すので、clientが偽の300でないことを確かめられるようにMESSAGE-INTEGRITYを付ける
+    async fn respond_with_redirect(
+        &mut self,
+        message: &Message,
+        message_integrity: &MessageIntegrity,
+        redirect: Redirect,
+    ) -> Result<()> {
+        tracing::info!(
+            src = %self.src_address,
+            to = %redirect.address,
+            "redirecting allocate"
+        );
+        self.state
+            .metrics
+            .record_error_response(CODE_TRY_ALTERNATE.0);
+        let mut response_message = Message::new(METHOD_ALLOCATE, CLASS_ERROR);
+        response_message.transaction_id = message.transaction_id;
+        response_message.set_extra_attribute(Box::new(ErrorCodeAttribute {
+            code: CODE_TRY_ALTERNATE,
+            reason: b"Try Alternate".to_vec(),
+        }))?;
+        response_message.set_extra_attribute(Box::new(AlternateServer {
+            address: redirect.address,
+        }))?;
+        if let Some(domain) = redirect.domain {
+            response_message.set_extra_attribute(Box::new(AlternateDomain(domain)))?;
+        }
+        self.send_response(&response_message, Some(message_integrity))
+            .await
+    }
+
+    // 認証するmethodでは、認証できたclientにだけ署名付きの420でどのattributeを理解できないかを教える。
+    // 認証できなければauthenticate_requestが401などを返している
+    async fn reject_unknown_attributes(
+        &mut self,
+        message: &Message,
+        unknown_attributes: UnknownAttributes,
+    ) -> Result<()> {
+        let message_integrity = match message.method {
+            METHOD_ALLOCATE | METHOD_REFRESH | METHOD_CREATE_PERMISSION | METHOD_CHANNEL_BIND => {
+                match self.authenticate_request(message, message.method).await? {
+                    Some(message_integrity) => Some(message_integrity),
+                    None => return Ok(()),
+                }
+            }
+            _ => None,
+        };
+        self.respond_with_unknown_attributes(
+            message,
+            unknown_attributes,
+            message_integrity.as_ref(),
+        )
+        .await
+    }
+
+    fn discard_unknown_attributes(&self, unknown_attributes: &UnknownAttributes) -> Result<()> {
+        tracing::debug!(
+            src = %self.src_address,
+            attributes = ?unknown_attributes.0,
+            "discarding indication with unknown comprehension-required attributes"
+        );
+        self.state
+            .metrics
+            .record_invalid_packet("unknown_attribute");
+        Ok(())
+    }
+
+    async fn respond_with_unknown_attributes(
+        &mut self,
+        message: &Message,
+        unknown_attributes: UnknownAttributes,
+        message_integrity: Option<&MessageIntegrity>,
+    ) -> Result<()> {
+        tracing::debug!(
+            attributes = ?unknown_attributes.0,
+            "unknown comprehension-required attributes"
+        );
+        self.state
+            .metrics
+            .record_error_response(CODE_UNKNOWN_ATTRIBUTE.0);
+        let mut response_message = Message::new(message.method, CLASS_ERROR);
+        response_message.transaction_id = message.transaction_id;
+        response_message.set_extra_attribute(Box::new(ErrorCodeAttribute {
+            code: CODE_UNKNOWN_ATTRIBUTE,
+            reason: b"Unknown Attribute".to_vec(),
+        }))?;
+        response_message.set_extra_attribute(Box::new(unknown_attributes))?;
+        self.send_response(&response_message, message_integrity)
+            .await
+    }
+
     async fn respond_with_nonce(
         &mut self,
         message: &Message,
         method: Method,
         response_code: ErrorCode,
+        reason: &[u8],
     ) -> Result<()> {
         let nonce = build_nonce()?;
 
         {
             // Nonce has already been taken
-            let mut nonces = self.nonces.lock().await;
+            let mut nonces = self.state.nonces.lock().await;
+            nonces.retain(|_, issued_at| issued_at.elapsed() < NONCE_LIFETIME);
             if nonces.contains_key(&nonce) {
                 return Err(Error::ErrDuplicatedNonce);
             }
             nonces.insert(nonce.clone(), Instant::now());
         }
+        self.state.metrics.record_error_response(response_code.0);
         // STUNメッセージの構築
         // transaction_idは同じものを使うので、取り出す
         let transaction_id = message.transaction_id;
         // 返信する時のSTUN Message Method と Class
-        let mut response_message = Message::new(METHOD_ALLOCATE, CLASS_ERROR);
+        let mut response_message = Message::new(method, CLASS_ERROR);
         response_message.transaction_id = transaction_id;
         // ErrorCodeを入れる
         response_message.set_extra_attribute(Box::new(ErrorCodeAttribute {
-            code: CODE_UNAUTHORIZED,
-            reason: b"Unauthorized".to_vec(),
+            code: response_code,
+            reason: reason.to_vec(),
         }))?;
-        println!(
-            "adding ErrorCode to response_mesasge: {:?}",
-            response_message
-        );
         // nonce, realmを入れる
         response_message.set_extra_attribute(Box::new(Nonce::new(ATTR_NONCE, nonce)))?;
-        println!("adding nonce to response_mesasge: {:?}", response_message);
         response_message
-            .set_extra_attribute(Box::new(Realm::new(ATTR_REALM, self.realm.clone())))?;
-        println!("adding realm to response_mesasge: {:?}", response_message);
-        let response_message_packet = response_message.encode_to_packet();
+            .set_extra_attribute(Box::new(Realm::new(ATTR_REALM, self.state.realm.clone())))?;
+        // RFC 7635 sec 6.1
+        // OAuthを使う場合は、401でclientにどの認可サーバーからACCESS-TOKENを取るかを伝える
+        if response_code == CODE_UNAUTHORIZED {
+            if let Some(server_name) = self.state.auth_handler.third_party_authorization() {
+                response_message
+                    .set_extra_attribute(Box::new(ThirdPartyAuthorization(server_name)))?;
+            }
+        }
         // メッセージの送信
-        self.conn
-            .send_to(&response_message_packet, self.src_address)
-            .await?;
+        self.send_response(&response_message, None).await?;
         return Ok(());
     }
 }
 
+// RFC 5766 sec 10.2, 11.6
+// permissionのあるpeerにだけ、受信したpacketのpayloadをコピーせずにそのまま送る
+async fn relay_to_peer(
+    allocation: &Allocation,
+    metrics: &Metrics,
+    peer: SocketAddr,
+    data: &[u8],
+) -> Result<()> {
+    if !allocation.has_permission(peer).await {
+        tracing::debug!(peer = %peer, "discarding data: no permission");
+        metrics.record_invalid_packet("no_permission");
+        return Ok(());
+    }
+    allocation.relay_socket.send_to(data, peer).await?;
+    allocation.add_bytes_sent(data.len());
+    metrics.record_relayed(RelayDirection::ToPeer, data.len());
+    Ok(())
+}
+
+// RFC 5389 sec 15.4
+// MESSAGE-INTEGRITYより後のattributeはMACで守られていないので、FINGERPRINT以外は捨てる。
+// 経路上で付け足されたXOR-PEER-ADDRESSやLIFETIMEを、handlerが認証済みの値として使わないようにする
+fn ignore_attributes_after_integrity(message: &mut Message) {
+    let integrity = match message
+        .attributes
+        .iter()
+        .position(|attribute| attribute.typ == ATTR_MESSAGE_INTEGRITY)
+    {
+        Some(integrity) => integrity,
+        None => return,
+    };
+    let mut index = 0;
+    message.attributes.retain(|attribute| {
+        let keep = index <= integrity || attribute.typ.0 == ATTR_FINGERPRINT_TYPE;
+        index += 1;
+        keep
+    });
+}
+
+// USERNAME attributeを文字列として取り出す
+pub(crate) fn get_username(message: &Message) -> Result<String> {
+    match message.attributes.iter().find(|e| e.typ == ATTR_USERNAME) {
+        Some(attribute) => Ok(String::from_utf8_lossy(&attribute.value).to_string()),
+        None => Err(Error::ErrNoUsernameAttribute),
+    }
+}
+
+pub(crate) fn get_nonce(message: &Message) -> Option<String> {
+    message
+        .attributes
+        .iter()
+        .find(|e| e.typ == ATTR_NONCE)
+        .map(|attribute| String::from_utf8_lossy(&attribute.value).to_string())
+}
+
+pub(crate) fn get_realm(message: &Message) -> Option<String> {
+    message
+        .attributes
+        .iter()
+        .find(|e| e.typ == ATTR_REALM)
+        .map(|attribute| String::from_utf8_lossy(&attribute.value).to_string())
+}
+
 // nonceとは、RFC2617で定義されたHTTPダイジェスト認証の際に、最初にサーバー側から送るランダム文字列である。
 // ランダム文字列にユーザー名とパスワードをくっつけて、MD5でハッシュ化して送信する。
 // Basic認証はユーザー名とパスワードを平文で送るが、ダイジェスト認証はハッシュ化して送るため、ユーザー名とパスワードを復号するのが困難。
@@ -190,3 +1129,36 @@ pub struct RequestType(u8);
 pub const STUN_PACKET: RequestType = RequestType(0x00);
 pub const CHANNEL_DATA: RequestType = RequestType(0x01);
 pub const UNKNOWN_PACKET: RequestType = RequestType(0x77);
+
+const MIN_PACKET_SIZE: usize = 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stun::attribute::{AttrType, Attribute};
+
+    #[test]
+    fn test_attributes_after_integrity_are_ignored() {
+        let mut message = Message::new(METHOD_CREATE_PERMISSION, CLASS_REQUEST);
+        for typ in [
+            ATTR_USERNAME,
+            ATTR_MESSAGE_INTEGRITY,
+            AttrType(0x0012), // XOR-PEER-ADDRESS
+            AttrType(0x000D), // LIFETIME
+            AttrType(ATTR_FINGERPRINT_TYPE),
+        ] {
+            message.attributes.push(Attribute::new(typ, 4, vec![0; 4]));
+        }
+        ignore_attributes_after_integrity(&mut message);
+        let types: Vec<u16> = message.attributes.iter().map(|a| a.typ.0).collect();
+        assert_eq!(types, vec![0x0006, 0x0008, ATTR_FINGERPRINT_TYPE]);
+
+        // MESSAGE-INTEGRITYが無ければ何も捨てない
+        let mut message = Message::new(METHOD_BINDING, CLASS_REQUEST);
+        message
+            .attributes
+            .push(Attribute::new(AttrType(0x000D), 4, vec![0; 4]));
+        ignore_attributes_after_integrity(&mut message);
+        assert_eq!(message.attributes.len(), 1);
+    }
+}
@@ -8,6 +8,25 @@ pub struct RequestedTransport {
 
 const REQUESTED_TRANSPORT_SIZE: usize = 4;
 
+impl RequestedTransport {
+    pub fn get_from(m: &Message) -> crate::error::Result<Option<RequestedTransport>> {
+        let attribute = match m
+            .attributes
+            .iter()
+            .find(|e| e.typ == ATTR_REQUESTED_TRANSPORT)
+        {
+            Some(attribute) => attribute,
+            None => return Ok(None),
+        };
+        if attribute.value.len() != REQUESTED_TRANSPORT_SIZE {
+            return Err(crate::error::Error::ErrBadAttributeLength);
+        }
+        Ok(Some(RequestedTransport {
+            protocol: Protocol(attribute.value[0]),
+        }))
+    }
+}
+
 impl Setter for RequestedTransport {
     fn set_extra_attribute(&self, m: &mut Message) -> Result<()> {
         let (mut raw, length) = (
@@ -27,6 +46,7 @@ impl Setter for RequestedTransport {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Protocol(pub u8);
 pub const PROTO_TCP: Protocol = Protocol(6);
 pub const PROTO_UDP: Protocol = Protocol(17);
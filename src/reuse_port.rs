@@ -0,0 +1,68 @@
+use crate::batch_conn::BatchUdpConn;
+use crate::util::{Conn, Result, UdpConn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+// SO_REUSEPORTで同じアドレスにsocketsの数だけsocketを開く。
+// カーネルが送信元の4-tupleで振り分けるので、同じclientのpacketは常に同じsocketに届く
+#[derive(Debug, Clone, Copy)]
+pub struct ReusePortListener {
+    pub address: SocketAddr,
+    pub sockets: usize,
+    // trueならBatchUdpConnで受信する
+    pub batch_io: bool,
+}
+
+impl ReusePortListener {
+    pub fn new(address: SocketAddr, sockets: usize) -> Self {
+        ReusePortListener {
+            address,
+            sockets,
+            batch_io: false,
+        }
+    }
+
+    // tokioのruntimeの中で呼ぶこと
+    pub fn bind(&self) -> Result<Vec<Arc<dyn Conn + Send + Sync>>> {
+        let mut conns: Vec<Arc<dyn Conn + Send + Sync>> = Vec::with_capacity(self.sockets);
+        // port 0の場合は最初のsocketでportを決め、残りは同じportにbindする
+        let mut address = self.address;
+        for _ in 0..self.sockets {
+            let socket = bind_reuse_port(address)?;
+            address = socket.local_addr()?;
+            if self.batch_io {
+                conns.push(Arc::new(BatchUdpConn::new(socket)));
+            } else {
+                conns.push(Arc::new(UdpConn::new(socket)));
+            }
+        }
+        tracing::info!(
+            address = %address,
+            sockets = conns.len(),
+            "listening with SO_REUSEPORT sockets"
+        );
+        Ok(conns)
+    }
+}
+
+#[cfg(unix)]
+pub fn bind_reuse_port(address: SocketAddr) -> Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(not(unix))]
+pub fn bind_reuse_port(_address: SocketAddr) -> Result<UdpSocket> {
+    Err(crate::util::Error::Other(
+        "SO_REUSEPORT is not supported on this platform".to_string(),
+    ))
+}
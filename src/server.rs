@@ -1,40 +1,177 @@
 use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::{watch, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-use crate::request::Request;
+use crate::acl::{PeerAcl, PortRange};
+use crate::admin::serve_admin;
+use crate::allocation::*;
+use crate::alternate_server::RedirectPolicy;
+use crate::auth::AuthHandler;
+use crate::error::Error;
+use crate::metrics::*;
+use crate::mobility::MobilityTicketSealer;
+use crate::observer::ServerObserver;
+use crate::pcap::{PcapTap, TapConn};
+use crate::request::{Request, RequestState, DEFAULT_LIFETIME, MAX_LIFETIME};
+use crate::reuse_port::ReusePortListener;
+use crate::transaction_cache::TransactionCache;
 use crate::util::*;
+use crate::vnet::Net;
 pub const INBOUND_MTU: usize = 1500;
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// workerごとに溜めておけるpacketの数。溢れたpacketは捨てる(UDPなのでclientが再送する)
+const REQUEST_QUEUE_SIZE: usize = 1024;
+// read_loopが受信に使うbufferの大きさ。ここから切り出したBytesを各workerに渡し、
+// 全て使い切るかworkerが手放すまで次の領域を確保しない
+const RECEIVE_POOL_SIZE: usize = INBOUND_MTU * 64;
+// available_parallelismが取れなかった時のworker数
+const DEFAULT_REQUEST_WORKERS: usize = 4;
 
 pub struct Server {
     shutdown_tx: Mutex<Option<watch::Sender<bool>>>,
     realm: String,
+    metrics: Arc<Metrics>,
+    allocation_manager: Arc<AllocationManager>,
+    // closeで閉じるlistenerのsocket。閉じたら空にして、Serverが残っていてもsocketを手放す
+    conns: Mutex<Vec<Arc<dyn Conn + Send + Sync>>>,
+    // spawnしたread_loop、worker、expiry loop、管理用とメトリクスのHTTPサーバー。closeで終わるのを待つ
+    tasks: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl Server {
     pub async fn new(config: ServerConfig) -> Result<Self> {
         config.validate()?;
         let (shutdown_tx, shutdown_rx) = watch::channel(false);
-        let s = Server {
-            shutdown_tx: Mutex::new(Some(shutdown_tx)),
-            realm: String::from("ucchy-webrtc-realm"),
-        };
-        tokio::spawn(async move {
-            let _ = Server::read_loop(config.conn_config, shutdown_rx).await;
+        let metrics = Arc::new(Metrics::new());
+        let allocation_manager = Arc::new(AllocationManager::new(
+            RelayConfig {
+                relay_ip: config.relay_ip,
+                external_ip: config.external_ip,
+                port_range: config.relay_port_range,
+                vnet: config.vnet.clone(),
+                pcap: config.pcap.clone(),
+            },
+            AllocationQuota {
+                max_allocations: config.max_allocations,
+                max_allocations_per_user: config.max_allocations_per_user,
+            },
+            Arc::clone(&metrics),
+            config.observer.clone(),
+        ));
+        let mut tasks = vec![];
+        tasks.push(tokio::spawn(
+            Arc::clone(&allocation_manager).run_expiry_loop(shutdown_rx.clone()),
+        ));
+        let transaction_cache = Arc::new(TransactionCache::new());
+        tasks.push(tokio::spawn(
+            Arc::clone(&transaction_cache).run_expiry_loop(shutdown_rx.clone()),
+        ));
+        if let Some(admin_address) = config.admin_address {
+            let allocation_manager = Arc::clone(&allocation_manager);
+            let shutdown_rx = shutdown_rx.clone();
+            tasks.push(tokio::spawn(async move {
+                if let Err(err) = serve_admin(allocation_manager, admin_address, shutdown_rx).await
+                {
+                    tracing::error!(error = %err, "admin endpoint stopped");
+                }
+            }));
+        }
+        if let Some(metrics_address) = config.metrics_address {
+            let metrics = Arc::clone(&metrics);
+            let shutdown_rx = shutdown_rx.clone();
+            tasks.push(tokio::spawn(async move {
+                if let Err(err) = serve_metrics(metrics, metrics_address, shutdown_rx).await {
+                    tracing::error!(error = %err, "metrics endpoint stopped");
+                }
+            }));
+        }
+        let state = Arc::new(RequestState {
+            realm: config.realm.clone(),
+            nonces: Mutex::new(HashMap::new()),
+            auth_handler: config.auth_handler,
+            peer_acl: config.peer_acl,
+            metrics: Arc::clone(&metrics),
+            allocation_manager: Arc::clone(&allocation_manager),
+            observer: config.observer,
+            transaction_cache,
+            redirect_policy: config.redirect_policy,
+            mobility: if config.mobility {
+                Some(Arc::new(MobilityTicketSealer::new()))
+            } else {
+                None
+            },
+            default_lifetime: config.default_lifetime,
+            max_lifetime: config.max_lifetime,
         });
+        // configは一部moveしているので、closureにはpcapだけを借用させる
+        let pcap = config.pcap.clone();
+        let tap = |conn: Arc<dyn Conn + Send + Sync>| -> Arc<dyn Conn + Send + Sync> {
+            match &pcap {
+                Some(pcap) => Arc::new(TapConn::client_side(conn, Arc::clone(pcap))),
+                None => conn,
+            }
+        };
+        let mut conns: Vec<_> = config.conn_configs.iter().cloned().map(tap).collect();
+        // SO_REUSEPORTのsocketも1つのlistenerとして扱う。
+        // どのsocketに届いてもallocationなどの状態は共有しているので同じように処理できる
+        for listener in &config.reuse_port_listeners {
+            conns.extend(listener.bind()?.into_iter().map(tap));
+        }
+        // listenerごとにread_loopとworkerを動かす。allocationなどの状態は全てのlistenerで共有する
+        for conn in &conns {
+            let mut worker_txs = Vec::with_capacity(config.request_workers);
+            for _ in 0..config.request_workers {
+                let (tx, rx) = mpsc::channel(REQUEST_QUEUE_SIZE);
+                tasks.push(tokio::spawn(Server::request_worker(
+                    Arc::clone(conn),
+                    Arc::clone(&state),
+                    rx,
+                )));
+                worker_txs.push(tx);
+            }
+            tasks.push(tokio::spawn(Server::read_loop(
+                Arc::clone(conn),
+                Arc::clone(&state),
+                worker_txs,
+                shutdown_rx.clone(),
+            )));
+        }
 
-        Ok(s)
+        Ok(Server {
+            shutdown_tx: Mutex::new(Some(shutdown_tx)),
+            realm: config.realm,
+            metrics,
+            allocation_manager,
+            conns: Mutex::new(conns),
+            tasks: Mutex::new(tasks),
+        })
     }
 
-    async fn read_loop(conn: Arc<dyn Conn + Send + Sync>, mut shutdown_rx: watch::Receiver<bool>) {
-        let mut buf = vec![0u8; INBOUND_MTU];
+    // read_loopは受信したpacketをworkerに振り分けるだけで、requestの処理は待たない。
+    // 同じclientのpacketは常に同じworkerに渡すので、clientごとの処理順は保たれる
+    async fn read_loop(
+        conn: Arc<dyn Conn + Send + Sync>,
+        state: Arc<RequestState>,
+        worker_txs: Vec<mpsc::Sender<(Bytes, SocketAddr)>>,
+        mut shutdown_rx: watch::Receiver<bool>,
+    ) {
+        let mut pool = BytesMut::with_capacity(RECEIVE_POOL_SIZE);
         loop {
+            prepare_receive_buffer(&mut pool);
             let (n, addr) = tokio::select! {
-                v = conn.recv_from(&mut buf) => {
+                v = conn.recv_from(&mut pool) => {
                     match v {
                         Ok(v) => v,
                         Err(err) => {
-                            log::debug!("exit read loop on error: {}", err);
+                            tracing::debug!(error = %err, "exit read loop on error");
                             break;
                         }
                     }
@@ -49,34 +186,509 @@ impl Server {
                     }
                 }
             };
-            println!("{:?}", &buf[..n]);
-            let mut request = Request::new(Arc::clone(&conn), buf[..n].to_vec(), addr)
-                .expect("Cant decode packet");
-            if let Err(err) = request.handle_request().await {
-                log::error!("error when handling datagram: {}", err);
+            let packet = pool.split_to(n).freeze();
+            pool.clear();
+            let worker = worker_index(addr, worker_txs.len());
+            if worker_txs[worker].try_send((packet, addr)).is_err() {
+                tracing::warn!(src = %addr, "dropping packet: request queue is full");
+                state.metrics.record_invalid_packet("queue_full");
+            }
+        }
+    }
+
+    // read_loopが終わってsenderが全てdropされると、queueに残っているpacketを処理してから終わる
+    async fn request_worker(
+        conn: Arc<dyn Conn + Send + Sync>,
+        state: Arc<RequestState>,
+        mut rx: mpsc::Receiver<(Bytes, SocketAddr)>,
+    ) {
+        while let Some((packet, addr)) = rx.recv().await {
+            Server::handle_packet(&conn, &state, packet, addr).await;
+        }
+    }
+
+    async fn handle_packet(
+        conn: &Arc<dyn Conn + Send + Sync>,
+        state: &RequestState,
+        packet: Bytes,
+        addr: SocketAddr,
+    ) {
+        let mut request = match Request::new(conn, packet, addr, state) {
+            Ok(request) => request,
+            Err(err) => {
+                // STUNでもChannelDataでもないpacketは黙って捨てる
+                tracing::debug!(src = %addr, error = %err, "discarding packet");
+                state.metrics.record_invalid_packet("unknown_packet");
+                return;
+            }
+        };
+        if let Err(err) = request.handle_request().await {
+            tracing::error!(src = %addr, error = %err, "error when handling datagram");
+            state.metrics.record_handler_error();
+        }
+    }
+
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    // 現在の全てのallocationの持ち主、アドレス、残り時間、通信量を返す
+    pub async fn list_allocations(&self) -> Vec<AllocationInfo> {
+        self.allocation_manager.list_allocations().await
+    }
+
+    pub async fn delete_allocation_by_id(&self, id: u64) -> bool {
+        self.allocation_manager.delete_allocation_by_id(id).await
+    }
+
+    // アカウントが失効したユーザーのセッションを即座に切るために使う。削除した数を返す
+    pub async fn delete_allocations_by_username(&self, username: &str) -> usize {
+        self.allocation_manager
+            .delete_allocations_by_username(username)
+            .await
+    }
+
+    // 新しいAllocateを508で断りつつ、既存のallocationが自然に消えるのをdeadlineまで待ってからcloseする
+    pub async fn close_gracefully(&self, deadline: Duration) -> Result<()> {
+        self.allocation_manager.start_draining();
+        let deadline = Instant::now() + deadline;
+        while Instant::now() < deadline {
+            let remaining = self.allocation_manager.allocation_count().await;
+            if remaining == 0 {
+                break;
             }
+            tracing::debug!(remaining, "draining allocations");
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
         }
+        self.shutdown(Some(deadline)).await
     }
 
     pub async fn close(&self) -> Result<()> {
-        let mut shutdown_tx = self.shutdown_tx.lock().await;
-        if let Some(tx) = shutdown_tx.take() {
+        self.shutdown(None).await
+    }
+
+    // spawnした全てのtaskが終わるのを待つ。deadlineを過ぎても終わらないtaskはabortする
+    async fn shutdown(&self, deadline: Option<Instant>) -> Result<()> {
+        self.allocation_manager.start_draining();
+        if let Some(tx) = self.shutdown_tx.lock().await.take() {
             // errors if there are no receivers, but that's irrelevant.
             let _ = tx.send(true);
-            // wait for all receivers to drop/close.
-            let _ = tx.closed().await;
+        }
+        let tasks = std::mem::take(&mut *self.tasks.lock().await);
+        join_tasks(tasks, deadline).await;
+        // read_loopとworkerが止まった後に残っているallocationを全て削除し、relay_loopも待つ
+        let deleted = self.allocation_manager.delete_all_allocations().await;
+        tracing::debug!(deleted = deleted.len(), "closed allocations");
+        let relay_tasks = deleted
+            .iter()
+            .filter_map(|allocation| allocation.take_relay_task())
+            .collect();
+        join_tasks(relay_tasks, deadline).await;
+        let conns = std::mem::take(&mut *self.conns.lock().await);
+        for conn in &conns {
+            if let Err(err) = conn.close().await {
+                tracing::debug!(error = %err, "failed to close listener");
+            }
         }
 
         Ok(())
     }
 }
 
+// 順に終わるのを待つ。deadlineを過ぎたら、残りのtaskはabortしてから待つ
+async fn join_tasks(tasks: Vec<JoinHandle<()>>, deadline: Option<Instant>) {
+    for mut task in tasks {
+        let result = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, &mut task).await {
+                Ok(result) => result,
+                Err(_) => {
+                    task.abort();
+                    task.await
+                }
+            },
+            None => task.await,
+        };
+        if let Err(err) = result {
+            if err.is_panic() {
+                tracing::error!(error = %err, "task panicked");
+            }
+        }
+    }
+}
+
+// 受信用にpoolの先頭INBOUND_MTU byteを用意する。
+// workerが前に渡したBytesを全て手放していれば、reserveは同じ領域を再利用する
+fn prepare_receive_buffer(pool: &mut BytesMut) {
+    if pool.capacity() < INBOUND_MTU {
+        pool.reserve(RECEIVE_POOL_SIZE);
+    }
+    pool.resize(INBOUND_MTU, 0);
+}
+
+// 同じ送信元は常に同じworkerになるように振り分ける
+fn worker_index(addr: SocketAddr, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    addr.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
 pub struct ServerConfig {
-    pub conn_config: Arc<dyn Conn + Send + Sync>,
+    // TURNのrequestを受け付けるsocket。reuse_port_listenersと合わせて1つ以上必要
+    pub conn_configs: Vec<Arc<dyn Conn + Send + Sync>>,
+    // SO_REUSEPORTで同じアドレスに複数のsocketを開き、socketごとに受信・処理するlistener
+    pub reuse_port_listeners: Vec<ReusePortListener>,
+    pub realm: String,
+    // long-term credentialのkeyを引く
+    pub auth_handler: Arc<dyn AuthHandler>,
+    // relay用のsocketをbindするIP
+    pub relay_ip: IpAddr,
+    // NATの内側で動かす場合に、XOR-RELAYED-ADDRESSとしてclientに見せるIP
+    pub external_ip: Option<IpAddr>,
+    // relay用のportの範囲。NoneならOSに選ばせる
+    pub relay_port_range: Option<PortRange>,
+    // LIFETIMEが指定されなかった場合のallocationの寿命
+    pub default_lifetime: Duration,
+    // clientが要求できるallocationの寿命の上限
+    pub max_lifetime: Duration,
+    // サーバー全体のallocation数の上限
+    pub max_allocations: Option<usize>,
+    // ユーザーごとのallocation数の上限
+    pub max_allocations_per_user: Option<usize>,
+    // listener(SO_REUSEPORTのsocketは1つずつ)ごとにrequestを並行して処理するworkerの数
+    pub request_workers: usize,
+    // 認証済みユーザーがどのpeerに到達できるかのルール。Noneなら全て許可する
+    pub peer_acl: Option<Arc<PeerAcl>>,
+    // Prometheus形式のメトリクスを公開するHTTPのアドレス。Noneなら公開しない
+    pub metrics_address: Option<SocketAddr>,
+    // allocationのライフサイクルなどのイベントを受け取る。課金や監視に使う
+    pub observer: Option<Arc<dyn ServerObserver>>,
+    // allocationを一覧・削除する管理用HTTPのアドレス。Noneなら公開しない。loopbackのアドレスのみ
+    pub admin_address: Option<SocketAddr>,
+    // 新しいAllocateを300 Try Alternateで別のサーバーに向かわせるかを決める。Noneならredirectしない
+    pub redirect_policy: Option<Arc<dyn RedirectPolicy>>,
+    // RFC 8016のMOBILITY-TICKETを発行し、Refreshでallocationを別の5-tupleに移せるようにする
+    pub mobility: bool,
+    // Someならrelay用のsocketをこのvnetのNetにbindする。conn_configsもvnetのConnにすれば、
+    // 実際のsocketを使わずにserverを動かせる
+    pub vnet: Option<Arc<Net>>,
+    // Someならclientとpeerとの間のpacketをpcapngに書き出す
+    pub pcap: Option<Arc<PcapTap>>,
 }
 
 impl ServerConfig {
+    // conn_configsとauth_handler以外を既定値で埋める
+    pub fn new(
+        conn_configs: Vec<Arc<dyn Conn + Send + Sync>>,
+        realm: String,
+        auth_handler: Arc<dyn AuthHandler>,
+        relay_ip: IpAddr,
+    ) -> Self {
+        ServerConfig {
+            conn_configs,
+            reuse_port_listeners: vec![],
+            realm,
+            auth_handler,
+            relay_ip,
+            external_ip: None,
+            relay_port_range: None,
+            default_lifetime: DEFAULT_LIFETIME,
+            max_lifetime: MAX_LIFETIME,
+            max_allocations: None,
+            max_allocations_per_user: None,
+            request_workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(DEFAULT_REQUEST_WORKERS),
+            peer_acl: None,
+            metrics_address: None,
+            observer: None,
+            admin_address: None,
+            redirect_policy: None,
+            mobility: false,
+            vnet: None,
+            pcap: None,
+        }
+    }
+
     pub fn validate(&self) -> Result<()> {
+        if self.conn_configs.is_empty() && self.reuse_port_listeners.is_empty() {
+            return Err(Error::ErrNoListeners.into());
+        }
+        if self
+            .reuse_port_listeners
+            .iter()
+            .any(|listener| listener.sockets == 0)
+        {
+            return Err(Error::ErrNoReusePortSockets.into());
+        }
+        if self.realm.is_empty() {
+            return Err(Error::ErrRealmEmpty.into());
+        }
+        // 0.0.0.0のままだとclientに返すrelayアドレスが決まらない
+        if self.relay_ip.is_unspecified() && self.external_ip.is_none() {
+            return Err(Error::ErrRelayAddressUnspecified.into());
+        }
+        if let Some(external_ip) = self.external_ip {
+            if external_ip.is_unspecified() {
+                return Err(Error::ErrRelayAddressUnspecified.into());
+            }
+        }
+        if let Some(port_range) = self.relay_port_range {
+            if port_range.start == 0 {
+                return Err(Error::from(crate::util::Error::ErrInvalidPortNumber).into());
+            }
+            if port_range.end < port_range.start {
+                return Err(Error::from(crate::util::Error::ErrEndPortLessThanStart).into());
+            }
+        }
+        if self.default_lifetime.as_secs() == 0
+            || self.max_lifetime.as_secs() == 0
+            || self.default_lifetime > self.max_lifetime
+        {
+            return Err(Error::ErrInvalidLifetime.into());
+        }
+        // 管理用APIには認証が無く、allocationを削除できるので、外から届くアドレスでは開かない
+        if let Some(admin_address) = self.admin_address {
+            if !admin_address.ip().is_loopback() {
+                return Err(Error::ErrAdminAddressNotLoopback.into());
+            }
+        }
+        if self.request_workers == 0 {
+            return Err(Error::ErrNoRequestWorkers.into());
+        }
+        if self.max_allocations == Some(0) || self.max_allocations_per_user == Some(0) {
+            return Err(Error::ErrInvalidQuota.into());
+        }
+        if let (Some(max_allocations), Some(max_allocations_per_user)) =
+            (self.max_allocations, self.max_allocations_per_user)
+        {
+            if max_allocations_per_user > max_allocations {
+                return Err(Error::ErrInvalidQuota.into());
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::SharedSecretAuthHandler;
+    use crate::five_tuple::FiveTuple;
+    use crate::requested_transport::PROTO_UDP;
+    use crate::vnet::{NetConfig, Router, RouterConfig};
+
+    // validateを通る最小限のconfig。routerはnetが使われている間dropしないように返す
+    fn valid_config() -> (Arc<Router>, ServerConfig) {
+        let router = Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_string(),
+            nat: None,
+        })
+        .unwrap();
+        let net = Net::new(NetConfig {
+            static_ips: vec!["10.0.0.1".to_string()],
+        })
+        .unwrap();
+        router.add_net(&net).unwrap();
+        let conn: Arc<dyn Conn + Send + Sync> =
+            Arc::new(net.bind("10.0.0.1:3478".parse().unwrap()).unwrap());
+        let mut config = ServerConfig::new(
+            vec![conn],
+            "webrtc.rs".to_string(),
+            Arc::new(SharedSecretAuthHandler::new("secret".to_string())),
+            "10.0.0.1".parse().unwrap(),
+        );
+        config.vnet = Some(net);
+        config.request_workers = 2;
+        (router, config)
+    }
+
+    async fn new_server() -> (Arc<Router>, Server, Arc<dyn Conn + Send + Sync>) {
+        let (router, mut config) = valid_config();
+        let conn = Arc::clone(&config.conn_configs[0]);
+        config.admin_address = Some("127.0.0.1:0".parse().unwrap());
+        config.metrics_address = Some("127.0.0.1:0".parse().unwrap());
+        let server = Server::new(config).await.unwrap();
+        (router, server, conn)
+    }
+
+    fn validate_error(modify: impl FnOnce(&mut ServerConfig)) -> Error {
+        let (_router, mut config) = valid_config();
+        modify(&mut config);
+        let err = config.validate().expect_err("config should be rejected");
+        err.downcast::<Error>().unwrap()
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_config() {
+        let (_router, config) = valid_config();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_listeners() {
+        assert_eq!(
+            validate_error(|config| config.conn_configs.clear()),
+            Error::ErrNoListeners
+        );
+        assert_eq!(
+            validate_error(|config| config
+                .reuse_port_listeners
+                .push(ReusePortListener::new("10.0.0.1:3478".parse().unwrap(), 0))),
+            Error::ErrNoReusePortSockets
+        );
+    }
+
+    #[test]
+    fn test_validate_realm() {
+        assert_eq!(
+            validate_error(|config| config.realm.clear()),
+            Error::ErrRealmEmpty
+        );
+    }
+
+    #[test]
+    fn test_validate_relay_ip() {
+        assert_eq!(
+            validate_error(|config| config.relay_ip = "0.0.0.0".parse().unwrap()),
+            Error::ErrRelayAddressUnspecified
+        );
+        assert_eq!(
+            validate_error(|config| config.external_ip = Some("::".parse().unwrap())),
+            Error::ErrRelayAddressUnspecified
+        );
+        // NATの内側で0.0.0.0にbindしても、external_ipがあればrelayアドレスは決まる
+        let (_router, mut config) = valid_config();
+        config.relay_ip = "0.0.0.0".parse().unwrap();
+        config.external_ip = Some("203.0.113.5".parse().unwrap());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_port_range() {
+        assert_eq!(
+            validate_error(|config| config.relay_port_range = Some(PortRange {
+                start: 0,
+                end: 1000
+            })),
+            Error::from(crate::util::Error::ErrInvalidPortNumber)
+        );
+        assert_eq!(
+            validate_error(|config| config.relay_port_range = Some(PortRange {
+                start: 50000,
+                end: 49999
+            })),
+            Error::from(crate::util::Error::ErrEndPortLessThanStart)
+        );
+    }
+
+    #[test]
+    fn test_validate_lifetimes() {
+        assert_eq!(
+            validate_error(|config| config.default_lifetime = Duration::from_millis(500)),
+            Error::ErrInvalidLifetime
+        );
+        assert_eq!(
+            validate_error(|config| config.max_lifetime = Duration::from_secs(0)),
+            Error::ErrInvalidLifetime
+        );
+        assert_eq!(
+            validate_error(|config| {
+                config.default_lifetime = Duration::from_secs(3600);
+                config.max_lifetime = Duration::from_secs(600);
+            }),
+            Error::ErrInvalidLifetime
+        );
+    }
+
+    #[test]
+    fn test_validate_admin_address() {
+        assert_eq!(
+            validate_error(|config| config.admin_address = Some("0.0.0.0:8080".parse().unwrap())),
+            Error::ErrAdminAddressNotLoopback
+        );
+        let (_router, mut config) = valid_config();
+        config.admin_address = Some("[::1]:8080".parse().unwrap());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_request_workers() {
+        assert_eq!(
+            validate_error(|config| config.request_workers = 0),
+            Error::ErrNoRequestWorkers
+        );
+    }
+
+    #[test]
+    fn test_validate_quotas() {
+        assert_eq!(
+            validate_error(|config| config.max_allocations = Some(0)),
+            Error::ErrInvalidQuota
+        );
+        assert_eq!(
+            validate_error(|config| config.max_allocations_per_user = Some(0)),
+            Error::ErrInvalidQuota
+        );
+        assert_eq!(
+            validate_error(|config| {
+                config.max_allocations = Some(10);
+                config.max_allocations_per_user = Some(11);
+            }),
+            Error::ErrInvalidQuota
+        );
+    }
+
+    async fn allocate(server: &Server, conn: &Arc<dyn Conn + Send + Sync>) -> Arc<Allocation> {
+        server
+            .allocation_manager
+            .create_allocation(
+                FiveTuple {
+                    protocol: PROTO_UDP,
+                    src_addr: "10.0.0.2:5000".parse().unwrap(),
+                    dst_addr: "10.0.0.1:3478".parse().unwrap(),
+                },
+                "alice".to_string(),
+                Duration::from_secs(600),
+                Arc::clone(conn),
+            )
+            .await
+            .unwrap()
+    }
+
+    // closeから戻った時には、relay_loopも含めて全てのtaskが終わっている
+    #[tokio::test]
+    async fn test_close_waits_for_all_tasks() {
+        let (_router, server, conn) = new_server().await;
+        let allocation = allocate(&server, &conn).await;
+        server.close().await.unwrap();
+
+        assert!(server.tasks.lock().await.is_empty());
+        assert_eq!(server.allocation_manager.allocation_count().await, 0);
+        // relay_loopが持っていた参照も残っていない
+        assert_eq!(Arc::strong_count(&allocation), 1);
+        assert!(allocation.take_relay_task().is_none());
+        // 2回目のcloseは何もしない
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_gracefully_applies_deadline() {
+        let (_router, server, conn) = new_server().await;
+        let allocation = allocate(&server, &conn).await;
+        let start = Instant::now();
+        server
+            .close_gracefully(Duration::from_millis(300))
+            .await
+            .unwrap();
+
+        // allocationが残っていても、deadlineを大きく過ぎずに戻る
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(server.tasks.lock().await.is_empty());
+        assert_eq!(server.allocation_manager.allocation_count().await, 0);
+        assert_eq!(Arc::strong_count(&allocation), 1);
+    }
+}
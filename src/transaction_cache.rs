@@ -0,0 +1,203 @@
+use crate::five_tuple::FiveTuple;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio::time::Instant;
+
+// RFC 5766 sec 6.2
+// UDPのclientはRc * RTO(既定では39.5秒)の間requestを再送しうるので、その間はresponseを覚えておく
+pub const TRANSACTION_CACHE_TTL: Duration = Duration::from_secs(40);
+// 大量のrequestでメモリを使い切らないように、これを超えたら古いものから捨てる
+const MAX_CACHED_TRANSACTIONS: usize = 65536;
+const CACHE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+type TransactionKey = (FiveTuple, [u8; 12]);
+
+struct Responses {
+    by_key: HashMap<TransactionKey, (Vec<u8>, Instant)>,
+    // 入れた順のkeyと期限。TTLは一定なので、先頭が一番先に期限切れになる
+    order: VecDeque<(TransactionKey, Instant)>,
+}
+
+impl Responses {
+    // 先頭のものを捨てる。同じkeyで入れ直されていれば、新しい方は残す
+    fn pop_oldest(&mut self) {
+        if let Some((key, expires_at)) = self.order.pop_front() {
+            if self.by_key.get(&key).map(|(_, e)| *e) == Some(expires_at) {
+                self.by_key.remove(&key);
+            }
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        while matches!(self.order.front(), Some((_, expires_at)) if *expires_at <= now) {
+            self.pop_oldest();
+        }
+    }
+}
+
+// 再送されたrequestに、最初に返したresponseのbyte列をそのまま返すためのcache。
+// これが無いと、再送されたAllocateに437を返したり、処理を2回行ったりしてしまう
+pub struct TransactionCache {
+    responses: Mutex<Responses>,
+}
+
+impl Default for TransactionCache {
+    fn default() -> Self {
+        TransactionCache::new()
+    }
+}
+
+impl TransactionCache {
+    pub fn new() -> Self {
+        TransactionCache {
+            responses: Mutex::new(Responses {
+                by_key: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub async fn get(&self, five_tuple: FiveTuple, transaction_id: [u8; 12]) -> Option<Vec<u8>> {
+        let responses = self.responses.lock().await;
+        match responses.by_key.get(&(five_tuple, transaction_id)) {
+            Some((response, expires_at)) if *expires_at > Instant::now() => Some(response.clone()),
+            _ => None,
+        }
+    }
+
+    pub async fn insert(&self, five_tuple: FiveTuple, transaction_id: [u8; 12], response: Vec<u8>) {
+        let mut responses = self.responses.lock().await;
+        while responses.order.len() >= MAX_CACHED_TRANSACTIONS {
+            responses.pop_oldest();
+        }
+        let key = (five_tuple, transaction_id);
+        let expires_at = Instant::now() + TRANSACTION_CACHE_TTL;
+        responses.by_key.insert(key, (response, expires_at));
+        responses.order.push_back((key, expires_at));
+    }
+
+    // 期限切れのresponseを定期的に捨てる
+    pub async fn run_expiry_loop(self: Arc<Self>, mut shutdown_rx: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(CACHE_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                did_change = shutdown_rx.changed() => {
+                    if did_change.is_err() || *shutdown_rx.borrow() {
+                        break;
+                    } else {
+                        continue;
+                    }
+                }
+            }
+            self.responses.lock().await.remove_expired(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requested_transport::PROTO_UDP;
+
+    fn five_tuple(port: u16) -> FiveTuple {
+        FiveTuple {
+            protocol: PROTO_UDP,
+            src_addr: format!("10.0.0.2:{}", port).parse().unwrap(),
+            dst_addr: "10.0.0.1:3478".parse().unwrap(),
+        }
+    }
+
+    fn transaction_id(n: u32) -> [u8; 12] {
+        let mut id = [0u8; 12];
+        id[..4].copy_from_slice(&n.to_be_bytes());
+        id
+    }
+
+    #[tokio::test]
+    async fn test_replay_hit() {
+        let cache = TransactionCache::new();
+        cache
+            .insert(five_tuple(5000), transaction_id(1), b"response".to_vec())
+            .await;
+        assert_eq!(
+            cache.get(five_tuple(5000), transaction_id(1)).await,
+            Some(b"response".to_vec())
+        );
+        assert_eq!(cache.get(five_tuple(5000), transaction_id(2)).await, None);
+    }
+
+    // 同じtransaction IDでも、別の5-tupleから届いたrequestには返さない
+    #[tokio::test]
+    async fn test_five_tuple_mismatch_misses() {
+        let cache = TransactionCache::new();
+        cache
+            .insert(five_tuple(5000), transaction_id(1), b"response".to_vec())
+            .await;
+        assert_eq!(cache.get(five_tuple(5001), transaction_id(1)).await, None);
+        let mut tcp = five_tuple(5000);
+        tcp.protocol = crate::requested_transport::Protocol(6);
+        assert_eq!(cache.get(tcp, transaction_id(1)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_expired_responses_are_evicted() {
+        let cache = TransactionCache::new();
+        cache
+            .insert(five_tuple(5000), transaction_id(1), b"first".to_vec())
+            .await;
+        cache
+            .insert(five_tuple(5000), transaction_id(2), b"second".to_vec())
+            .await;
+        let mut responses = cache.responses.lock().await;
+        // 期限の切れたものはexpiry loopで捨てる前でも返さない
+        let first_expires_at = responses.order[0].1;
+        let expired = Instant::now();
+        responses
+            .by_key
+            .get_mut(&(five_tuple(5000), transaction_id(1)))
+            .unwrap()
+            .1 = expired;
+        responses.order[0].1 = expired;
+        drop(responses);
+        assert_eq!(cache.get(five_tuple(5000), transaction_id(1)).await, None);
+
+        let mut responses = cache.responses.lock().await;
+        responses.remove_expired(first_expires_at);
+        assert_eq!(responses.by_key.len(), 1);
+        assert_eq!(responses.order.len(), 1);
+        responses.remove_expired(Instant::now() + TRANSACTION_CACHE_TTL);
+        assert!(responses.by_key.is_empty());
+        assert!(responses.order.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_oldest_is_evicted_when_full() {
+        let cache = TransactionCache::new();
+        for i in 0..=MAX_CACHED_TRANSACTIONS as u32 {
+            cache
+                .insert(
+                    five_tuple(5000),
+                    transaction_id(i),
+                    i.to_be_bytes().to_vec(),
+                )
+                .await;
+        }
+        assert_eq!(cache.get(five_tuple(5000), transaction_id(0)).await, None);
+        assert_eq!(
+            cache.get(five_tuple(5000), transaction_id(1)).await,
+            Some(1u32.to_be_bytes().to_vec())
+        );
+        let last = MAX_CACHED_TRANSACTIONS as u32;
+        assert_eq!(
+            cache.get(five_tuple(5000), transaction_id(last)).await,
+            Some(last.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            cache.responses.lock().await.by_key.len(),
+            MAX_CACHED_TRANSACTIONS
+        );
+    }
+}
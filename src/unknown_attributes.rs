@@ -0,0 +1,74 @@
+use crate::integrity::*;
+use stun::attribute::*;
+use stun::message::*;
+
+// RFC 5389 sec 15.9
+// 420 Unknown Attributeのresponseに、理解できなかったattributeのtypeを並べる
+pub struct UnknownAttributes(pub Vec<u16>);
+
+// 0x0000-0x7FFFはcomprehension-requiredで、知らないattributeがあればrequestを処理してはいけない
+const COMPREHENSION_OPTIONAL_START: u16 = 0x8000;
+
+// このサーバーがrequestの中で処理するcomprehension-requiredのattribute。
+// REQUESTED-ADDRESS-FAMILY, EVEN-PORT, DONT-FRAGMENT, RESERVATION-TOKENなどは
+// 実装していないので、黙って無視せずに420で知らせる
+const KNOWN_ATTRIBUTES: &[u16] = &[
+    0x0006, // USERNAME
+    0x0008, // MESSAGE-INTEGRITY
+    0x000C, // CHANNEL-NUMBER
+    0x000D, // LIFETIME
+    0x0012, // XOR-PEER-ADDRESS
+    0x0013, // DATA
+    0x0014, // REALM
+    0x0015, // NONCE
+    0x0019, // REQUESTED-TRANSPORT
+    0x001B, // ACCESS-TOKEN
+];
+
+impl UnknownAttributes {
+    // packetに含まれる、理解できないcomprehension-requiredのattributeを集める。
+    // MESSAGE-INTEGRITYより後のattributeは無視するので、420の対象にもしない
+    pub fn find_in(packet: &[u8]) -> UnknownAttributes {
+        let mut types = attribute_types(packet);
+        if let Some(integrity) = types
+            .iter()
+            .position(|typ| *typ == ATTR_MESSAGE_INTEGRITY_TYPE)
+        {
+            types.truncate(integrity + 1);
+        }
+        let mut unknown: Vec<u16> = types
+            .into_iter()
+            .filter(|typ| *typ < COMPREHENSION_OPTIONAL_START && !KNOWN_ATTRIBUTES.contains(typ))
+            .collect();
+        unknown.sort_unstable();
+        unknown.dedup();
+        UnknownAttributes(unknown)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get_from(m: &Message) -> Option<UnknownAttributes> {
+        let attribute = m
+            .attributes
+            .iter()
+            .find(|e| e.typ == ATTR_UNKNOWN_ATTRIBUTES)?;
+        Some(UnknownAttributes(
+            attribute
+                .value
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect(),
+        ))
+    }
+}
+
+impl Setter for UnknownAttributes {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let raw: Vec<u8> = self.0.iter().flat_map(|typ| typ.to_be_bytes()).collect();
+        let extra_attribute = Attribute::new(ATTR_UNKNOWN_ATTRIBUTES, raw.len() as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
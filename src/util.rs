@@ -1,8 +1,10 @@
 use async_trait::async_trait;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
-use tokio::net::UdpSocket;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::watch;
 pub type Result<T> = std::result::Result<T, Error>;
 #[async_trait]
 pub trait Conn {
@@ -14,39 +16,97 @@ pub trait Conn {
     async fn local_addr(&self) -> Result<SocketAddr>;
     async fn remote_addr(&self) -> Option<SocketAddr>;
     async fn close(&self) -> Result<()>;
+
+    // 複数のdatagramをまとめて送る。送れたdatagramの数を返す。
+    // batchで送れない実装は1つずつsend_toする
+    async fn send_batch(&self, packets: &[(&[u8], SocketAddr)]) -> Result<usize> {
+        for (buf, target) in packets {
+            self.send_to(buf, *target).await?;
+        }
+        Ok(packets.len())
+    }
+}
+
+// closeすると待っているrecv_fromがErrUseClosedNetworkConnで返り、socketが閉じられるConn
+pub struct UdpConn {
+    // closeでNoneにする。送受信中のものが手放した時点でsocketが閉じる
+    socket: Mutex<Option<Arc<UdpSocket>>>,
+    close_tx: watch::Sender<bool>,
+}
+
+impl UdpConn {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Ok(UdpConn::new(UdpSocket::bind(addr).await?))
+    }
+
+    pub fn new(socket: UdpSocket) -> Self {
+        UdpConn::from_shared(Arc::new(socket))
+    }
+
+    // socketを他でも参照している場合。closeしても、全ての参照がdropされるまでsocketは閉じない
+    pub(crate) fn from_shared(socket: Arc<UdpSocket>) -> Self {
+        let (close_tx, _) = watch::channel(false);
+        UdpConn {
+            socket: Mutex::new(Some(socket)),
+            close_tx,
+        }
+    }
+
+    fn socket(&self) -> Result<Arc<UdpSocket>> {
+        self.socket
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or(Error::ErrUseClosedNetworkConn)
+    }
 }
 
 #[async_trait]
-impl Conn for UdpSocket {
+impl Conn for UdpConn {
     async fn connect(&self, addr: SocketAddr) -> Result<()> {
-        Ok(self.connect(addr).await?)
+        Ok(self.socket()?.connect(addr).await?)
     }
 
     async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
-        Ok(self.recv(buf).await?)
+        // 先にsubscribeしておけば、socketを取り出した後のcloseも取りこぼさない
+        let mut close_rx = self.close_tx.subscribe();
+        let socket = self.socket()?;
+        tokio::select! {
+            v = socket.recv(buf) => Ok(v?),
+            _ = close_rx.changed() => Err(Error::ErrUseClosedNetworkConn),
+        }
     }
 
     async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
-        Ok(self.recv_from(buf).await?)
+        let mut close_rx = self.close_tx.subscribe();
+        let socket = self.socket()?;
+        tokio::select! {
+            v = socket.recv_from(buf) => Ok(v?),
+            _ = close_rx.changed() => Err(Error::ErrUseClosedNetworkConn),
+        }
     }
 
     async fn send(&self, buf: &[u8]) -> Result<usize> {
-        Ok(self.send(buf).await?)
+        Ok(self.socket()?.send(buf).await?)
     }
 
     async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
-        Ok(self.send_to(buf, target).await?)
+        Ok(self.socket()?.send_to(buf, target).await?)
     }
 
     async fn local_addr(&self) -> Result<SocketAddr> {
-        Ok(self.local_addr()?)
+        Ok(self.socket()?.local_addr()?)
     }
 
     async fn remote_addr(&self) -> Option<SocketAddr> {
-        None
+        self.socket().ok()?.peer_addr().ok()
     }
 
     async fn close(&self) -> Result<()> {
+        if self.socket.lock().unwrap().take().is_none() {
+            return Err(Error::ErrAlreadyClosed);
+        }
+        let _ = self.close_tx.send(true);
         Ok(())
     }
 }
@@ -149,6 +209,10 @@ pub enum Error {
     ErrNoIpaddrEth0,
     #[error("Invalid mask")]
     ErrInvalidMask,
+    #[error(
+        "chaos: rates must be between 0.0 and 1.0, and bandwidth and queue_limit must be positive"
+    )]
+    ErrInvalidChaosConfig,
     #[error("{0}")]
     Io(#[source] IoError),
     #[error("{0}")]
@@ -0,0 +1,106 @@
+use super::net::Net;
+use super::Chunk;
+use crate::util::{Conn, Error, Result};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+// Net::bindで作る、UdpSocketの代わりになるConn
+pub struct VNetConn {
+    net: Arc<Net>,
+    local_addr: SocketAddr,
+    remote_addr: std::sync::Mutex<Option<SocketAddr>>,
+    rx: Mutex<mpsc::Receiver<Chunk>>,
+    closed: AtomicBool,
+}
+
+impl VNetConn {
+    pub(crate) fn new(net: Arc<Net>, local_addr: SocketAddr, rx: mpsc::Receiver<Chunk>) -> Self {
+        VNetConn {
+            net,
+            local_addr,
+            remote_addr: std::sync::Mutex::new(None),
+            rx: Mutex::new(rx),
+            closed: AtomicBool::new(false),
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for VNetConn {
+    async fn connect(&self, addr: SocketAddr) -> Result<()> {
+        *self.remote_addr.lock().unwrap() = Some(addr);
+        Ok(())
+    }
+
+    // connectしている場合は、その相手以外からのdatagramを捨てる
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let (n, from) = self.recv_from(buf).await?;
+            match *self.remote_addr.lock().unwrap() {
+                Some(remote_addr) if remote_addr != from => continue,
+                _ => return Ok(n),
+            }
+        }
+    }
+
+    // bufに入りきらない部分は、実際のUDPと同じように切り捨てる
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let chunk = {
+            let mut rx = self.rx.lock().await;
+            rx.recv().await.ok_or(Error::ErrUseClosedNetworkConn)?
+        };
+        let n = chunk.data.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk.data[..n]);
+        Ok((n, chunk.src))
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let remote_addr = (*self.remote_addr.lock().unwrap()).ok_or(Error::ErrNoRemAddr)?;
+        self.send_to(buf, remote_addr).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> Result<usize> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::ErrUseClosedNetworkConn);
+        }
+        let src_ip = if self.local_addr.ip().is_unspecified() {
+            self.net.source_ip(target)?
+        } else {
+            self.local_addr.ip()
+        };
+        self.net.send(Chunk {
+            src: SocketAddr::new(src_ip, self.local_addr.port()),
+            dst: target,
+            data: buf.to_vec(),
+        })?;
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    async fn remote_addr(&self) -> Option<SocketAddr> {
+        *self.remote_addr.lock().unwrap()
+    }
+
+    async fn close(&self) -> Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Err(Error::ErrAlreadyClosed);
+        }
+        self.net.unbind(self.local_addr);
+        Ok(())
+    }
+}
+
+// closeせずに捨てられた場合もportを解放する。close済みなら、同じportを別のconnがbindしているかもしれないので触らない
+impl Drop for VNetConn {
+    fn drop(&mut self) {
+        if !self.closed.load(Ordering::SeqCst) {
+            self.net.unbind(self.local_addr);
+        }
+    }
+}
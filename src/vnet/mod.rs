@@ -0,0 +1,31 @@
+// 実際のsocketを使わずに、server, client, peerを1つのprocessの中で動かすための仮想ネットワーク。
+//
+//   let router = Router::new(RouterConfig { cidr: "10.0.0.0/24".to_string() })?;
+//   let net = Net::new(NetConfig::default())?;
+//   router.add_net(&net)?;
+//   let conn = net.bind("0.0.0.0:3478".parse()?)?;
+//
+// packetはsend_toの中で同期的に宛先のconnの受信queueまで運ばれるので、
+// 遅延や順序の入れ替わりは起きない。
+//
+// RouterConfigのnatを指定すると、そのrouterは親routerとの間のNATになる。
+// 親routerのアドレス空間にexternal_ipsを持ち、内側のnetからはNatTypeに従って外に出られる
+mod conn;
+mod nat;
+mod net;
+mod router;
+
+pub use conn::VNetConn;
+pub use nat::{NatConfig, NatType, DEFAULT_MAPPING_LIFETIME};
+pub use net::{Net, NetConfig};
+pub use router::{Router, RouterConfig};
+
+use std::net::SocketAddr;
+
+// vnetの中を流れるUDPのdatagram
+#[derive(Debug, Clone)]
+pub(crate) struct Chunk {
+    pub(crate) src: SocketAddr,
+    pub(crate) dst: SocketAddr,
+    pub(crate) data: Vec<u8>,
+}
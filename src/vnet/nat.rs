@@ -0,0 +1,311 @@
+use super::Chunk;
+use crate::util::{Error, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// mapped portを選ぶ範囲
+const MAPPED_PORT_START: u16 = 49152;
+const MAPPED_PORT_END: u16 = 65535;
+// 外に向けたpacketが途絶えてからbindingを消すまでの時間
+pub const DEFAULT_MAPPING_LIFETIME: Duration = Duration::from_secs(30);
+
+// RFC 3489 sec 5 の分類。Staticは1:1 NATで、portは変えずにIPだけを付け替える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    // 一度外に送ったportには、誰からでも届く
+    FullCone,
+    // 送ったことのあるIPからなら、どのportからでも届く
+    AddressRestricted,
+    // 送ったことのあるIPとportからだけ届く
+    PortRestricted,
+    // 宛先ごとに別のmapped portを使い、その宛先からだけ届く
+    Symmetric,
+    Static,
+}
+
+#[derive(Debug, Clone)]
+pub struct NatConfig {
+    pub nat_type: NatType,
+    // 親routerのアドレス空間で、このNATが外に見せるIP。Static以外は先頭だけを使う
+    pub external_ips: Vec<String>,
+    // Staticの場合に、external_ipsと同じ順で対応させる内側のIP
+    pub internal_ips: Vec<String>,
+    pub mapping_lifetime: Duration,
+}
+
+impl NatConfig {
+    pub fn new(nat_type: NatType, external_ip: &str) -> Self {
+        NatConfig {
+            nat_type,
+            external_ips: vec![external_ip.to_string()],
+            internal_ips: vec![],
+            mapping_lifetime: DEFAULT_MAPPING_LIFETIME,
+        }
+    }
+}
+
+struct Binding {
+    local: SocketAddr,
+    mapped: SocketAddr,
+    // 内側から送ったことのある宛先。inboundのfilterに使う
+    remotes: Vec<SocketAddr>,
+    expires_at: Instant,
+}
+
+pub(crate) struct Nat {
+    nat_type: NatType,
+    external_ips: Vec<IpAddr>,
+    // Staticの場合の 内側のIP -> 外側のIP
+    static_mappings: HashMap<IpAddr, IpAddr>,
+    mapping_lifetime: Duration,
+    state: Mutex<NatState>,
+}
+
+struct NatState {
+    // Symmetricでは(local, remote)、それ以外では(local, None)がkey
+    outbound: HashMap<(SocketAddr, Option<SocketAddr>), SocketAddr>,
+    // mapped address -> binding
+    inbound: HashMap<SocketAddr, Binding>,
+}
+
+impl Nat {
+    pub(crate) fn new(config: &NatConfig) -> Result<Nat> {
+        let parse = |ips: &[String]| {
+            ips.iter()
+                .map(|ip| ip.parse().map_err(|_| Error::ErrFailedToParseIpaddr))
+                .collect::<Result<Vec<IpAddr>>>()
+        };
+        let external_ips = parse(&config.external_ips)?;
+        let internal_ips = parse(&config.internal_ips)?;
+        if external_ips.is_empty() {
+            return Err(Error::ErrNatRequriesMapping);
+        }
+        let static_mappings = if config.nat_type == NatType::Static {
+            if internal_ips.len() != external_ips.len() {
+                return Err(Error::ErrNatRequriesMapping);
+            }
+            internal_ips
+                .into_iter()
+                .zip(external_ips.iter().copied())
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Nat {
+            nat_type: config.nat_type,
+            external_ips,
+            static_mappings,
+            mapping_lifetime: config.mapping_lifetime,
+            state: Mutex::new(NatState {
+                outbound: HashMap::new(),
+                inbound: HashMap::new(),
+            }),
+        })
+    }
+
+    pub(crate) fn external_ips(&self) -> &[IpAddr] {
+        &self.external_ips
+    }
+
+    // 内側から外に出るpacketの送信元をmapped addressに付け替える
+    pub(crate) fn translate_outbound(&self, mut chunk: Chunk) -> Result<Chunk> {
+        if self.nat_type == NatType::Static {
+            let external_ip = self
+                .static_mappings
+                .get(&chunk.src.ip())
+                .ok_or(Error::ErrNoNatBindingFound)?;
+            chunk.src = SocketAddr::new(*external_ip, chunk.src.port());
+            return Ok(chunk);
+        }
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.inbound.retain(|_, binding| binding.expires_at > now);
+        let NatState { outbound, inbound } = &mut *state;
+        outbound.retain(|_, mapped| inbound.contains_key(mapped));
+
+        let key = match self.nat_type {
+            NatType::Symmetric => (chunk.src, Some(chunk.dst)),
+            _ => (chunk.src, None),
+        };
+        let mapped = match outbound.get(&key) {
+            Some(mapped) => *mapped,
+            None => {
+                let external_ip = self.external_ips[0];
+                let port = (MAPPED_PORT_START..=MAPPED_PORT_END)
+                    .find(|port| !inbound.contains_key(&SocketAddr::new(external_ip, *port)))
+                    .ok_or(Error::ErrPortSpaceExhausted)?;
+                let mapped = SocketAddr::new(external_ip, port);
+                outbound.insert(key, mapped);
+                inbound.insert(
+                    mapped,
+                    Binding {
+                        local: chunk.src,
+                        mapped,
+                        remotes: vec![],
+                        expires_at: now,
+                    },
+                );
+                tracing::trace!(src = %chunk.src, mapped = %mapped, "vnet: new NAT binding");
+                mapped
+            }
+        };
+        let binding = inbound
+            .get_mut(&mapped)
+            .ok_or(Error::ErrNoNatBindingFound)?;
+        if !binding.remotes.contains(&chunk.dst) {
+            binding.remotes.push(chunk.dst);
+        }
+        binding.expires_at = now + self.mapping_lifetime;
+        chunk.src = binding.mapped;
+        Ok(chunk)
+    }
+
+    // 外から届いたpacketの宛先を内側のaddressに戻す。NATの種類ごとのfilterを通らなければErr
+    pub(crate) fn translate_inbound(&self, mut chunk: Chunk) -> Result<Chunk> {
+        if self.nat_type == NatType::Static {
+            let internal_ip = self
+                .static_mappings
+                .iter()
+                .find(|(_, external_ip)| **external_ip == chunk.dst.ip())
+                .map(|(internal_ip, _)| *internal_ip)
+                .ok_or(Error::ErrNoNatBindingFound)?;
+            chunk.dst = SocketAddr::new(internal_ip, chunk.dst.port());
+            return Ok(chunk);
+        }
+
+        let state = self.state.lock().unwrap();
+        let binding = match state.inbound.get(&chunk.dst) {
+            Some(binding) if binding.expires_at > Instant::now() => binding,
+            _ => return Err(Error::ErrNoNatBindingFound),
+        };
+        let allowed = match self.nat_type {
+            NatType::FullCone => true,
+            NatType::AddressRestricted => binding
+                .remotes
+                .iter()
+                .any(|remote| remote.ip() == chunk.src.ip()),
+            NatType::PortRestricted | NatType::Symmetric => binding.remotes.contains(&chunk.src),
+            NatType::Static => unreachable!(),
+        };
+        if !allowed {
+            return Err(Error::ErrNoNatBindingFound);
+        }
+        chunk.dst = binding.local;
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTERNAL_IP: &str = "10.0.0.2";
+
+    fn chunk(src: &str, dst: &str) -> Chunk {
+        Chunk {
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            data: vec![],
+        }
+    }
+
+    fn new_nat(nat_type: NatType) -> Nat {
+        Nat::new(&NatConfig::new(nat_type, EXTERNAL_IP)).unwrap()
+    }
+
+    // 内側の192.168.0.10:5000から宛先に送り、外から見えたmapped addressを返す
+    fn send_out(nat: &Nat, dst: &str) -> SocketAddr {
+        nat.translate_outbound(chunk("192.168.0.10:5000", dst))
+            .unwrap()
+            .src
+    }
+
+    fn is_allowed(nat: &Nat, src: &str, mapped: SocketAddr) -> bool {
+        nat.translate_inbound(chunk(src, &mapped.to_string()))
+            .is_ok()
+    }
+
+    #[test]
+    fn test_full_cone() {
+        let nat = new_nat(NatType::FullCone);
+        let mapped = send_out(&nat, "10.0.0.1:3478");
+        assert_eq!(
+            mapped,
+            SocketAddr::new(EXTERNAL_IP.parse().unwrap(), MAPPED_PORT_START)
+        );
+        // 宛先が変わっても同じmapped portを使う
+        assert_eq!(send_out(&nat, "10.0.0.3:4000"), mapped);
+        assert!(is_allowed(&nat, "10.0.0.1:3478", mapped));
+        assert!(is_allowed(&nat, "10.0.0.1:9999", mapped));
+        assert!(is_allowed(&nat, "10.0.0.9:9999", mapped));
+        let inbound = nat
+            .translate_inbound(chunk("10.0.0.9:9999", &mapped.to_string()))
+            .unwrap();
+        assert_eq!(inbound.dst, "192.168.0.10:5000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_address_restricted() {
+        let nat = new_nat(NatType::AddressRestricted);
+        let mapped = send_out(&nat, "10.0.0.1:3478");
+        assert_eq!(send_out(&nat, "10.0.0.3:4000"), mapped);
+        assert!(is_allowed(&nat, "10.0.0.1:3478", mapped));
+        assert!(is_allowed(&nat, "10.0.0.1:9999", mapped));
+        assert!(!is_allowed(&nat, "10.0.0.9:3478", mapped));
+    }
+
+    #[test]
+    fn test_port_restricted() {
+        let nat = new_nat(NatType::PortRestricted);
+        let mapped = send_out(&nat, "10.0.0.1:3478");
+        assert_eq!(send_out(&nat, "10.0.0.3:4000"), mapped);
+        assert!(is_allowed(&nat, "10.0.0.1:3478", mapped));
+        assert!(is_allowed(&nat, "10.0.0.3:4000", mapped));
+        assert!(!is_allowed(&nat, "10.0.0.1:9999", mapped));
+        assert!(!is_allowed(&nat, "10.0.0.9:3478", mapped));
+    }
+
+    #[test]
+    fn test_symmetric() {
+        let nat = new_nat(NatType::Symmetric);
+        let to_server = send_out(&nat, "10.0.0.1:3478");
+        let to_peer = send_out(&nat, "10.0.0.3:4000");
+        // 宛先ごとに別のmapped portになり、同じ宛先なら使い回す
+        assert_ne!(to_server.port(), to_peer.port());
+        assert_eq!(send_out(&nat, "10.0.0.1:3478"), to_server);
+        assert!(is_allowed(&nat, "10.0.0.1:3478", to_server));
+        assert!(!is_allowed(&nat, "10.0.0.3:4000", to_server));
+        assert!(is_allowed(&nat, "10.0.0.3:4000", to_peer));
+        assert!(!is_allowed(&nat, "10.0.0.1:9999", to_server));
+    }
+
+    #[test]
+    fn test_static() {
+        let nat = Nat::new(&NatConfig {
+            nat_type: NatType::Static,
+            external_ips: vec![EXTERNAL_IP.to_string()],
+            internal_ips: vec!["192.168.0.10".to_string()],
+            mapping_lifetime: DEFAULT_MAPPING_LIFETIME,
+        })
+        .unwrap();
+        // portは変えずにIPだけを付け替え、外からは誰でも届く
+        let mapped = send_out(&nat, "10.0.0.1:3478");
+        assert_eq!(mapped, format!("{}:5000", EXTERNAL_IP).parse().unwrap());
+        let inbound = nat
+            .translate_inbound(chunk("10.0.0.9:9999", &format!("{}:6000", EXTERNAL_IP)))
+            .unwrap();
+        assert_eq!(inbound.dst, "192.168.0.10:6000".parse().unwrap());
+    }
+
+    #[test]
+    fn test_unknown_mapped_port() {
+        let nat = new_nat(NatType::FullCone);
+        let mapped = send_out(&nat, "10.0.0.1:3478");
+        let unused = SocketAddr::new(mapped.ip(), mapped.port() + 1);
+        assert!(!is_allowed(&nat, "10.0.0.1:3478", unused));
+    }
+}
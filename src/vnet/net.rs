@@ -0,0 +1,257 @@
+use super::conn::VNetConn;
+use super::router::Router;
+use super::Chunk;
+use crate::util::{Error, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::mpsc;
+
+// portを0でbindした時に選ぶ範囲
+const EPHEMERAL_PORT_START: u16 = 49152;
+const EPHEMERAL_PORT_END: u16 = 65535;
+// connごとに溜めておけるdatagramの数。溢れたら実際のUDPと同じように捨てる
+const RECEIVE_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct NetConfig {
+    // routerのアドレス空間の中で固定したいIP。空ならrouterが割り当てる
+    pub static_ips: Vec<String>,
+}
+
+// 1台のhostにあたる。routerにつながるとIPが割り当てられ、bindでConnを作れるようになる
+pub struct Net {
+    static_ips: Vec<IpAddr>,
+    state: Mutex<NetState>,
+}
+
+struct NetState {
+    router: Option<Weak<Router>>,
+    ips: Vec<IpAddr>,
+    // bindしたアドレス -> そのconnの受信queue
+    conns: HashMap<SocketAddr, mpsc::Sender<Chunk>>,
+}
+
+impl fmt::Debug for Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Net").field("ips", &self.ips()).finish()
+    }
+}
+
+impl Net {
+    pub fn new(config: NetConfig) -> Result<Arc<Net>> {
+        let static_ips = config
+            .static_ips
+            .iter()
+            .map(|ip| ip.parse().map_err(|_| Error::ErrFailedToParseIpaddr))
+            .collect::<Result<Vec<IpAddr>>>()?;
+        Ok(Arc::new(Net {
+            static_ips,
+            state: Mutex::new(NetState {
+                router: None,
+                ips: vec![],
+                conns: HashMap::new(),
+            }),
+        }))
+    }
+
+    pub(crate) fn static_ips(&self) -> &[IpAddr] {
+        &self.static_ips
+    }
+
+    // routerから割り当てられたIP。routerにつながる前は空
+    pub fn ips(&self) -> Vec<IpAddr> {
+        self.state.lock().unwrap().ips.clone()
+    }
+
+    pub(crate) fn attach(&self, router: Weak<Router>, ips: Vec<IpAddr>) {
+        let mut state = self.state.lock().unwrap();
+        state.router = Some(router);
+        state.ips = ips;
+    }
+
+    // 0.0.0.0にbindすると、このnetの全てのIPとloopbackで受信する。portが0なら空いているものを選ぶ。
+    // routerにつながる前はbindできない
+    pub fn bind(self: &Arc<Self>, addr: SocketAddr) -> Result<VNetConn> {
+        let mut state = self.state.lock().unwrap();
+        if state.router.is_none() {
+            return Err(Error::ErrVnetDisabled);
+        }
+        let ip = addr.ip();
+        if !ip.is_unspecified() && !ip.is_loopback() && !state.ips.contains(&ip) {
+            return Err(if state.ips.is_empty() {
+                Error::ErrNoIpaddrEth0
+            } else {
+                Error::ErrCantAssignRequestedAddr
+            });
+        }
+        let port = if addr.port() == 0 {
+            (EPHEMERAL_PORT_START..=EPHEMERAL_PORT_END)
+                .find(|port| !is_in_use(&state.conns, SocketAddr::new(ip, *port)))
+                .ok_or(Error::ErrPortSpaceExhausted)?
+        } else if is_in_use(&state.conns, addr) {
+            return Err(Error::ErrAddressAlreadyInUse);
+        } else {
+            addr.port()
+        };
+        let local_addr = SocketAddr::new(ip, port);
+        let (tx, rx) = mpsc::channel(RECEIVE_QUEUE_SIZE);
+        state.conns.insert(local_addr, tx);
+        Ok(VNetConn::new(Arc::clone(self), local_addr, rx))
+    }
+
+    // closeされたconnの受信queueを外す。Senderが捨てられるので、待っているrecv_fromが起きる
+    pub(crate) fn unbind(&self, local_addr: SocketAddr) {
+        self.state.lock().unwrap().conns.remove(&local_addr);
+    }
+
+    // 0.0.0.0にbindしたconnから送る時の送信元IP
+    pub(crate) fn source_ip(&self, dst: SocketAddr) -> Result<IpAddr> {
+        if dst.ip().is_loopback() {
+            return Ok(dst.ip());
+        }
+        let state = self.state.lock().unwrap();
+        state
+            .ips
+            .iter()
+            .copied()
+            .find(|ip| ip.is_ipv4() == dst.is_ipv4())
+            .ok_or(Error::ErrNoIpaddrEth0)
+    }
+
+    // 自分宛てならそのまま受信queueに入れ、それ以外はrouterに渡す
+    pub(crate) fn send(&self, chunk: Chunk) -> Result<()> {
+        let router = {
+            let state = self.state.lock().unwrap();
+            let dst_ip = chunk.dst.ip();
+            if dst_ip.is_loopback() || state.ips.contains(&dst_ip) {
+                None
+            } else {
+                Some(
+                    state
+                        .router
+                        .as_ref()
+                        .and_then(Weak::upgrade)
+                        .ok_or(Error::ErrNoRouterLinked)?,
+                )
+            }
+        };
+        match router {
+            Some(router) => router.push(chunk),
+            None => self.deliver(chunk),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn deliver(&self, chunk: Chunk) {
+        let state = self.state.lock().unwrap();
+        let wildcard = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), chunk.dst.port());
+        let tx = match state
+            .conns
+            .get(&chunk.dst)
+            .or_else(|| state.conns.get(&wildcard))
+        {
+            Some(tx) => tx,
+            None => {
+                tracing::trace!(dst = %chunk.dst, "vnet: nothing is bound");
+                return;
+            }
+        };
+        if tx.try_send(chunk).is_err() {
+            tracing::trace!("vnet: receive queue is full or closed");
+        }
+    }
+}
+
+// 同じportに、同じIPか0.0.0.0のどちらかが既にbindされていれば使えない
+fn is_in_use(conns: &HashMap<SocketAddr, mpsc::Sender<Chunk>>, addr: SocketAddr) -> bool {
+    conns.keys().any(|bound| {
+        bound.port() == addr.port()
+            && (bound.ip() == addr.ip()
+                || bound.ip().is_unspecified()
+                || addr.ip().is_unspecified())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Conn;
+    use crate::vnet::RouterConfig;
+
+    fn attached_net(ip: &str) -> (Arc<Router>, Arc<Net>) {
+        let router = Router::new(RouterConfig {
+            cidr: "10.0.0.0/24".to_string(),
+            nat: None,
+        })
+        .unwrap();
+        let net = Net::new(NetConfig {
+            static_ips: vec![ip.to_string()],
+        })
+        .unwrap();
+        router.add_net(&net).unwrap();
+        (router, net)
+    }
+
+    #[test]
+    fn test_bind_before_attach() {
+        let net = Net::new(NetConfig::default()).unwrap();
+        assert_eq!(
+            net.bind("0.0.0.0:0".parse().unwrap()).err(),
+            Some(Error::ErrVnetDisabled)
+        );
+    }
+
+    #[test]
+    fn test_bind_address_already_in_use() {
+        let (_router, net) = attached_net("10.0.0.1");
+        let _conn = net.bind("10.0.0.1:3478".parse().unwrap()).unwrap();
+        assert_eq!(
+            net.bind("10.0.0.1:3478".parse().unwrap()).err(),
+            Some(Error::ErrAddressAlreadyInUse)
+        );
+        // 0.0.0.0は全てのIPと重なる
+        assert_eq!(
+            net.bind("0.0.0.0:3478".parse().unwrap()).err(),
+            Some(Error::ErrAddressAlreadyInUse)
+        );
+    }
+
+    #[test]
+    fn test_bind_cant_assign_requested_address() {
+        let (_router, net) = attached_net("10.0.0.1");
+        assert_eq!(
+            net.bind("10.0.0.2:3478".parse().unwrap()).err(),
+            Some(Error::ErrCantAssignRequestedAddr)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_port_is_released_on_close() {
+        let (_router, net) = attached_net("10.0.0.1");
+        let conn = net.bind("10.0.0.1:3478".parse().unwrap()).unwrap();
+        conn.close().await.unwrap();
+        assert!(net.bind("10.0.0.1:3478".parse().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_port_is_released_on_drop() {
+        let (_router, net) = attached_net("10.0.0.1");
+        drop(net.bind("10.0.0.1:3478".parse().unwrap()).unwrap());
+        assert!(net.bind("10.0.0.1:3478".parse().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_drop_after_close_keeps_rebound_port() {
+        let (_router, net) = attached_net("10.0.0.1");
+        let closed = net.bind("10.0.0.1:3478".parse().unwrap()).unwrap();
+        closed.close().await.unwrap();
+        let _rebound = net.bind("10.0.0.1:3478".parse().unwrap()).unwrap();
+        drop(closed);
+        assert_eq!(
+            net.bind("10.0.0.1:3478".parse().unwrap()).err(),
+            Some(Error::ErrAddressAlreadyInUse)
+        );
+    }
+}
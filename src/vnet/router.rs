@@ -0,0 +1,194 @@
+use super::nat::{Nat, NatConfig};
+use super::net::Net;
+use super::Chunk;
+use crate::acl::Cidr;
+use crate::util::{Error, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex, Weak};
+
+#[derive(Debug, Clone, Default)]
+pub struct RouterConfig {
+    // "10.0.0.0/24" のような、このrouterにつながるnetのアドレス空間。IPv4のみ
+    pub cidr: String,
+    // Someなら、親routerとの間でNATとして振る舞う。親からはexternal_ipsにしか届かない
+    pub nat: Option<NatConfig>,
+}
+
+// 自分のアドレス空間のnetにpacketを届け、子routerのアドレス空間なら子に、
+// どちらでもなければ親に渡す。どこにも届かないpacketは黙って捨てる
+pub struct Router {
+    cidr: Cidr,
+    nat: Option<Nat>,
+    state: Mutex<RouterState>,
+}
+
+struct RouterState {
+    parent: Option<Weak<Router>>,
+    children: Vec<Arc<Router>>,
+    // 割り当てたIP -> そのIPを持つnet
+    nets: HashMap<IpAddr, Arc<Net>>,
+    // NATの子routerが外に見せるIP -> その子router
+    nat_routers: HashMap<IpAddr, Arc<Router>>,
+}
+
+// packetの次の行き先
+enum Hop {
+    Net(Arc<Net>),
+    Router(Arc<Router>),
+    // NATならここで送信元を付け替える
+    Parent(Arc<Router>),
+    Drop,
+}
+
+impl Router {
+    pub fn new(config: RouterConfig) -> Result<Arc<Router>> {
+        let cidr: Cidr = config.cidr.parse().map_err(|_| Error::ErrInvalidMask)?;
+        // network addressとbroadcast addressを除いて、最低でも2つのhostが要る
+        if !cidr.address.is_ipv4() || cidr.prefix_len > 30 {
+            return Err(Error::ErrInvalidMask);
+        }
+        let nat = match &config.nat {
+            Some(nat_config) => Some(Nat::new(nat_config)?),
+            None => None,
+        };
+        Ok(Arc::new(Router {
+            cidr,
+            nat,
+            state: Mutex::new(RouterState {
+                parent: None,
+                children: vec![],
+                nets: HashMap::new(),
+                nat_routers: HashMap::new(),
+            }),
+        }))
+    }
+
+    pub fn cidr(&self) -> Cidr {
+        self.cidr
+    }
+
+    // netにIPを割り当ててつなぐ。NetConfigのstatic_ipsがあればそれを使い、
+    // 無ければアドレス空間の先頭から空いているものを割り当てる
+    pub fn add_net(self: &Arc<Self>, net: &Arc<Net>) -> Result<()> {
+        let ips = {
+            let mut state = self.state.lock().unwrap();
+            let ips = if net.static_ips().is_empty() {
+                vec![self.assign_ip(&state)?]
+            } else {
+                net.static_ips().to_vec()
+            };
+            for ip in &ips {
+                if !self.cidr.contains(*ip) {
+                    return Err(Error::ErrStaticIpIsBeyondSubnet);
+                }
+                if state.nets.contains_key(ip) || state.nat_routers.contains_key(ip) {
+                    return Err(Error::ErrAddressAlreadyInUse);
+                }
+            }
+            for ip in &ips {
+                state.nets.insert(*ip, Arc::clone(net));
+            }
+            ips
+        };
+        net.attach(Arc::downgrade(self), ips);
+        Ok(())
+    }
+
+    // childのアドレス空間宛てのpacketはchildに、childから外に出るpacketはこのrouterに渡す。
+    // childがNATなら、childのアドレス空間ではなくexternal_ips宛てのpacketだけをchildに渡す
+    pub fn add_router(self: &Arc<Self>, child: &Arc<Router>) -> Result<()> {
+        if Arc::ptr_eq(self, child) {
+            return Err(Error::ErrUnexpectedNetwork);
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(nat) = &child.nat {
+                for ip in nat.external_ips() {
+                    if !self.cidr.contains(*ip) {
+                        return Err(Error::ErrStaticIpIsBeyondSubnet);
+                    }
+                    if state.nets.contains_key(ip) || state.nat_routers.contains_key(ip) {
+                        return Err(Error::ErrAddressAlreadyInUse);
+                    }
+                }
+                for ip in nat.external_ips() {
+                    state.nat_routers.insert(*ip, Arc::clone(child));
+                }
+            }
+            state.children.push(Arc::clone(child));
+        }
+        child.state.lock().unwrap().parent = Some(Arc::downgrade(self));
+        Ok(())
+    }
+
+    fn assign_ip(&self, state: &RouterState) -> Result<IpAddr> {
+        // "0.0.0.0/0" でもoverflowしないようにu64で計算する
+        let host_bits = 32 - self.cidr.prefix_len as u32;
+        let network = match self.cidr.address {
+            IpAddr::V4(ip) => u32::from(ip) as u64 & !((1u64 << host_bits) - 1),
+            IpAddr::V6(_) => return Err(Error::ErrInvalidMask),
+        };
+        let broadcast_host = (1u64 << host_bits) - 1;
+        (1..broadcast_host)
+            .map(|host| IpAddr::V4(Ipv4Addr::from((network + host) as u32)))
+            .find(|ip| !state.nets.contains_key(ip) && !state.nat_routers.contains_key(ip))
+            .ok_or(Error::ErrAddressSpaceExhausted)
+    }
+
+    pub(crate) fn push(&self, mut chunk: Chunk) {
+        // 親から届いた、このNATのexternal_ips宛てのpacketは内側のaddressに戻す
+        if let Some(nat) = &self.nat {
+            if nat.external_ips().contains(&chunk.dst.ip()) {
+                let (src, dst) = (chunk.src, chunk.dst);
+                chunk = match nat.translate_inbound(chunk) {
+                    Ok(chunk) => chunk,
+                    Err(err) => {
+                        tracing::trace!(src = %src, dst = %dst, error = %err, "vnet: NAT dropped packet");
+                        return;
+                    }
+                };
+            }
+        }
+        let dst_ip = chunk.dst.ip();
+        let hop = {
+            let state = self.state.lock().unwrap();
+            if let Some(net) = state.nets.get(&dst_ip) {
+                Hop::Net(Arc::clone(net))
+            } else if let Some(child) = state.nat_routers.get(&dst_ip) {
+                Hop::Router(Arc::clone(child))
+            } else if let Some(child) = state
+                .children
+                .iter()
+                .find(|child| child.nat.is_none() && child.cidr.contains(dst_ip))
+            {
+                Hop::Router(Arc::clone(child))
+            } else if self.cidr.contains(dst_ip) {
+                // 自分のアドレス空間で、どのnetも持っていないIP
+                Hop::Drop
+            } else {
+                match state.parent.as_ref().and_then(Weak::upgrade) {
+                    Some(parent) => Hop::Parent(parent),
+                    None => Hop::Drop,
+                }
+            }
+        };
+        match hop {
+            Hop::Net(net) => net.deliver(chunk),
+            Hop::Router(router) => router.push(chunk),
+            Hop::Parent(parent) => match &self.nat {
+                Some(nat) => {
+                    let (src, dst) = (chunk.src, chunk.dst);
+                    match nat.translate_outbound(chunk) {
+                        Ok(chunk) => parent.push(chunk),
+                        Err(err) => {
+                            tracing::trace!(src = %src, dst = %dst, error = %err, "vnet: NAT dropped packet")
+                        }
+                    }
+                }
+                None => parent.push(chunk),
+            },
+            Hop::Drop => tracing::trace!(src = %chunk.src, dst = %chunk.dst, "vnet: no route"),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::error::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use stun::attribute::*;
+use stun::message::*;
+
+// RFC 5389 sec 15.2
+// XOR-MAPPED-ADDRESS / XOR-PEER-ADDRESS / XOR-RELAYED-ADDRESS は同じエンコード方式を使う。
+// portはmagic cookieの上位16bit、IPv4はmagic cookie、IPv6はmagic cookie + transaction_idでXORする。
+pub(crate) const MAGIC_COOKIE: u32 = 0x2112A442;
+pub(crate) const FAMILY_IPV4: u8 = 0x01;
+pub(crate) const FAMILY_IPV6: u8 = 0x02;
+pub const IPV4_ADDRESS_SIZE: usize = 8;
+pub const IPV6_ADDRESS_SIZE: usize = 20;
+pub(crate) const TRANSACTION_ID_SIZE: usize = 12;
+
+// data pathでも使うので、heapを使わずにkeyを作る
+fn xor_key(transaction_id: &[u8]) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    let n = std::cmp::min(transaction_id.len(), TRANSACTION_ID_SIZE);
+    key[4..4 + n].copy_from_slice(&transaction_id[..n]);
+    key
+}
+
+pub fn xor_address_size(addr: SocketAddr) -> usize {
+    match addr {
+        SocketAddr::V4(_) => IPV4_ADDRESS_SIZE,
+        SocketAddr::V6(_) => IPV6_ADDRESS_SIZE,
+    }
+}
+
+pub fn encode_xor_address(addr: SocketAddr, transaction_id: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0u8; xor_address_size(addr)];
+    encode_xor_address_into(addr, transaction_id, &mut raw);
+    raw
+}
+
+// outにはxor_address_size(addr) byte以上の領域が必要
+pub fn encode_xor_address_into(addr: SocketAddr, transaction_id: &[u8], out: &mut [u8]) {
+    let key = xor_key(transaction_id);
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    out[0] = 0;
+    out[2..4].copy_from_slice(&port.to_be_bytes());
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out[1] = FAMILY_IPV4;
+            for (i, b) in ip.octets().iter().enumerate() {
+                out[4 + i] = b ^ key[i];
+            }
+        }
+        IpAddr::V6(ip) => {
+            out[1] = FAMILY_IPV6;
+            for (i, b) in ip.octets().iter().enumerate() {
+                out[4 + i] = b ^ key[i];
+            }
+        }
+    }
+}
+
+pub fn decode_xor_address(raw: &[u8], transaction_id: &[u8]) -> Result<SocketAddr> {
+    if raw.len() < IPV4_ADDRESS_SIZE {
+        return Err(Error::ErrXorAddressMalformed);
+    }
+    let key = xor_key(transaction_id);
+    let port = u16::from_be_bytes([raw[2], raw[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match raw[1] {
+        FAMILY_IPV4 => {
+            let mut octets = [0u8; 4];
+            for i in 0..4 {
+                octets[i] = raw[4 + i] ^ key[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_IPV6 if raw.len() >= IPV6_ADDRESS_SIZE => {
+            let mut octets = [0u8; 16];
+            for i in 0..16 {
+                octets[i] = raw[4 + i] ^ key[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(Error::ErrXorAddressMalformed),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+// RFC 5766 sec 14.3
+// クライアントから見たpeerのアドレス。CreatePermission, ChannelBind, Send, Dataで使われる
+pub struct XorPeerAddress {
+    pub address: SocketAddr,
+}
+
+impl XorPeerAddress {
+    // messageに含まれる全てのXOR-PEER-ADDRESSを取り出す
+    pub fn get_all_from(m: &Message) -> Result<Vec<XorPeerAddress>> {
+        m.attributes
+            .iter()
+            .filter(|attribute| attribute.typ == ATTR_XOR_PEER_ADDRESS)
+            .map(|attribute| {
+                Ok(XorPeerAddress {
+                    address: decode_xor_address(&attribute.value, &m.transaction_id.0)?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Setter for XorPeerAddress {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let raw = encode_xor_address(self.address, &m.transaction_id.0);
+        let extra_attribute = Attribute::new(ATTR_XOR_PEER_ADDRESS, raw.len() as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
+
+// RFC 5766 sec 14.5
+// serverがclientのために確保したrelayアドレス。Allocateのsuccess responseで返す
+pub struct XorRelayedAddress {
+    pub address: SocketAddr,
+}
+
+impl Setter for XorRelayedAddress {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let raw = encode_xor_address(self.address, &m.transaction_id.0);
+        let extra_attribute = Attribute::new(ATTR_XOR_RELAYED_ADDRESS, raw.len() as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}
+
+// RFC 5389 sec 15.2
+// serverから見たclientのアドレス(server reflexive address)
+pub struct XorMappedAddress {
+    pub address: SocketAddr,
+}
+
+impl Setter for XorMappedAddress {
+    fn set_extra_attribute(&self, m: &mut Message) -> stun::error::Result<()> {
+        let raw = encode_xor_address(self.address, &m.transaction_id.0);
+        let extra_attribute = Attribute::new(ATTR_XOR_MAPPED_ADDRESS, raw.len() as u16, raw);
+        m.attributes.push(extra_attribute);
+        Ok(())
+    }
+}